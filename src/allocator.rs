@@ -1,7 +1,8 @@
+use crate::page::NODE_PAGES;
 use crate::pager::PageId;
-use crate::types::{Serializable, SizedOnDisk};
+use crate::types::{layout, PageOffset, Serializable, SizedOnDisk, Varint};
 use crate::{deserialize, deserialize_with_var, serialize};
-use ser_derive::SizedOnDisk;
+use std::collections::BTreeSet;
 // enum PageType {
 //     INVALID,
 //     BRANCH,
@@ -14,25 +15,138 @@ pub trait PageAllocator {
     fn dealloc(&mut self, _addr: PageId) {}
 }
 
-#[derive(Default, SizedOnDisk, Clone, Debug)]
+/// On disk: counter, then up to format 5 the free list. From format 6 the superblock stores the
+/// free list apart, as it has no bound
+#[derive(Default, Clone, Debug)]
 pub struct SimpleAllocator {
     counter: PageId,
+    /// Nodes released below `counter`, reused before the file grows
+    free: BTreeSet<PageId>,
+}
+
+impl SimpleAllocator {
+    /// The largest page id reserved so far
+    pub fn last_allocated(&self) -> PageId {
+        self.counter
+    }
+
+    /// Reserve a node past every other one, so that successive calls return adjacent extents
+    pub fn alloc_fresh(&mut self) -> PageId {
+        let page_id = self.counter + 1;
+        self.counter += NODE_PAGES;
+        page_id
+    }
+
+    /// Free nodes grouped into runs of adjacent extents
+    pub fn free_list(&self) -> FreeList {
+        let mut runs: Vec<(PageId, u64)> = vec![];
+        for &page_id in self.free.iter() {
+            match runs.last_mut() {
+                Some((first, len)) if *first + *len * NODE_PAGES == page_id => *len += 1,
+                _ => runs.push((page_id, 1)),
+            }
+        }
+        FreeList(runs)
+    }
+
+    /// Release the nodes of `free_list`, read back from disk
+    pub fn set_free_list(&mut self, free_list: FreeList) {
+        self.free = free_list
+            .0
+            .into_iter()
+            .flat_map(|(first, len)| (0..len).map(move |i| first + i * NODE_PAGES))
+            .collect();
+    }
+}
+
+/// Released nodes as runs of adjacent extents
+/// On disk: a varint number of runs, each a varint first page and a varint number of nodes
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct FreeList(Vec<(PageId, u64)>);
+
+impl SizedOnDisk for FreeList {
+    fn size(&self) -> PageOffset {
+        Varint::from(self.0.len()).size()
+            + self
+                .0
+                .iter()
+                .map(|&(first, len)| Varint(first).size() + Varint(len).size())
+                .sum::<PageOffset>()
+    }
+}
+
+impl Serializable for FreeList {
+    fn serialize(&self, destination: &mut [u8]) {
+        let mut _cursor = 0;
+        serialize!(Varint::from(self.0.len()), destination, _cursor);
+        self.0.iter().for_each(|&(first, len)| {
+            serialize!(Varint(first), destination, _cursor);
+            serialize!(Varint(len), destination, _cursor);
+        });
+    }
+
+    fn deserialize(src: &[u8]) -> Self {
+        let mut _cursor = 0;
+        deserialize_with_var!(runs, Varint, src, _cursor);
+        Self(
+            (0..usize::from(runs))
+                .map(|_| {
+                    deserialize_with_var!(first, Varint, src, _cursor);
+                    deserialize_with_var!(len, Varint, src, _cursor);
+                    (first.0, len.0)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl SizedOnDisk for SimpleAllocator {
+    fn size(&self) -> PageOffset {
+        let free_list = if layout() < 6 {
+            self.free_list().size()
+        } else {
+            0
+        };
+        self.counter.size() + free_list
+    }
 }
 
 impl Serializable for SimpleAllocator {
     fn serialize(&self, destination: &mut [u8]) {
-        serialize!(self.counter, destination);
+        let mut _cursor = 0;
+        serialize!(self.counter, destination, _cursor);
+        if layout() < 6 {
+            serialize!(self.free_list(), destination, _cursor);
+        }
     }
 
     fn deserialize(src: &[u8]) -> Self {
-        deserialize_with_var!(counter, PageId, src);
-        Self { counter }
+        let mut _cursor = 0;
+        deserialize_with_var!(counter, PageId, src, _cursor);
+        let mut allocator = Self {
+            counter,
+            free: BTreeSet::new(),
+        };
+        if layout() < 6 {
+            deserialize_with_var!(free_list, FreeList, src, _cursor);
+            allocator.set_free_list(free_list);
+        }
+        allocator
     }
 }
 
 impl PageAllocator for SimpleAllocator {
+    // Every node reserves NODE_PAGES consecutive pages
     fn alloc(&mut self) -> PageId {
-        self.counter += 1;
-        self.counter
+        if let Some(page_id) = self.free.pop_first() {
+            return page_id;
+        }
+        self.alloc_fresh()
+    }
+
+    fn dealloc(&mut self, page_id: PageId) {
+        debug_assert!(page_id <= self.counter);
+        let fresh = self.free.insert(page_id);
+        debug_assert!(fresh, "Double free of {}", page_id);
     }
 }
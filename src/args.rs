@@ -5,6 +5,9 @@ pub struct Args {
 
     /// buffer size
     pub buffer_size: usize,
+
+    /// Bloom filter bits per key of internal message buffers, 0 for none
+    pub bloom_bits: u8,
 }
 
 impl Default for Args {
@@ -12,6 +15,7 @@ impl Default for Args {
         Self {
             eps: 0.5,
             buffer_size: 34,
+            bloom_bits: 0,
         }
     }
 }
@@ -0,0 +1,78 @@
+/// Writes to the trees of one database, applied and committed together by `Betree::write`
+#[derive(Default)]
+pub struct WriteBatch {
+    /// Tree name, key and value of every insert, in order
+    pub(crate) writes: Vec<(String, Vec<u8>, Vec<u8>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, tree: &str, key: Vec<u8>, val: Vec<u8>) {
+        self.writes.push((tree.to_owned(), key, val));
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+#[test]
+fn test_batch_across_trees() {
+    use crate::testing::TempPath;
+    use crate::{Betree, Error, DEFAULT_TREE};
+    let temp = TempPath::new("batch");
+    let path = temp.path();
+    {
+        let mut db = Betree::new(path);
+        db.create_tree("by_email").unwrap();
+        assert!(matches!(
+            db.create_tree("by_email"),
+            Err(Error::TreeAlreadyExists(_))
+        ));
+        for i in 0..1500u64 {
+            let mut batch = WriteBatch::new();
+            batch.insert(DEFAULT_TREE, i.to_be_bytes().to_vec(), vec![1; 20]);
+            batch.insert(
+                "by_email",
+                format!("{}@mail", i).into_bytes(),
+                i.to_be_bytes().to_vec(),
+            );
+            db.write(batch).unwrap();
+        }
+        // Nothing is applied when a write of the batch is invalid
+        let mut batch = WriteBatch::new();
+        batch.insert(DEFAULT_TREE, b"orphan".to_vec(), vec![]);
+        batch.insert("missing", b"key".to_vec(), vec![]);
+        assert!(matches!(db.write(batch), Err(Error::TreeNotFound(_))));
+        assert_eq!(db.get(b"orphan"), None);
+    }
+    let mut db = Betree::open(path).unwrap();
+    assert_eq!(db.tree_names(), vec![DEFAULT_TREE, "by_email"]);
+    for i in 0..1500u64 {
+        assert_eq!(db.get(&i.to_be_bytes()), Some(vec![1; 20]));
+        let email = format!("{}@mail", i).into_bytes();
+        assert_eq!(
+            db.get_from("by_email", &email).unwrap(),
+            Some(i.to_be_bytes().to_vec())
+        );
+        assert_eq!(db.get_from(DEFAULT_TREE, &email).unwrap(), None);
+    }
+    assert!(db.get_from("missing", b"key").is_err());
+
+    // Names that would not fit in the superblock page are refused
+    let long = "t".repeat(3000);
+    db.create_tree(&format!("{}1", long)).unwrap();
+    assert!(matches!(
+        db.create_tree(&format!("{}2", long)),
+        Err(Error::SuperblockFull)
+    ));
+    assert_eq!(db.tree_names().len(), 3);
+    db.flush().unwrap();
+}
@@ -1,83 +1,430 @@
-use crate::node::{InternalNode, MsgBuffer, Node, NodeType, MAX_KEY_SIZE};
+use crate::batch::WriteBatch;
+use crate::diff::Diff;
+use crate::error::Error;
+use crate::node::{
+    InternalNode, LeafNode, MsgBuffer, Node, NodeType, PivotMap, Pivots, MAX_KEY_SIZE,
+};
+use crate::pager::{Pager, SimplePager};
 use crate::pool::NodeCache;
+use crate::range_delete;
+use crate::reader::Reader;
+use crate::scan::{self, LATEST};
+use crate::seal::{Key, Seal};
+use crate::snapshot::Snapshot;
 use crate::superblock;
+use crate::transaction::{Transaction, WriteLog};
+use crate::ttl;
 use crate::types::MessageData;
-use crate::types::{MessageType, OnDiskKey, SizedOnDisk};
+use crate::types::{Comparator, MessageType, OnDiskKey, OnDiskValue, SizedOnDisk, FORMAT_VERSION};
 use crate::CFG;
 use crate::{allocator::PageAllocator, node::ChildId};
-use std::collections::{HashSet, VecDeque};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::ops::RangeBounds;
 use std::path::Path;
-use superblock::Superblock;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use superblock::{Checkpoint, Superblock};
 
 // const POOLSIZE: usize = 34000 / 1000;
 
-pub struct Betree {
+/// Name of the tree every database starts with, rooted at the superblock root
+pub const DEFAULT_TREE: &str = "default";
+
+/// Nodes `bulk_load` keeps before writing them to the pager together
+const BULK_WRITE_NODES: usize = 64;
+
+pub struct Betree<P: Pager = SimplePager> {
     root: ChildId,
     // memtable: Memtable,
-    pool: NodeCache,
+    pool: NodeCache<P>,
     superblock: Superblock,
+    /// Roots held by live snapshots
+    pins: Vec<Weak<ChildId>>,
+    /// Writes to the default tree that open transactions may conflict with
+    log: WriteLog,
+    /// Sequence numbers of live readers, whose versions are kept
+    readers: Vec<Weak<u64>>,
 }
 
 impl Betree {
-    fn copy_node(&mut self, old_id: &ChildId) -> ChildId {
+    pub fn new<Q: AsRef<Path>>(path: Q) -> Self {
+        Self::new_with_pager(path)
+    }
+
+    pub fn open<Q: AsRef<Path>>(path: Q) -> Result<Self, Error> {
+        Self::open_with_pager(path)
+    }
+
+    /// Create a new tree whose keys are ordered by `comparator`
+    pub fn new_with_comparator<Q: AsRef<Path>>(path: Q, comparator: Comparator) -> Self {
+        Self::create(path, None, comparator)
+    }
+
+    /// Open an existing encrypted tree(or create one) with `key`
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted<Q: AsRef<Path>>(path: Q, key: &Key) -> Result<Self, Error> {
+        Self::open_encrypted_with_pager(path, key)
+    }
+}
+
+impl<P: Pager> Betree<P> {
+    fn copy_node(&mut self, old_id: &ChildId) -> Result<ChildId, Error> {
         let new_page_id = self.superblock.alloc();
-        let mut new_node = self.pool.get(old_id).clone();
+        let mut new_node = self.pool.get(old_id)?.clone();
         new_node.dirt();
         // println!("New :{}, old: {}", old_id, new_page_id);
-        self.pool.put(new_page_id, new_node);
-        new_page_id
+        self.pool.put(new_page_id, new_node)?;
+        Ok(new_page_id)
+    }
+
+    /// Keep the write of `key` to the default tree about to be made for open transactions
+    fn record_write(&mut self, key: &[u8]) {
+        let key = OnDiskKey::with_comparator(key.to_vec(), self.superblock.comparator);
+        self.log.record(key, self.superblock.seq + 1);
     }
 
     pub fn insert(&mut self, key: Vec<u8>, val: Vec<u8>) {
+        self.insert_into(DEFAULT_TREE, key, val)
+            .expect("Failed to write the tree");
+    }
+
+    /// Insert a value that `get` and scans stop seeing once `ttl` has passed, and that is
+    /// removed as the leaves holding it are rewritten
+    pub fn insert_with_ttl(&mut self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) {
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        self.insert_expiring(key, val, ttl::now().saturating_add(ttl));
+    }
+
+    /// Insert a value expiring at `expiry`, in milliseconds since the Unix epoch
+    pub(crate) fn insert_expiring(&mut self, key: Vec<u8>, val: Vec<u8>, expiry: u64) {
+        self.record_write(&key);
+        let val = ttl::encode(val, expiry);
+        let root = self
+            .insert_at(self.root, key, MessageType::InsertWithTtl, val)
+            .expect("Failed to write the tree");
+        self.set_root_of(DEFAULT_TREE, root);
+    }
+
+    /// Insert into the tree named `tree`
+    pub fn insert_into(&mut self, tree: &str, key: Vec<u8>, val: Vec<u8>) -> Result<(), Error> {
+        let root = self.root_of(tree)?;
+        if tree == DEFAULT_TREE {
+            self.record_write(&key);
+        }
+        let root = self.insert_at(root, key, MessageType::Insert, val)?;
+        self.set_root_of(tree, root);
+        Ok(())
+    }
+
+    /// Insert unless `key` already has a value, which fails with `Error::KeyAlreadyExists`
+    pub fn insert_if_absent(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<(), Error> {
+        check_key_size(&key)?;
+        if self.get_at(&key, LATEST)?.is_some() {
+            return Err(Error::KeyAlreadyExists);
+        }
+        self.insert_into(DEFAULT_TREE, key, val)
+    }
+
+    /// Replace the value of `key`, failing with `Error::KeyNotFound` if it has none
+    pub fn update_if_present(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<(), Error> {
+        check_key_size(&key)?;
+        if self.get_at(&key, LATEST)?.is_none() {
+            return Err(Error::KeyNotFound);
+        }
+        self.insert_into(DEFAULT_TREE, key, val)
+    }
+
+    /// Set `key` to `new` if its value is `expected`(None for no value), or fail with
+    /// `Error::ValueMismatch` carrying the current value
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        new: Vec<u8>,
+    ) -> Result<(), Error> {
+        check_key_size(&key)?;
+        let current = self.get_at(&key, LATEST)?;
+        if current.as_deref() != expected {
+            return Err(Error::ValueMismatch(current));
+        }
+        self.insert_into(DEFAULT_TREE, key, new)
+    }
+
+    /// Return the new root of the tree rooted at `root`
+    fn insert_at(
+        &mut self,
+        root: ChildId,
+        key: Vec<u8>,
+        ty: MessageType,
+        val: Vec<u8>,
+    ) -> Result<ChildId, Error> {
         // logging here
 
-        let key = OnDiskKey::new(key);
+        let key = OnDiskKey::with_comparator(key, self.superblock.comparator);
         assert!(key.size() <= MAX_KEY_SIZE);
-        let msg_data = MessageData::new(MessageType::Insert, val);
+        self.superblock.seq += 1;
+        let msg_data = MessageData::with_seq(ty, val, self.superblock.seq);
 
         let mut buf = MsgBuffer::new();
         buf.insert(key, msg_data);
-        let (child_id, p) = self.send_msgs_to_subtree(self.root, buf);
-        debug_assert!(p.is_none());
+        let readers = self.reader_seqs();
+        let (child_id, p) = self.send_msgs_to_subtree(root, buf, MsgBuffer::new(), &readers)?;
+        debug_assert!(p.is_empty());
         // while !res.1.is_empty() {
         // let node = self.pool.get_mut(self.root);
         // node.
         // }
-        self.root = child_id;
-        self.superblock.root = child_id;
+        Ok(child_id)
         // self.pool.flush();
     }
 
-    /// Return new pivot, left, right child id if split
+    /// Apply every write of `batch` and commit them with one superblock flush, so either all of
+    /// them survive a crash or none does
+    pub fn write(&mut self, batch: WriteBatch) -> Result<(), Error> {
+        for (tree, key, _) in batch.writes.iter() {
+            self.root_of(tree)?;
+            check_key_size(key)?;
+        }
+        for (tree, key, val) in batch.writes {
+            self.insert_into(&tree, key, val)?;
+        }
+        self.flush()
+    }
+
+    /// Replace the default tree with `pairs`, given in increasing key order. Leaves are packed
+    /// full and each level of internal nodes is built over the one below with empty message
+    /// buffers, into adjacent extents past the end of the file written around the cache. One
+    /// superblock flush installs the new root, then the pages of the replaced tree are released.
+    /// Nothing changes if a key is not greater than the one before it(`Error::UnsortedInput`) or
+    /// while a reader or snapshot is alive(`Error::LiveReaders`)
+    pub fn bulk_load<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(
+        &mut self,
+        pairs: I,
+    ) -> Result<(), Error> {
+        if !self.reader_seqs().is_empty() || !self.pinned_roots().is_empty() {
+            return Err(Error::LiveReaders);
+        }
+        let mut written = vec![];
+        let root = match self.build_bottom_up(pairs, &mut written) {
+            Ok(root) => root,
+            Err(e) => {
+                for page_id in written {
+                    self.pool.forget(&page_id);
+                    self.superblock.allocator.dealloc(page_id);
+                }
+                return Err(e);
+            }
+        };
+        let replaced = [self.root, self.superblock.last_flushed_root];
+        self.log.record_all(self.superblock.seq + 1);
+        self.superblock.seq += 1;
+        self.set_root_of(DEFAULT_TREE, root);
+        self.flush()?;
+        self.release_unreachable(&replaced)?;
+        Ok(())
+    }
+
+    /// Write the nodes of a tree holding `pairs`, adding their pages to `written`, and return
+    /// its root
+    fn build_bottom_up<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(
+        &mut self,
+        pairs: I,
+        written: &mut Vec<ChildId>,
+    ) -> Result<ChildId, Error> {
+        // The last node built is held back, as it is the root once the input ends
+        let mut pending: Vec<(ChildId, Node)> = vec![];
+        let mut put = |tree: &mut Self, node| -> Result<ChildId, Error> {
+            if pending.len() > BULK_WRITE_NODES {
+                let last = pending.pop().unwrap();
+                tree.pool.write_uncached(&pending)?;
+                pending = vec![last];
+            }
+            let page_id = tree.superblock.alloc_fresh();
+            pending.push((page_id, node));
+            written.push(page_id);
+            Ok(page_id)
+        };
+        // First key and page of each node of the level being built
+        let mut level: Vec<(OnDiskKey, ChildId)> = vec![];
+        let mut leaf = LeafNode::new();
+        let mut first: Option<OnDiskKey> = None;
+        let mut last: Option<OnDiskKey> = None;
+        for (key, val) in pairs {
+            let key = OnDiskKey::with_comparator(key, self.superblock.comparator);
+            if key.size() > MAX_KEY_SIZE {
+                return Err(Error::KeyOverflowError);
+            }
+            if last.as_ref().is_some_and(|last| *last >= key) {
+                return Err(Error::UnsortedInput(key.to_vec()));
+            }
+            last = Some(key.clone());
+            first.get_or_insert_with(|| key.clone());
+            if let Err((key, val)) = leaf.push(key, OnDiskValue::new(val)) {
+                let full = std::mem::replace(&mut leaf, LeafNode::new());
+                let page_id = put(self, Node::new(NodeType::Leaf(full)))?;
+                level.push((first.replace(key.clone()).unwrap(), page_id));
+                leaf.push(key, val).map_err(|_| Error::ValueOverflowError)?;
+            }
+        }
+        // Without input the tree is the empty leaf, whose first key is never a pivot
+        let first =
+            first.unwrap_or_else(|| OnDiskKey::with_comparator(vec![], self.superblock.comparator));
+        level.push((first, put(self, Node::new(NodeType::Leaf(leaf)))?));
+        while level.len() > 1 {
+            let mut children = std::mem::take(&mut level).into_iter();
+            let (mut first, child_id) = children.next().unwrap();
+            let mut internal = InternalNode::new_internel_root(PivotMap::new(), child_id);
+            for (pivot, child_id) in children {
+                if let Err((pivot, child_id)) = internal.push(pivot, child_id) {
+                    let next = InternalNode::new_internel_root(PivotMap::new(), child_id);
+                    let full = std::mem::replace(&mut internal, next);
+                    let page_id = put(self, Node::new(NodeType::Internal(full)))?;
+                    level.push((std::mem::replace(&mut first, pivot), page_id));
+                }
+            }
+            level.push((first, put(self, Node::new(NodeType::Internal(internal)))?));
+        }
+        pending.last_mut().unwrap().1.set_root();
+        self.pool.write_uncached(&pending)?;
+        Ok(level[0].1)
+    }
+
+    /// Begin a transaction on the default tree, see `Transaction`
+    pub fn transaction(&mut self) -> Transaction {
+        Transaction::new(
+            self.log.begin(self.superblock.seq),
+            self.superblock.comparator,
+        )
+    }
+
+    /// Apply the writes of `txn` like a `WriteBatch`, unless a key it read has been written
+    /// since it began
+    pub fn commit(&mut self, txn: Transaction) -> Result<(), Error> {
+        let batch = txn
+            .into_batch(&self.log)
+            .map_err(Error::TransactionConflict)?;
+        self.write(batch)
+    }
+
+    /// Create an empty tree named `name` sharing the storage, node cache and WAL of this one
+    pub fn create_tree(&mut self, name: &str) -> Result<(), Error> {
+        if name == DEFAULT_TREE || self.superblock.catalog.contains_key(name) {
+            return Err(Error::TreeAlreadyExists(name.to_owned()));
+        }
+        self.superblock.catalog.insert(name.to_owned(), 0);
+        if !self.superblock.fits() {
+            self.superblock.catalog.remove(name);
+            return Err(Error::SuperblockFull);
+        }
+        let page_id = self.superblock.alloc();
+        self.pool.put(page_id, Node::new_empty_leaf(true))?;
+        self.superblock.catalog.insert(name.to_owned(), page_id);
+        Ok(())
+    }
+
+    /// Names of the trees in the database, the default one first
+    pub fn tree_names(&self) -> Vec<String> {
+        std::iter::once(DEFAULT_TREE.to_owned())
+            .chain(self.superblock.catalog.keys().cloned())
+            .collect()
+    }
+
+    fn root_of(&self, tree: &str) -> Result<ChildId, Error> {
+        match tree {
+            DEFAULT_TREE => Ok(self.root),
+            _ => self
+                .superblock
+                .catalog
+                .get(tree)
+                .copied()
+                .ok_or_else(|| Error::TreeNotFound(tree.to_owned())),
+        }
+    }
+
+    fn set_root_of(&mut self, tree: &str, root: ChildId) {
+        if tree == DEFAULT_TREE {
+            self.root = root;
+            self.superblock.root = root;
+        } else {
+            *self.superblock.catalog.get_mut(tree).unwrap() = root;
+        }
+    }
+
+    /// Return the new id of `current` and the pivots and right siblings it split into
     /// Parent must not be full
-    /// How to handle tons of msg? May need more than one split
     fn send_msgs_to_subtree(
         &mut self,
         mut current: ChildId,
-        msgs: MsgBuffer,
-    ) -> (ChildId, Option<(OnDiskKey, ChildId)>) {
-        if msgs.is_empty() {
-            return (current, None);
+        mut msgs: MsgBuffer,
+        deletes: MsgBuffer,
+        readers: &[u64],
+    ) -> Result<(ChildId, Pivots), Error> {
+        if msgs.is_empty() && deletes.is_empty() {
+            return Ok((current, vec![]));
         }
+        let now = ttl::now();
+        msgs.values_mut().for_each(|msg| msg.expire(now));
         let safe = self.superblock.safe_to_overwrite_in_place(current);
         if !safe {
-            current = self.copy_node(&current);
+            current = self.copy_node(&current)?;
             // safe = true;
         };
-        let mut node = self.pool.acquire(&current);
+        let mut node = self.pool.acquire(&current)?;
         let old_current = current;
-        let pivots = match &mut node.node_inner {
+        // The node goes back to the cache even when reading or writing below it fails
+        let pivots = match self.apply_msgs(&mut node, msgs, deletes, readers, now) {
+            Ok(pivots) => pivots,
+            Err(e) => {
+                self.pool.release(old_current, node)?;
+                return Err(e);
+            }
+        };
+
+        let res = if node.is_root() && !pivots.is_empty() {
+            let parent = Node::new_internel_root(current, pivots);
+            let parent_id = self.superblock.alloc();
+            node.unset_root();
+            self.pool.put(parent_id, parent)?;
+            (parent_id, vec![])
+        } else {
+            (current, pivots)
+        };
+        self.pool.release(old_current, node)?;
+        Ok(res)
+    }
+
+    /// Apply `msgs` and `deletes` to `node`, sending them further down from an internal node
+    /// whose buffer fills up. Return the pivots and right siblings `node` split into
+    fn apply_msgs(
+        &mut self,
+        node: &mut Node,
+        mut msgs: MsgBuffer,
+        deletes: MsgBuffer,
+        readers: &[u64],
+        now: u64,
+    ) -> Result<Pivots, Error> {
+        Ok(match &mut node.node_inner {
             NodeType::Leaf(leaf) => {
-                msgs.into_iter().for_each(|(key, msg)| leaf.apply(key, msg));
-                let mut pivots = None;
-                if leaf.is_node_full() {
+                leaf.delete_ranges(&deletes, &msgs, readers);
+                msgs.into_iter().for_each(|(key, msg)| {
+                    let msg = match range_delete::tombstones(&deletes, &key) {
+                        Some(tombstones) => msg.merge(tombstones),
+                        None => msg,
+                    };
+                    leaf.apply(key, msg, readers)
+                });
+                leaf.collect(readers);
+                leaf.expire(now);
+                let mut pivots = vec![];
+                // Versions pinned by readers can fill more than two leaves
+                while leaf.is_node_full() {
                     let right_sib_id = self.superblock.alloc();
                     let (right_sib, median) = leaf.split();
-                    self.pool.put(right_sib_id, right_sib);
-                    pivots = Some((median, right_sib_id));
+                    self.pool.put(right_sib_id, right_sib)?;
+                    pivots.push((median, right_sib_id));
                 }
-                assert!(!leaf.is_node_full());
-                // pivots.reverse();
+                // Every split takes the keys below the one before it
+                pivots.reverse();
                 pivots
                 // if node.is_root() && !pivots.is_empty() {
                 //     let parent = Node::new_internel_root(current, &pivots);
@@ -91,34 +438,71 @@ impl Betree {
             }
             NodeType::Internal(internal) => {
                 let mut merging_possible = HashSet::new();
-                let first_key = msgs.first_key_value().unwrap().0;
-                let last_key = msgs.last_key_value().unwrap().0;
-                let first_child = internal.find_child_with_key(first_key);
-                let last_child = internal.find_child_with_key(last_key);
-                if first_child == last_child
-                    && self.pool.contains(&first_child)
-                    && self.pool.get(&first_child).dirty()
+                let first_child = msgs
+                    .first_key_value()
+                    .map(|(k, _)| internal.find_child_with_key(k));
+                let last_child = msgs
+                    .last_key_value()
+                    .map(|(k, _)| internal.find_child_with_key(k));
+                // Range deletes buffered here must stay above the newer messages they would hide
+                let direct = first_child == last_child
+                    && deletes.is_empty()
+                    && internal.range_deletes.is_empty();
+                if let Some(first_child) =
+                    first_child.filter(|c| direct && self.pool.peek(c).is_some_and(Node::dirty))
                 {
                     // internal.msg_buffer
                     //     .extract_if(|k, _v| internal.find_child_with_key(k) == last_child).for_each(
                     //     |(k, v)| {msgs.insert(k, v);}
                     //     );
                     // internal.msg_buffer.refresh_size();
-                    msgs.keys().for_each(|k| {
-                        internal.msg_buffer.remove(k);
+                    // Messages buffered here are older and travel down with the new ones
+                    msgs.iter_mut().for_each(|(k, msg)| {
+                        if let Some(old) = internal.msg_buffer.remove(k) {
+                            msg.push_older(old);
+                        }
                     });
-                    let (child_id, new_pivots) = self.send_msgs_to_subtree(first_child, msgs);
-                    if new_pivots.is_none() && self.merging_possible(&child_id) {
+                    let (child_id, new_pivots) =
+                        self.send_msgs_to_subtree(first_child, msgs, deletes, readers)?;
+                    if new_pivots.is_empty() && self.merging_possible(&child_id)? {
                         merging_possible.insert(child_id);
                     }
                     internal.update_pivots(first_child, child_id, new_pivots)
                 } else {
-                    internal.merge_buffers(msgs);
+                    internal.merge_deletes(deletes, &msgs, readers);
+                    internal.merge_buffers(msgs, readers);
                     if internal.is_msg_buffer_full() {
                         let msgs_map = internal.prepare_msg_flush();
-                        for (c, msgs) in msgs_map {
-                            let (child_id, new_pivots) = self.send_msgs_to_subtree(c, msgs);
-                            if new_pivots.is_none() && self.merging_possible(&child_id) {
+                        let horizon = readers.first().copied().unwrap_or(u64::MAX);
+                        // A child every reader sees deleted is replaced without being read
+                        let spanned: Vec<bool> = msgs_map
+                            .iter()
+                            .map(|(c, _, deletes)| match internal.bounds(*c) {
+                                (Some(lower), Some(upper)) => {
+                                    range_delete::spans(deletes, lower, upper, horizon)
+                                }
+                                _ => false,
+                            })
+                            .collect();
+                        // The other children are read in one batch
+                        let reads: Vec<ChildId> = msgs_map
+                            .iter()
+                            .zip(&spanned)
+                            .filter(|(_, &spanned)| !spanned)
+                            .map(|((c, _, _), _)| *c)
+                            .collect();
+                        self.pool.prefetch(&reads)?;
+                        for ((c, msgs, deletes), spanned) in msgs_map.into_iter().zip(spanned) {
+                            let target = if spanned {
+                                let leaf_id = self.superblock.alloc();
+                                self.pool.put(leaf_id, Node::new_empty_leaf(false))?;
+                                leaf_id
+                            } else {
+                                c
+                            };
+                            let (child_id, new_pivots) =
+                                self.send_msgs_to_subtree(target, msgs, deletes, readers)?;
+                            if new_pivots.is_empty() && self.merging_possible(&child_id)? {
                                 merging_possible.insert(child_id);
                             }
                             internal.update_pivots(c, child_id, new_pivots)
@@ -128,53 +512,44 @@ impl Betree {
 
                 // self.merge(merging_possible, internal);
 
-                let mut pivots = None;
-                if internal.is_pivots_full() {
+                let mut pivots = vec![];
+                // Children that split more than once can add more pivots than one split frees
+                while internal.is_pivots_full() {
                     let right_sib_id = self.superblock.alloc();
                     let (right_sib, median) = internal.split();
-                    self.pool.put(right_sib_id, right_sib);
-                    pivots = Some((median, right_sib_id));
+                    self.pool.put(right_sib_id, right_sib)?;
+                    pivots.push((median, right_sib_id));
                 }
-                // pivots.reverse();
-                assert!(!internal.is_pivots_full());
+                pivots.reverse();
                 pivots
             }
             _ => unimplemented!(),
-        };
-
-        let res = if node.is_root() && !pivots.is_none() {
-            // Do it in while loop
-            let parent = Node::new_internel_root(current, pivots);
-            let parent_id = self.superblock.alloc();
-            node.unset_root();
-            self.pool.put(parent_id, parent);
-            (parent_id, None)
-        } else {
-            (current, pivots)
-        };
-        self.pool.release(old_current, node);
-        res
+        })
     }
 
-    pub fn merging_possible(&mut self, child_id: &ChildId) -> bool {
-        let node = self.pool.get(child_id);
-        node.merging_possible()
+    pub fn merging_possible(&mut self, child_id: &ChildId) -> Result<bool, Error> {
+        let node = self.pool.get(child_id)?;
+        Ok(node.merging_possible())
     }
 
-    pub fn merge(&mut self, mut merging_possible: HashSet<ChildId>, node: &mut InternalNode) {
+    pub fn merge(
+        &mut self,
+        mut merging_possible: HashSet<ChildId>,
+        node: &mut InternalNode,
+    ) -> Result<(), Error> {
         let mut pre_child = node.rightmost_child;
         let mut deleted_keys = vec![];
 
         for (k, &c) in node.pivot_map.iter().rev() {
-            if merging_possible.contains(&pre_child) && self.pool.get(&c).merging_possible() {
+            if merging_possible.contains(&pre_child) && self.pool.get(&c)?.merging_possible() {
                 let mut c = c;
                 let safe = self.superblock.safe_to_overwrite_in_place(c);
                 if !safe {
-                    c = self.copy_node(&c);
+                    c = self.copy_node(&c)?;
                     // safe = true;
                 };
-                let pre_node = self.pool.acquire(&pre_child);
-                let current = self.pool.get_mut(&c);
+                let pre_node = self.pool.acquire(&pre_child)?;
+                let current = self.pool.get_mut(&c)?;
                 current.merge(pre_node);
                 deleted_keys.push((k.clone(), c));
                 if current.merging_possible() {
@@ -195,18 +570,42 @@ impl Betree {
         //     }
         //     node.pivot_map.remove(&k);
         // }
+        Ok(())
     }
 
     pub fn delete(&mut self, key: Vec<u8>) {
-        let key = OnDiskKey::new(key);
+        let key = OnDiskKey::with_comparator(key, self.superblock.comparator);
         let msg_data = MessageData::new(MessageType::Delete, vec![]);
         let mut buf = MsgBuffer::new();
         buf.insert(key, msg_data);
         // Merging
     }
 
+    /// Delete the keys of the default tree from `start` up to `end`(exclusive) with a single
+    /// message, which hides them at once and removes them as it is flushed to the leaves
+    pub fn delete_range(&mut self, start: Vec<u8>, end: Vec<u8>) {
+        let start = OnDiskKey::with_comparator(start, self.superblock.comparator);
+        let end = OnDiskKey::with_comparator(end, self.superblock.comparator);
+        assert!(start.size() <= MAX_KEY_SIZE && end.size() <= MAX_KEY_SIZE);
+        if start >= end {
+            return;
+        }
+        self.log.record_range(&start, &end, self.superblock.seq + 1);
+        self.superblock.seq += 1;
+        let msg_data =
+            MessageData::with_seq(MessageType::RangeDelete, end.to_vec(), self.superblock.seq);
+        let mut deletes = MsgBuffer::new();
+        deletes.insert(start, msg_data);
+        let readers = self.reader_seqs();
+        let (root, p) = self
+            .send_msgs_to_subtree(self.root, MsgBuffer::new(), deletes, &readers)
+            .expect("Failed to write the tree");
+        debug_assert!(p.is_empty());
+        self.set_root_of(DEFAULT_TREE, root);
+    }
+
     pub fn upsert(&mut self, key: Vec<u8>, val: Vec<u8>) {
-        let key = OnDiskKey::new(key);
+        let key = OnDiskKey::with_comparator(key, self.superblock.comparator);
         let msg_data = MessageData::new(MessageType::Upsert, val);
 
         let mut buf = MsgBuffer::new();
@@ -216,8 +615,230 @@ impl Betree {
     }
 
     pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
-        let key = OnDiskKey::new(key.to_vec());
-        self.get_from_subtree(&key, self.root)
+        self.get_at(key, LATEST).expect("Failed to read the tree")
+    }
+
+    /// Value of `key` in the default tree for a reader at `seq`
+    pub(crate) fn get_at(&mut self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>, Error> {
+        let key = OnDiskKey::with_comparator(key.to_vec(), self.superblock.comparator);
+        scan::lookup(&mut self.pool, self.root, &key, seq)
+    }
+
+    /// Values of `keys` in the default tree, in the same order, found in one descent shared by
+    /// all of them
+    pub fn multi_get(&mut self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let keys: Vec<OnDiskKey> = keys
+            .iter()
+            .map(|key| OnDiskKey::with_comparator(key.to_vec(), self.superblock.comparator))
+            .collect();
+        scan::lookup_many(&mut self.pool, self.root, &keys, LATEST)
+            .expect("Failed to read the tree")
+    }
+
+    /// Look `key` up in the tree named `tree`
+    pub fn get_from(&mut self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let root = self.root_of(tree)?;
+        let key = OnDiskKey::with_comparator(key.to_vec(), self.superblock.comparator);
+        scan::lookup(&mut self.pool, root, &key, LATEST)
+    }
+
+    /// Expiry of every key of the default tree, or of its checkpoint `checkpoint`, whose value
+    /// expires
+    pub(crate) fn expiries(
+        &mut self,
+        checkpoint: Option<&str>,
+    ) -> Result<BTreeMap<Vec<u8>, u64>, Error> {
+        let root = match checkpoint {
+            Some(name) => self.checkpoint_root(name)?,
+            None => self.root,
+        };
+        let mut found = BTreeMap::new();
+        scan::expiries(&mut self.pool, root, &mut found)?;
+        Ok(found
+            .into_iter()
+            .filter_map(|(k, expiry)| expiry.map(|expiry| (k.to_vec(), expiry)))
+            .collect())
+    }
+
+    /// Keys and values of the existing tree named `tree`, in key order
+    pub(crate) fn scan_all(&mut self, tree: &str) -> Result<scan::Pairs, Error> {
+        let root = self.root_of(tree)?;
+        let range = scan::key_range(&(..), self.superblock.comparator);
+        scan::scan(&mut self.pool, root, &range, LATEST)
+    }
+
+    /// Keys and values of the default tree in `range`, in key order
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.scan_at(range, LATEST)
+            .expect("Failed to read the tree")
+    }
+
+    /// Keys and values of the default tree in `range` for a reader at `seq`, in key order
+    pub(crate) fn scan_at<R: RangeBounds<Vec<u8>>>(
+        &mut self,
+        range: R,
+        seq: u64,
+    ) -> Result<scan::Pairs, Error> {
+        let range = scan::key_range(&range, self.superblock.comparator);
+        scan::scan(&mut self.pool, self.root, &range, seq)
+    }
+
+    /// A reader of the default tree that keeps seeing it as of now while writes go on
+    pub fn reader(&mut self) -> Reader {
+        let seq = Arc::new(self.superblock.seq);
+        self.readers.push(Arc::downgrade(&seq));
+        Reader::new(seq)
+    }
+
+    /// Sequence numbers of the readers still alive, in order
+    fn reader_seqs(&mut self) -> Vec<u64> {
+        self.readers.retain(|seq| seq.strong_count() > 0);
+        let mut seqs: Vec<u64> = self
+            .readers
+            .iter()
+            .filter_map(|seq| seq.upgrade())
+            .map(|seq| *seq)
+            .collect();
+        seqs.sort_unstable();
+        seqs
+    }
+
+    /// A read-only view of the default tree as of the last flush
+    pub fn snapshot(&mut self) -> Snapshot<P> {
+        let root = self.superblock.last_flushed_root;
+        self.snapshot_at(root)
+    }
+
+    /// A read-only view of the tree named `tree` as of the last flush
+    pub fn snapshot_of(&mut self, tree: &str) -> Result<Snapshot<P>, Error> {
+        let root = match tree {
+            DEFAULT_TREE => self.superblock.last_flushed_root,
+            _ => self
+                .superblock
+                .last_flushed_catalog
+                .get(tree)
+                .copied()
+                .ok_or_else(|| Error::TreeNotFound(tree.to_owned()))?,
+        };
+        Ok(self.snapshot_at(root))
+    }
+
+    fn snapshot_at(&mut self, root: ChildId) -> Snapshot<P> {
+        let cfg = CFG.get_or_init(|| crate::Args::default());
+        // Flushed pages are never written in place, so a separate cache can read them
+        let pool = NodeCache::new(
+            &self.superblock.storage_filename,
+            false,
+            cfg.buffer_size.try_into().unwrap(),
+            self.pool.seal().map(Seal::opener),
+        );
+        let pin = Arc::new(root);
+        self.pins.push(Arc::downgrade(&pin));
+        Snapshot::new(pool, root, self.superblock.comparator, pin)
+    }
+
+    /// Roots of the snapshots still alive, whose pages must not be reclaimed
+    pub fn pinned_roots(&mut self) -> Vec<ChildId> {
+        self.pins.retain(|pin| pin.strong_count() > 0);
+        self.pins
+            .iter()
+            .filter_map(|pin| pin.upgrade())
+            .map(|root| *root)
+            .collect()
+    }
+
+    /// Flush and keep the default tree as of now under `name`, across restarts
+    pub fn checkpoint(&mut self, name: &str) -> Result<(), Error> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.checkpoint_at(name, timestamp)
+    }
+
+    /// Checkpoint the default tree as taken at `timestamp`
+    pub(crate) fn checkpoint_at(&mut self, name: &str, timestamp: u64) -> Result<(), Error> {
+        if self.superblock.checkpoints.iter().any(|c| c.name == name) {
+            return Err(Error::CheckpointAlreadyExists(name.to_owned()));
+        }
+        self.superblock.checkpoints.push(Checkpoint {
+            name: name.to_owned(),
+            root: self.root,
+            timestamp,
+        });
+        if !self.superblock.fits() {
+            self.superblock.checkpoints.pop();
+            return Err(Error::SuperblockFull);
+        }
+        self.flush()
+    }
+
+    /// Checkpoints of the default tree, oldest first
+    pub fn checkpoints(&self) -> &[Checkpoint] {
+        &self.superblock.checkpoints
+    }
+
+    /// A read-only view of the default tree as of the checkpoint `name`
+    pub fn at_checkpoint(&mut self, name: &str) -> Result<Snapshot<P>, Error> {
+        let root = self.checkpoint_root(name)?;
+        Ok(self.snapshot_at(root))
+    }
+
+    fn checkpoint_root(&self, name: &str) -> Result<ChildId, Error> {
+        self.superblock
+            .checkpoints
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| c.root)
+            .ok_or_else(|| Error::CheckpointNotFound(name.to_owned()))
+    }
+
+    /// Changes from the committed tree rooted at `old` to the one rooted at `new`, in key order
+    pub fn diff(&mut self, old: ChildId, new: ChildId) -> Diff<'_, P> {
+        Diff::new(&mut self.pool, old, new)
+    }
+
+    /// Changes from the checkpoint `old` to the checkpoint `new`, in key order
+    pub fn diff_checkpoints(&mut self, old: &str, new: &str) -> Result<Diff<'_, P>, Error> {
+        let old = self.checkpoint_root(old)?;
+        let new = self.checkpoint_root(new)?;
+        Ok(self.diff(old, new))
+    }
+
+    /// Drop the checkpoint `name` and release the pages no other tree, checkpoint or live
+    /// snapshot reaches. Return the number of nodes released
+    pub fn delete_checkpoint(&mut self, name: &str) -> Result<usize, Error> {
+        let root = self.checkpoint_root(name)?;
+        self.superblock.checkpoints.retain(|c| c.name != name);
+        // The checkpoint must be gone on disk before its pages are handed out again
+        self.flush()?;
+        self.release_unreachable(&[root])
+    }
+
+    /// Release the pages of the trees rooted at `roots`, which the flushed superblock no longer
+    /// refers to, that no other tree, checkpoint or live snapshot reaches. Return the number of
+    /// nodes released
+    fn release_unreachable(&mut self, roots: &[ChildId]) -> Result<usize, Error> {
+        let pinned = self.pinned_roots();
+        let live_roots: Vec<ChildId> = std::iter::once(self.root)
+            .chain(self.superblock.catalog.values().copied())
+            .chain(self.superblock.checkpoints.iter().map(|c| c.root))
+            .chain(pinned)
+            .collect();
+        let mut live = HashSet::new();
+        for root in live_roots {
+            scan::reachable(&mut self.pool, root, &mut live)?;
+        }
+        let mut pages = live.clone();
+        for root in roots {
+            scan::reachable(&mut self.pool, *root, &mut pages)?;
+        }
+        let released: Vec<ChildId> = pages.difference(&live).copied().collect();
+        for page_id in released.iter() {
+            self.pool.forget(page_id);
+            self.superblock.allocator.dealloc(*page_id);
+        }
+        self.superblock.flush_sb(self.pool.pager_mut())?;
+        Ok(released.len())
     }
 
     // Stupid borrow checker
@@ -247,66 +868,148 @@ impl Betree {
     //     self.get_from_subtree(key, next_child)
     // }
 
-    fn get_from_subtree(&mut self, key: &OnDiskKey, page: ChildId) -> Option<Vec<u8>> {
-        let node = self.pool.get(&page);
-        match &node.node_inner {
-            NodeType::Leaf(leaf) => leaf.get(key).map(|s| s.to_vec()),
-            NodeType::Internal(internal) => match internal.get(key) {
-                Ok(slice) => Some(slice.to_vec()),
-                Err(child_id) => self.get_from_subtree(key, child_id),
-            },
-            _ => {
-                unimplemented!()
-            }
-        }
+    /// Create a new tree whose storage file is accessed through `P`
+    pub fn new_with_pager<Q: AsRef<Path>>(path: Q) -> Self {
+        Self::create(path, None, Comparator::Bytewise)
+    }
+
+    /// Open an existing tree(or create one) whose storage file is accessed through `P`
+    pub fn open_with_pager<Q: AsRef<Path>>(path: Q) -> Result<Self, Error> {
+        Self::load(path, None)
     }
 
-    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+    /// Open an existing encrypted tree(or create one) with `key` through `P`
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted_with_pager<Q: AsRef<Path>>(path: Q, key: &Key) -> Result<Self, Error> {
+        Self::load(path, Some(key))
+    }
+
+    pub(crate) fn create<Q: AsRef<Path>>(
+        path: Q,
+        key: Option<&Key>,
+        comparator: Comparator,
+    ) -> Self {
         let cfg = CFG.get_or_init(|| crate::Args::default());
         let mut superblock = Superblock::new(&path);
+        superblock.comparator = comparator;
+        superblock.encrypted = key.is_some();
+        superblock.epoch = 1;
         let mut pool = NodeCache::new(
             &superblock.storage_filename,
             true,
             cfg.buffer_size.try_into().unwrap(),
+            key.map(|key| Seal::new(key, superblock.epoch)),
         );
         let root = Node::new_empty_leaf(true);
         let page_id = superblock.allocator.alloc();
-        pool.put(page_id, root);
-        pool.write_through(&page_id);
+        pool.put(page_id, root).unwrap();
+        pool.write_through(&page_id).unwrap();
         superblock.set_root(page_id);
-        superblock.flush_sb().unwrap();
+        superblock.flush_sb(pool.pager_mut()).unwrap();
         Self {
             root: page_id,
             superblock,
             pool,
+            pins: vec![],
+            log: WriteLog::default(),
+            readers: vec![],
         }
     }
 
-    pub fn open<P: AsRef<Path>>(path: P) -> Self {
+    /// Open the tree at `path`, which fails with `Error::IncompatibleFormat` if it needs an
+    /// `upgrade`
+    fn load<Q: AsRef<Path>>(path: Q, key: Option<&Key>) -> Result<Self, Error> {
         let cfg = CFG.get_or_init(|| crate::Args::default());
         if Superblock::exists(&path) {
-            let superblock = Superblock::open(path);
+            let version = Superblock::format_version(&path)?;
+            if version != FORMAT_VERSION {
+                return Err(Error::IncompatibleFormat(version));
+            }
+            let mut superblock = Superblock::open(path);
+            superblock.check_extents()?;
+            if superblock.encrypted != key.is_some() {
+                return Err(Error::KeyMismatch);
+            }
+            if key.is_some() {
+                superblock.epoch += 1;
+            }
             let mut pool = NodeCache::new(
                 &superblock.storage_filename,
                 false,
                 cfg.buffer_size.try_into().unwrap(),
+                key.map(|key| Seal::new(key, superblock.epoch)),
             );
+            // A wrong key fails to open the root before anything is written
             let root = superblock.root;
-            assert!(pool.get(&root).is_root());
-            Self {
+            if !pool.get(&root)?.is_root() {
+                return Err(Error::UnexpectedError(format!(
+                    "Page {} is not a root",
+                    root
+                )));
+            }
+            // Persist the new epoch before any node is sealed with it
+            if key.is_some() {
+                superblock.flush_sb(pool.pager_mut())?;
+            }
+            Ok(Self {
                 root,
                 superblock,
                 pool,
-            }
+                pins: vec![],
+                log: WriteLog::default(),
+                readers: vec![],
+            })
         } else {
-            Self::new(path)
+            Ok(Self::create(path, key, Comparator::Bytewise))
+        }
+    }
+
+    /// Open the tree at `path` in any format from 2 on, only to be read by `upgrade` in the
+    /// layout of its version. The epoch is not bumped, as nothing is sealed.
+    pub(crate) fn open_to_upgrade<Q: AsRef<Path>>(
+        path: Q,
+        key: Option<&Key>,
+    ) -> Result<Self, Error> {
+        let cfg = CFG.get_or_init(crate::Args::default);
+        let superblock = Superblock::open(path);
+        if superblock.encrypted != key.is_some() {
+            return Err(Error::KeyMismatch);
         }
+        let pool = NodeCache::new(
+            &superblock.storage_filename,
+            false,
+            cfg.buffer_size.try_into().unwrap(),
+            key.map(|key| Seal::new(key, superblock.epoch)),
+        );
+        Ok(Self {
+            root: superblock.root,
+            superblock,
+            pool,
+            pins: vec![],
+            log: WriteLog::default(),
+            readers: vec![],
+        })
+    }
+
+    pub fn pager_mut(&mut self) -> &mut P {
+        self.pool.pager_mut()
     }
 
-    pub fn flush(&mut self) {
-        self.pool.flush();
+    /// Record `storage` as the name of the storage file in the superblock, for a caller about
+    /// to rename the storage file to it
+    pub(crate) fn rename_storage(&mut self, storage: String) -> Result<(), Error> {
+        self.superblock.storage_filename = storage;
+        self.superblock.flush_sb(self.pool.pager_mut())
+    }
+
+    pub fn comparator(&self) -> Comparator {
+        self.superblock.comparator
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.pool.flush()?;
         self.superblock.flush_wal();
-        self.superblock.flush_sb().unwrap();
+        self.superblock.flush_sb(self.pool.pager_mut())
     }
 
     pub fn print_tree(&mut self) {
@@ -317,9 +1020,12 @@ impl Betree {
         let mut new_queue = VecDeque::new();
         q.push_back(self.root);
         loop {
+            self.pool
+                .prefetch(q.make_contiguous())
+                .expect("Failed to read the tree");
             while let Some(n) = q.pop_front() {
                 count += 1;
-                let node = self.pool.get(&n);
+                let node = self.pool.get(&n).expect("Failed to read the tree");
                 let mut s = "".to_owned();
 
                 match &node.node_inner {
@@ -333,7 +1039,7 @@ impl Betree {
                         s.push_str("Internel ");
                         s.push_str(&format!(
                             "Msg buffer size: {}/{} ",
-                            internel.msg_buffer.size(),
+                            internel.get_msg_buffer_size(),
                             internel.get_msg_buffer_capacity()
                         ));
                         s.push_str(&format!(
@@ -374,6 +1080,14 @@ impl Betree {
     }
 }
 
+/// Fail with `Error::KeyOverflowError` if `key` is too long to be stored
+fn check_key_size(key: &[u8]) -> Result<(), Error> {
+    if OnDiskKey::size_of_len(key.len()) > MAX_KEY_SIZE {
+        return Err(Error::KeyOverflowError);
+    }
+    Ok(())
+}
+
 // #[cfg(test)]
 
 #[cfg(test)]
@@ -411,3 +1125,153 @@ fn test_both() {
     generate_test_file();
     // test_btree();
 }
+
+#[test]
+fn test_conditional_writes() {
+    use crate::testing::TempPath;
+    let temp = TempPath::new("conditional");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    tree.insert_if_absent(b"a".to_vec(), b"1".to_vec()).unwrap();
+    assert!(matches!(
+        tree.insert_if_absent(b"a".to_vec(), b"2".to_vec()),
+        Err(Error::KeyAlreadyExists)
+    ));
+    assert!(matches!(
+        tree.update_if_present(b"b".to_vec(), b"2".to_vec()),
+        Err(Error::KeyNotFound)
+    ));
+    assert!(matches!(
+        tree.update_if_present(b"a".to_vec(), b"3".to_vec()),
+        Ok(())
+    ));
+    assert_eq!(tree.get(b"a"), Some(b"3".to_vec()));
+    assert_eq!(tree.get(b"b"), None);
+
+    assert!(matches!(
+        tree.compare_and_swap(b"a".to_vec(), Some(b"1"), b"4".to_vec()),
+        Err(Error::ValueMismatch(Some(v))) if v == b"3"
+    ));
+    assert!(matches!(
+        tree.compare_and_swap(b"a".to_vec(), Some(b"3"), b"4".to_vec()),
+        Ok(())
+    ));
+    assert!(matches!(
+        tree.compare_and_swap(b"b".to_vec(), Some(b"4"), b"5".to_vec()),
+        Err(Error::ValueMismatch(None))
+    ));
+    assert!(matches!(
+        tree.compare_and_swap(b"b".to_vec(), None, b"5".to_vec()),
+        Ok(())
+    ));
+    assert_eq!(tree.get(b"a"), Some(b"4".to_vec()));
+    assert_eq!(tree.get(b"b"), Some(b"5".to_vec()));
+
+    let long = vec![0; MAX_KEY_SIZE + 1];
+    assert!(matches!(
+        tree.insert_if_absent(long.clone(), b"1".to_vec()),
+        Err(Error::KeyOverflowError)
+    ));
+    assert!(matches!(
+        tree.update_if_present(long.clone(), b"1".to_vec()),
+        Err(Error::KeyOverflowError)
+    ));
+    assert!(matches!(
+        tree.compare_and_swap(long, None, b"1".to_vec()),
+        Err(Error::KeyOverflowError)
+    ));
+}
+
+#[test]
+fn test_multi_get() {
+    use crate::testing::{key, TempPath};
+    let temp = TempPath::new("multi_get");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    for i in (0..20000).step_by(2) {
+        tree.insert(key(i), key(i * 7));
+    }
+    tree.flush().unwrap();
+    for i in (0..20000).step_by(10) {
+        tree.delete_range(key(i), key(i + 1));
+    }
+    tree.insert(key(3), b"buffered".to_vec());
+    let wanted: Vec<Vec<u8>> = [19998, 3, 7, 4, 10, 12000, 4, 0, 25000]
+        .iter()
+        .map(|&i| key(i))
+        .collect();
+    let keys: Vec<&[u8]> = wanted.iter().map(Vec::as_slice).collect();
+    let values = tree.multi_get(&keys);
+    let expected: Vec<Option<Vec<u8>>> = keys.iter().map(|k| tree.get(k)).collect();
+    assert_eq!(values, expected);
+    assert_eq!(values[0], Some(key(19998 * 7)));
+    assert_eq!(values[1], Some(b"buffered".to_vec()));
+    assert_eq!(values[2], None);
+    assert_eq!(values[3], Some(key(28)));
+    assert_eq!(values[4], None);
+    assert_eq!(values[3], values[6]);
+    assert!(tree.multi_get(&[]).is_empty());
+}
+
+#[test]
+fn test_bulk_load() {
+    use crate::testing::{key, TempPath};
+    let temp = TempPath::new("bulk_load");
+    let path = temp.path();
+    let pairs = |n: u64| (0..n).map(move |i| (key(i * 2), key(i * 3)));
+    {
+        let mut tree = Betree::new(path);
+        tree.insert(key(1), b"replaced".to_vec());
+        tree.bulk_load(pairs(50000)).unwrap();
+        assert_eq!(tree.get(&key(1)), None);
+        assert_eq!(tree.get(&key(20000)), Some(key(30000)));
+        assert_eq!(tree.scan(..).len(), 50000);
+        // Nothing is loaded from input out of order
+        let unsorted = [(key(1), vec![]), (key(5), vec![]), (key(3), vec![])];
+        assert!(matches!(
+            tree.bulk_load(unsorted),
+            Err(Error::UnsortedInput(k)) if k == key(3)
+        ));
+        let duplicate = [(key(1), vec![]), (key(1), vec![])];
+        assert!(matches!(
+            tree.bulk_load(duplicate),
+            Err(Error::UnsortedInput(_))
+        ));
+        assert_eq!(tree.scan(..).len(), 50000);
+        // Packed nodes split as writes go on
+        for i in 0..5000 {
+            tree.insert(key(i * 20 + 1), b"odd".to_vec());
+        }
+        tree.flush().unwrap();
+    }
+    let mut tree = Betree::open(path).unwrap();
+    assert_eq!(tree.get(&key(99998)), Some(key(149997)));
+    assert_eq!(tree.get(&key(41)), Some(b"odd".to_vec()));
+    assert_eq!(tree.scan(..).len(), 55000);
+
+    // Readers and snapshots would lose the tree they see
+    let reader = tree.reader();
+    assert!(matches!(
+        tree.bulk_load(std::iter::empty()),
+        Err(Error::LiveReaders)
+    ));
+    drop(reader);
+    let snapshot = tree.snapshot();
+    assert!(matches!(
+        tree.bulk_load(std::iter::empty()),
+        Err(Error::LiveReaders)
+    ));
+    drop(snapshot);
+    tree.bulk_load(std::iter::empty()).unwrap();
+    assert!(tree.scan(..).is_empty());
+
+    // The pages of the replaced tree are reused by later writes instead of growing the file
+    let storage = format!("{}.storage", path);
+    let len = std::fs::metadata(&storage).unwrap().len();
+    for i in 0..10000 {
+        tree.insert(key(i), b"new".to_vec());
+    }
+    tree.flush().unwrap();
+    assert_eq!(std::fs::metadata(&storage).unwrap().len(), len);
+    assert_eq!(tree.scan(..).len(), 10000);
+}
@@ -0,0 +1,10 @@
+use b_epsilon_tree::upgrade;
+
+fn main() {
+    let path = std::env::args().nth(1).expect("Usage: upgrade <tree path>");
+    match upgrade(&path) {
+        Ok(true) => println!("Upgraded {}", path),
+        Ok(false) => println!("{} is already in the current format", path),
+        Err(e) => panic!("Failed to upgrade {}: {:?}", path, e),
+    }
+}
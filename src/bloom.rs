@@ -0,0 +1,80 @@
+use crate::types::{fnv1a, PageOffset};
+
+/// Bloom filter over the keys of a message buffer, stored right after the buffer.
+/// Its length follows from the number of keys and the bits per key, 0 bits means no filter.
+#[derive(Clone, Copy)]
+pub struct Bloom<'a> {
+    bits_per_key: u8,
+    bytes: &'a [u8],
+}
+
+/// Bytes of the filter for `keys` keys
+pub fn filter_len(keys: usize, bits_per_key: u8) -> PageOffset {
+    (keys * bits_per_key as usize).div_ceil(8)
+}
+
+/// Number of probes minimizing false positives, bits per key * ln 2
+fn probes(bits_per_key: u8) -> u32 {
+    (bits_per_key as u32 * 69 / 100).clamp(1, 30)
+}
+
+/// Bit positions of `key` in a filter of `bits` bits, by double hashing
+fn positions(key: &[u8], bits: usize, bits_per_key: u8) -> impl Iterator<Item = usize> {
+    // The restart point choice already uses the plain FNV-1a, so mix it first
+    let mut h = fnv1a(key);
+    h = (h ^ (h >> 33)).wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    let delta = h.rotate_right(17) | 1;
+    (0..probes(bits_per_key) as u64)
+        .map(move |i| (h.wrapping_add(i.wrapping_mul(delta)) % bits as u64) as usize)
+}
+
+/// Build the filter of `keys`, which must yield `len` keys
+pub fn build<'k>(keys: impl Iterator<Item = &'k [u8]>, len: usize, bits_per_key: u8) -> Vec<u8> {
+    let mut bytes = vec![0u8; filter_len(len, bits_per_key)];
+    if bytes.is_empty() {
+        return bytes;
+    }
+    let bits = bytes.len() * 8;
+    keys.for_each(|key| {
+        positions(key, bits, bits_per_key).for_each(|p| bytes[p / 8] |= 1 << (p % 8))
+    });
+    bytes
+}
+
+impl<'a> Bloom<'a> {
+    pub fn new(bytes: &'a [u8], bits_per_key: u8) -> Self {
+        Self {
+            bits_per_key,
+            bytes,
+        }
+    }
+
+    /// False only if `key` is certainly not in the buffer
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        if self.bits_per_key == 0 {
+            return true;
+        }
+        let bits = self.bytes.len() * 8;
+        bits > 0
+            && positions(key, bits, self.bits_per_key)
+                .all(|p| self.bytes[p / 8] & (1 << (p % 8)) != 0)
+    }
+}
+
+#[test]
+fn test_bloom() {
+    let keys: Vec<_> = (0..1000u64).map(|i| (i * 2).to_be_bytes()).collect();
+    let bytes = build(keys.iter().map(|k| &k[..]), keys.len(), 10);
+    assert_eq!(bytes.len(), 1250);
+    let bloom = Bloom::new(&bytes, 10);
+    assert!(keys.iter().all(|k| bloom.may_contain(k)));
+    let false_positives = (0..1000u64)
+        .filter(|i| bloom.may_contain(&(i * 2 + 1).to_be_bytes()))
+        .count();
+    // About 1% expected with 10 bits per key
+    assert!(false_positives < 30, "{}", false_positives);
+
+    assert!(!Bloom::new(&[], 10).may_contain(b"key"));
+    assert!(Bloom::new(&[], 0).may_contain(b"key"));
+}
@@ -0,0 +1,310 @@
+use crate::error::Error;
+use crate::node::{ChildId, NodeType};
+use crate::pager::Pager;
+use crate::pool::NodeCache;
+use crate::scan::{self, Hidden, KeyRange, LATEST};
+use crate::types::OnDiskKey;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ops::{Bound, RangeBounds};
+
+/// States of keys set by messages buffered above a subtree, None for deleted keys
+type Pending = BTreeMap<OnDiskKey, Option<Vec<u8>>>;
+
+/// How one key differs between two versions of a tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Removed {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Changed {
+        key: Vec<u8>,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+}
+
+/// Subtrees of both versions to compare over `range`
+struct Task {
+    old: ChildId,
+    new: ChildId,
+    range: KeyRange,
+    old_pending: Pending,
+    new_pending: Pending,
+    old_hidden: Hidden,
+    new_hidden: Hidden,
+}
+
+/// Key-level differences between two versions of a tree, in key order. Subtrees the versions
+/// share are never read, only the keys buffered above them are looked up
+pub struct Diff<'a, P: Pager> {
+    pool: &'a mut NodeCache<P>,
+    tasks: Vec<Task>,
+    changes: VecDeque<Change>,
+}
+
+impl<'a, P: Pager> Diff<'a, P> {
+    pub(crate) fn new(pool: &'a mut NodeCache<P>, old: ChildId, new: ChildId) -> Self {
+        let task = Task {
+            old,
+            new,
+            range: (Bound::Unbounded, Bound::Unbounded),
+            old_pending: Pending::new(),
+            new_pending: Pending::new(),
+            old_hidden: Hidden::new(),
+            new_hidden: Hidden::new(),
+        };
+        Self {
+            pool,
+            tasks: vec![task],
+            changes: VecDeque::new(),
+        }
+    }
+
+    fn run(&mut self, task: Task) -> Result<(), Error> {
+        let Task {
+            old,
+            new,
+            range,
+            mut old_pending,
+            mut new_pending,
+            mut old_hidden,
+            mut new_hidden,
+        } = task;
+        if old == new && old_hidden == new_hidden {
+            return self.compare_pending(old, old_pending, new_pending, &old_hidden);
+        }
+        let old_children = self.expand(old, &range, &mut old_pending, &mut old_hidden)?;
+        let new_children = self.expand(new, &range, &mut new_pending, &mut new_hidden)?;
+        let (Some(old_children), Some(new_children)) = (old_children, new_children) else {
+            // Leaves are small, so the subtrees are compared in full
+            scan::scan_subtree(
+                self.pool,
+                old,
+                &range,
+                LATEST,
+                &old_hidden,
+                &mut old_pending,
+            )?;
+            scan::scan_subtree(
+                self.pool,
+                new,
+                &range,
+                LATEST,
+                &new_hidden,
+                &mut new_pending,
+            )?;
+            self.compare(old_pending, new_pending);
+            return Ok(());
+        };
+
+        // Split the range at the pivots of either node, so each piece has one child on each side
+        let pivots: BTreeSet<&OnDiskKey> = old_children
+            .pivots
+            .iter()
+            .chain(new_children.pivots.iter())
+            .map(|(pivot, _)| pivot)
+            .filter(|&pivot| {
+                range.contains(pivot) && !matches!(&range.0, Bound::Included(l) if l == pivot)
+            })
+            .collect();
+        let lowers = std::iter::once(range.0.clone())
+            .chain(pivots.iter().map(|p| Bound::Included((*p).clone())));
+        let uppers = pivots
+            .iter()
+            .map(|p| Bound::Excluded((*p).clone()))
+            .chain(std::iter::once(range.1.clone()));
+        let mut pieces: Vec<(ChildId, ChildId, KeyRange)> = vec![];
+        for (lower, upper) in lowers.zip(uppers) {
+            let pair = (old_children.covering(&lower), new_children.covering(&lower));
+            match pieces.last_mut() {
+                // Neighbouring pieces under the same children are compared together
+                Some((o, n, piece)) if (*o, *n) == pair => piece.1 = upper,
+                _ => pieces.push((pair.0, pair.1, (lower, upper))),
+            }
+        }
+        let restrict = |pending: &Pending, range: &KeyRange| -> Pending {
+            pending
+                .range(range.clone())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        };
+        for (old, new, range) in pieces.into_iter().rev() {
+            self.tasks.push(Task {
+                old,
+                new,
+                old_pending: restrict(&old_pending, &range),
+                new_pending: restrict(&new_pending, &range),
+                old_hidden: old_hidden.clone(),
+                new_hidden: new_hidden.clone(),
+                range,
+            });
+        }
+        Ok(())
+    }
+
+    /// Add the messages `page` buffers in `range` below those already pending and the ranges
+    /// it deletes to `hidden`, and return its children, or None for a leaf
+    fn expand(
+        &mut self,
+        page: ChildId,
+        range: &KeyRange,
+        pending: &mut Pending,
+        hidden: &mut Hidden,
+    ) -> Result<Option<Children>, Error> {
+        Ok(match &self.pool.get(&page)?.node_inner {
+            NodeType::Leaf(_) => None,
+            NodeType::Internal(internal) => {
+                scan::buffered(internal, range, LATEST, hidden, pending);
+                Some(Children {
+                    pivots: internal
+                        .pivot_map
+                        .iter()
+                        .map(|(k, c)| (k.clone(), *c))
+                        .collect(),
+                    rightmost: internal.rightmost_child,
+                })
+            }
+            _ => unimplemented!(),
+        })
+    }
+
+    /// Both versions share the subtree at `page` and the ranges deleted above it, so only keys
+    /// buffered above it can differ
+    fn compare_pending(
+        &mut self,
+        page: ChildId,
+        mut old: Pending,
+        mut new: Pending,
+        hidden: &Hidden,
+    ) -> Result<(), Error> {
+        if old == new {
+            return Ok(());
+        }
+        let keys: BTreeSet<OnDiskKey> = old.keys().chain(new.keys()).cloned().collect();
+        for key in keys {
+            if old.get(&key).is_some_and(|o| new.get(&key) == Some(o)) {
+                continue;
+            }
+            let below = |pool: &mut NodeCache<P>| match scan::is_hidden(hidden, &key) {
+                true => Ok(None),
+                false => scan::lookup(pool, page, &key, LATEST),
+            };
+            let old_state = match old.remove(&key) {
+                Some(state) => state,
+                None => below(self.pool)?,
+            };
+            let new_state = match new.remove(&key) {
+                Some(state) => state,
+                None => below(self.pool)?,
+            };
+            self.push(&key, old_state, new_state);
+        }
+        Ok(())
+    }
+
+    fn compare(&mut self, mut old: Pending, mut new: Pending) {
+        let keys: BTreeSet<OnDiskKey> = old.keys().chain(new.keys()).cloned().collect();
+        for key in keys {
+            let old_state = old.remove(&key).flatten();
+            let new_state = new.remove(&key).flatten();
+            self.push(&key, old_state, new_state);
+        }
+    }
+
+    fn push(&mut self, key: &OnDiskKey, old: Option<Vec<u8>>, new: Option<Vec<u8>>) {
+        let key = key.to_vec();
+        let change = match (old, new) {
+            (None, Some(value)) => Change::Added { key, value },
+            (Some(value), None) => Change::Removed { key, value },
+            (Some(old), Some(new)) if old != new => Change::Changed { key, old, new },
+            _ => return,
+        };
+        self.changes.push_back(change);
+    }
+}
+
+impl<P: Pager> Iterator for Diff<'_, P> {
+    type Item = Change;
+
+    fn next(&mut self) -> Option<Change> {
+        while self.changes.is_empty() {
+            let task = self.tasks.pop()?;
+            self.run(task).expect("Failed to read the tree");
+        }
+        self.changes.pop_front()
+    }
+}
+
+/// Pivots and children of an internal node
+struct Children {
+    pivots: Vec<(OnDiskKey, ChildId)>,
+    rightmost: ChildId,
+}
+
+impl Children {
+    /// The child holding the keys from `lower` on
+    fn covering(&self, lower: &Bound<OnDiskKey>) -> ChildId {
+        let first_above = match lower {
+            Bound::Unbounded => self.pivots.first(),
+            Bound::Included(k) | Bound::Excluded(k) => self.pivots.iter().find(|(p, _)| p > k),
+        };
+        first_above.map_or(self.rightmost, |(_, child)| *child)
+    }
+}
+
+#[test]
+fn test_diff() {
+    use crate::testing::{key, TempPath};
+    use crate::Betree;
+    let temp = TempPath::new("diff");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    for i in 0..3000 {
+        tree.insert(key(i), b"a".to_vec());
+    }
+    tree.checkpoint("a").unwrap();
+    for i in (0..3000).step_by(97).chain(3000..3100) {
+        tree.insert(key(i), b"b".to_vec());
+    }
+    tree.checkpoint("b").unwrap();
+
+    // Agrees with comparing full scans of both versions
+    let a = tree.at_checkpoint("a").unwrap().scan(..);
+    let b: BTreeMap<Vec<u8>, Vec<u8>> = tree
+        .at_checkpoint("b")
+        .unwrap()
+        .scan(..)
+        .into_iter()
+        .collect();
+    let mut expected = vec![];
+    for (k, v) in a.iter() {
+        match b.get(k) {
+            Some(n) if n != v => expected.push(Change::Changed {
+                key: k.clone(),
+                old: v.clone(),
+                new: n.clone(),
+            }),
+            _ => {}
+        }
+    }
+    expected.extend((3000..3100).map(|i| Change::Added {
+        key: key(i),
+        value: b"b".to_vec(),
+    }));
+    let changes: Vec<Change> = tree.diff_checkpoints("a", "b").unwrap().collect();
+    assert_eq!(changes.len(), 31 + 100);
+    assert_eq!(changes, expected);
+
+    let removed = tree
+        .diff_checkpoints("b", "a")
+        .unwrap()
+        .filter(|c| matches!(c, Change::Removed { .. }));
+    assert_eq!(removed.count(), 100);
+    assert_eq!(tree.diff_checkpoints("a", "a").unwrap().count(), 0);
+    assert!(tree.diff_checkpoints("a", "c").is_err());
+}
@@ -0,0 +1,86 @@
+use crate::error::Error;
+use crate::page::{Page, PAGESIZE};
+use crate::pager::{write_runs, PageId, Pager};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+
+/// A `Pager` that opens the storage file with O_DIRECT so the `NodeCache` is the only cache.
+/// `Page` is 4096-aligned, which satisfies the O_DIRECT buffer requirement.
+/// With `DSYNC`, the file is also opened with O_DSYNC and every write is durable on return.
+pub struct DirectPager<const DSYNC: bool = false> {
+    file: File,
+}
+
+impl<const DSYNC: bool> DirectPager<DSYNC> {
+    fn flags() -> i32 {
+        if DSYNC {
+            libc::O_DIRECT | libc::O_DSYNC
+        } else {
+            libc::O_DIRECT
+        }
+    }
+}
+
+impl<const DSYNC: bool> Pager for DirectPager<DSYNC> {
+    fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let fd = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .custom_flags(Self::flags())
+            .open(path)?;
+        Ok(Self { file: fd })
+    }
+
+    fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let fd = OpenOptions::new()
+            .create(false)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .custom_flags(Self::flags())
+            .open(path)?;
+        Ok(Self { file: fd })
+    }
+
+    fn read(&self, page_id: &PageId, page: &mut Page) -> Result<(), Error> {
+        self.file.read_exact_at(page.into(), page_id * PAGESIZE)?;
+        Ok(())
+    }
+
+    fn write(&mut self, page_id: &PageId, data: &Page) -> Result<(), Error> {
+        self.file.write_all_at(data.into(), page_id * PAGESIZE)?;
+        Ok(())
+    }
+
+    fn write_many(&mut self, pages: &[(PageId, Page)]) -> Result<(), Error> {
+        write_runs(&self.file, pages)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        // O_DIRECT bypasses the page cache but not the device cache, and file size changes
+        // are metadata
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_direct_persist() {
+    use crate::testing::TempPath;
+    let temp = TempPath::new("direct");
+    let path = temp.path();
+    let mut pager = DirectPager::<true>::new(path).unwrap();
+    let mut a = Page::default();
+    a.fill(7);
+    let page_id = 5;
+    pager.write(&page_id, &a).unwrap();
+    core::mem::drop(pager);
+
+    let pager = DirectPager::<false>::open(path).unwrap();
+    let mut b = Page::default();
+    pager.read(&page_id, &mut b).unwrap();
+    assert_eq!(&a[..], &b[..]);
+}
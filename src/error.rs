@@ -1,3 +1,6 @@
+use crate::pager::PageId;
+use crate::types::Comparator;
+
 #[derive(Debug)]
 pub enum Error {
     KeyNotFound,
@@ -7,6 +10,60 @@ pub enum Error {
     ValueOverflowError,
     TryFromSliceError(&'static str),
     UTF8Error,
+    /// The page failed its authentication check
+    ChecksumMismatch(PageId),
+    /// A key was given for a tree that is not encrypted, or none for one that is
+    KeyMismatch,
+    /// The tree was written in another format version, see `upgrade`
+    IncompatibleFormat(u32),
+    /// The tree was written with these pages per node and codec, which this build does not use.
+    /// Only a build with the same `lz4`/`zstd` features opens it
+    IncompatibleBuild {
+        node_pages: u8,
+        codec: u8,
+    },
+    /// A typed key or value could not be encoded or decoded
+    SerdeError(String),
+    /// No tree of the database has this name
+    TreeNotFound(String),
+    /// A tree of the database already has this name
+    TreeAlreadyExists(String),
+    /// The superblock has no room left for another tree name or checkpoint
+    SuperblockFull,
+    /// No checkpoint has this name
+    CheckpointNotFound(String),
+    /// A checkpoint already has this name
+    CheckpointAlreadyExists(String),
+    /// A key the transaction read was written after it began
+    TransactionConflict(Vec<u8>),
+    /// The key did not hold the expected value, which it holds instead
+    ValueMismatch(Option<Vec<u8>>),
+    /// A bulk loaded key did not come after the one before it
+    UnsortedInput(Vec<u8>),
+    /// A reader or snapshot of the tree is still alive
+    LiveReaders,
+    /// Typed keys need a tree that orders keys bytewise, not by this comparator
+    ComparatorMismatch(Comparator),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl serde::ser::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Error::SerdeError(msg.to_string())
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Error::SerdeError(msg.to_string())
+    }
 }
 
 impl std::convert::From<std::io::Error> for Error {
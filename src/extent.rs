@@ -0,0 +1,233 @@
+use crate::error::Error;
+use crate::node::Node;
+use crate::page::{Page, NODE_PAYLOAD, NODE_SIZE, PAGESIZE};
+use crate::pager::{PageId, Pager};
+use crate::seal::{self, Seal, SEALED_MAGIC, SEAL_SIZE};
+use crate::types::{Serializable, SizedOnDisk};
+use std::borrow::Cow;
+
+/// On disk represenation of a node reserving NODE_PAGES pages:
+/// raw: the serialized node(starting with the node MAGIC), NODE_PAGES pages
+/// compressed: EXTENT_MAGIC, codec: 1 byte, payload length: 4 bytes, payload;
+/// only used when it saves at least one page
+/// With a key, either of them is wrapped in a sealed envelope(see seal.rs)
+const EXTENT_MAGIC: u64 = 0x2b83c1e5a9d0f47;
+const HEADER_SIZE: usize = 8 + 1 + 4;
+
+#[cfg(not(any(feature = "lz4", feature = "zstd")))]
+const CODEC_NONE: u8 = 0;
+#[cfg(feature = "lz4")]
+const CODEC_LZ4: u8 = 1;
+#[cfg(feature = "zstd")]
+const CODEC_ZSTD: u8 = 2;
+
+/// Codec this build compresses nodes with, recorded in the superblock
+#[cfg(feature = "zstd")]
+pub const CODEC: u8 = CODEC_ZSTD;
+#[cfg(all(feature = "lz4", not(feature = "zstd")))]
+pub const CODEC: u8 = CODEC_LZ4;
+#[cfg(not(any(feature = "lz4", feature = "zstd")))]
+pub const CODEC: u8 = CODEC_NONE;
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+#[cfg(feature = "zstd")]
+fn compress(raw: &[u8]) -> Option<(u8, Vec<u8>)> {
+    zstd::bulk::compress(raw, ZSTD_LEVEL)
+        .ok()
+        .map(|c| (CODEC_ZSTD, c))
+}
+
+#[cfg(all(feature = "lz4", not(feature = "zstd")))]
+fn compress(raw: &[u8]) -> Option<(u8, Vec<u8>)> {
+    Some((CODEC_LZ4, lz4_flex::block::compress(raw)))
+}
+
+#[cfg(not(any(feature = "lz4", feature = "zstd")))]
+fn compress(_raw: &[u8]) -> Option<(u8, Vec<u8>)> {
+    None
+}
+
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn decompress(codec: u8, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    match codec {
+        #[cfg(feature = "lz4")]
+        CODEC_LZ4 => lz4_flex::block::decompress(payload, NODE_PAYLOAD as usize)
+            .map_err(|e| Error::UnexpectedError(e.to_string())),
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD => Ok(zstd::bulk::decompress(payload, NODE_PAYLOAD as usize)?),
+        _ => Err(Error::UnexpectedError(format!(
+            "Unsupported node codec: {}",
+            codec
+        ))),
+    }
+}
+
+/// Split a node into the pages of its extent starting at `page_id`
+pub fn encode(page_id: PageId, node: &Node, seal: Option<&Seal>) -> Vec<(PageId, Page)> {
+    let raw = node.to_bytes().unwrap();
+    let bytes = match compress(&raw) {
+        Some((codec, payload))
+            if HEADER_SIZE + payload.len() + SEAL_SIZE <= (NODE_SIZE - PAGESIZE) as usize =>
+        {
+            let mut bytes = vec![0; HEADER_SIZE + payload.len()];
+            let mut _cursor = 0;
+            let len = payload.len() as u32;
+            serialize!(EXTENT_MAGIC, bytes, _cursor);
+            serialize!(codec, bytes, _cursor);
+            serialize!(len, bytes, _cursor);
+            bytes[_cursor..].copy_from_slice(&payload);
+            bytes
+        }
+        _ => raw,
+    };
+    let bytes = match seal {
+        Some(seal) => seal.seal(page_id, &bytes),
+        None => bytes,
+    };
+    bytes
+        .chunks(PAGESIZE as usize)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut page = Page::default();
+            page[..chunk.len()].copy_from_slice(chunk);
+            (page_id + i as PageId, page)
+        })
+        .collect()
+}
+
+/// Length in bytes of the extent whose first page is `first`
+fn extent_len(first: &[u8]) -> usize {
+    let mut _cursor = 0;
+    deserialize_with_var!(magic, u64, first, _cursor);
+    if magic == SEALED_MAGIC {
+        return seal::sealed_len(first);
+    }
+    if magic != EXTENT_MAGIC {
+        return NODE_PAYLOAD as usize;
+    }
+    _cursor += 1;
+    deserialize_with_var!(len, u32, first, _cursor);
+    HEADER_SIZE + len as usize
+}
+
+/// The serialized node in the bytes of its extent at `page_id`, decrypted and decompressed as
+/// needed; with a seal, only sealed extents that pass the authentication check are accepted
+pub fn node_bytes<'a>(
+    page_id: &PageId,
+    bytes: &'a [u8],
+    seal: Option<&Seal>,
+) -> Result<Cow<'a, [u8]>, Error> {
+    let mut _cursor = 0;
+    deserialize_with_var!(magic, u64, bytes, _cursor);
+    match (magic == SEALED_MAGIC, seal) {
+        (true, Some(seal)) => {
+            let plain = seal.open(*page_id, bytes)?;
+            return Ok(Cow::Owned(node_bytes(page_id, &plain, None)?.into_owned()));
+        }
+        (true, None) => {
+            return Err(Error::UnexpectedError(format!(
+                "Node {} is encrypted but no key was supplied",
+                page_id
+            )))
+        }
+        (false, Some(_)) => return Err(Error::ChecksumMismatch(*page_id)),
+        (false, None) => {}
+    }
+    if magic != EXTENT_MAGIC {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    deserialize_with_var!(codec, u8, bytes, _cursor);
+    deserialize_with_var!(len, u32, bytes, _cursor);
+    Ok(Cow::Owned(decompress(
+        codec,
+        &bytes[_cursor.._cursor + len as usize],
+    )?))
+}
+
+/// Decode a node from the bytes of its extent at `page_id`
+pub fn decode(page_id: &PageId, bytes: &[u8], seal: Option<&Seal>) -> Result<Node, Error> {
+    node_bytes(page_id, bytes, seal)?.as_ref().try_into()
+}
+
+/// Read the rest of the extent whose first page is `first` and decode the node
+pub fn read_node<P: Pager>(
+    pager: &P,
+    page_id: &PageId,
+    first: Page,
+    seal: Option<&Seal>,
+) -> Result<Node, Error> {
+    if extent_len(&first[..]) <= PAGESIZE as usize {
+        return decode(page_id, &first[..], seal);
+    }
+    decode(page_id, &read_extent(pager, page_id, first)?, seal)
+}
+
+/// Read the rest of the extent whose first page is `first` and return the serialized node
+pub fn read_node_bytes<P: Pager>(
+    pager: &P,
+    page_id: &PageId,
+    first: Page,
+    seal: Option<&Seal>,
+) -> Result<Vec<u8>, Error> {
+    let extent = read_extent(pager, page_id, first)?;
+    let decoded = match node_bytes(page_id, &extent, seal)? {
+        Cow::Borrowed(_) => None,
+        Cow::Owned(bytes) => Some(bytes),
+    };
+    Ok(decoded.unwrap_or(extent))
+}
+
+fn read_extent<P: Pager>(pager: &P, page_id: &PageId, first: Page) -> Result<Vec<u8>, Error> {
+    let len = extent_len(&first[..]);
+    if len <= PAGESIZE as usize {
+        return Ok(first[..].to_vec());
+    }
+    if len > NODE_SIZE as usize {
+        return Err(Error::ChecksumMismatch(*page_id));
+    }
+    let pages = (len + PAGESIZE as usize - 1) / PAGESIZE as usize;
+    let mut rest: Vec<_> = (1..pages as PageId)
+        .map(|i| (page_id + i, Page::default()))
+        .collect();
+    pager.read_many(&mut rest)?;
+    let mut bytes = Vec::with_capacity(pages * PAGESIZE as usize);
+    bytes.extend_from_slice(&first[..]);
+    rest.iter()
+        .for_each(|(_, page)| bytes.extend_from_slice(&page[..]));
+    Ok(bytes)
+}
+
+#[test]
+fn test_extent_roundtrip() {
+    use crate::node::NodeType;
+    use crate::page::NODE_PAGES;
+    use crate::types::{MessageData, MessageType, OnDiskKey};
+    let mut node = Node::new_empty_leaf(false);
+    let mut count = 0u64;
+    if let NodeType::Leaf(leaf) = &mut node.node_inner {
+        // Shared-prefix keys like the big-endian u64s of the benchmark
+        while leaf.size() < leaf.get_kv_capacity() * 3 / 4 {
+            let key = OnDiskKey::new(count.to_be_bytes().to_vec());
+            leaf.apply(key, MessageData::new(MessageType::Insert, vec![7; 8]), &[]);
+            count += 1;
+        }
+    }
+    let pages = encode(NODE_PAGES, &node, None);
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    assert!((pages.len() as PageId) < NODE_PAGES);
+    #[cfg(not(any(feature = "lz4", feature = "zstd")))]
+    assert_eq!(pages.len() as PageId, NODE_PAGES);
+
+    let bytes: Vec<u8> = pages.iter().flat_map(|(_, p)| p.iter().copied()).collect();
+    assert!(extent_len(&bytes) <= bytes.len());
+    let decoded = decode(&NODE_PAGES, &bytes, None).unwrap();
+    if let NodeType::Leaf(leaf) = &decoded.node_inner {
+        for i in 0..count {
+            let key = OnDiskKey::new(i.to_be_bytes().to_vec());
+            assert_eq!(leaf.get(&key, u64::MAX), Some(&[7u8; 8][..]));
+        }
+    } else {
+        panic!("Decoded a leaf as internal node");
+    }
+}
@@ -0,0 +1,384 @@
+use crate::error::Error;
+use crate::page::Page;
+use crate::pager::{PageId, Pager};
+use std::collections::HashSet;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Granularity at which a write can be torn
+pub const SECTOR_SIZE: usize = 512;
+
+/// What reaches the disk of the writes issued since the last `flush` when the device crashes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CrashMode {
+    /// Every unsynced write is lost
+    #[default]
+    DropUnsynced,
+    /// A random prefix of the unsynced writes survives, in issue order
+    Prefix,
+    /// A random subset of the unsynced writes survives, in any order
+    Reorder,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FaultConfig {
+    /// Seed of the pseudo-random choices made when crashing
+    pub seed: u64,
+    /// The operation with this index(counted from when the config is armed) and every later
+    /// one fail, as if the machine lost power right before it. Writes and flushes of the
+    /// storage and writes and syncs of the superblock are operations
+    pub crash_at: Option<u64>,
+    /// Writes of pages or of the superblock with these operation indices fail with an I/O error
+    /// without crashing the device
+    pub fail_writes: HashSet<u64>,
+    pub mode: CrashMode,
+    /// Surviving unsynced writes may be torn at SECTOR_SIZE granularity
+    pub tear: bool,
+}
+
+/// A write kept in memory until its file is synced
+enum Unsynced {
+    Page(PageId, Page),
+    /// Offset and bytes of a write to the superblock file
+    Superblock(File, u64, Vec<u8>),
+}
+
+/// A `Pager` wrapper that keeps writes in memory until `flush` and injects faults. Writes to the
+/// superblock file go through it as well, until `sync_superblock`.
+/// Operations made after a crash return errors.
+pub struct FaultPager<P: Pager> {
+    inner: P,
+    config: FaultConfig,
+    unsynced: Vec<Unsynced>,
+    operations: u64,
+    crashed: bool,
+    rng: u64,
+}
+
+impl<P: Pager> FaultPager<P> {
+    fn wrap(inner: P) -> Self {
+        Self {
+            inner,
+            config: FaultConfig::default(),
+            unsynced: vec![],
+            operations: 0,
+            crashed: false,
+            rng: 0,
+        }
+    }
+
+    /// Start injecting faults; operation indices are counted from here
+    pub fn arm(&mut self, config: FaultConfig) {
+        // xorshift must not start from 0
+        self.rng = config.seed | 1;
+        self.config = config;
+        self.operations = 0;
+    }
+
+    pub fn operations(&self) -> u64 {
+        self.operations
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.crashed
+    }
+
+    fn next_rand(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn coin(&mut self) -> bool {
+        self.next_rand() & 1 == 0
+    }
+
+    fn dead() -> Error {
+        Error::UnexpectedError("injected crash".to_owned())
+    }
+
+    /// Count an operation, and crash or fail it as configured
+    fn operation(&mut self, write: bool) -> Result<(), Error> {
+        if self.crashed {
+            return Err(Self::dead());
+        }
+        let index = self.operations;
+        self.operations += 1;
+        if self.config.crash_at == Some(index) {
+            self.crash();
+            return Err(Self::dead());
+        }
+        if write && self.config.fail_writes.contains(&index) {
+            return Err(Error::UnexpectedError(format!(
+                "injected write error: {}",
+                index
+            )));
+        }
+        Ok(())
+    }
+
+    /// Decide which unsynced writes survive and put them on disk
+    fn crash(&mut self) {
+        self.crashed = true;
+        let mut unsynced = core::mem::take(&mut self.unsynced);
+        let survivors = match self.config.mode {
+            CrashMode::DropUnsynced => vec![],
+            CrashMode::Prefix => {
+                let len = self.next_rand() as usize % (unsynced.len() + 1);
+                unsynced.truncate(len);
+                unsynced
+            }
+            CrashMode::Reorder => {
+                let mut survivors = vec![];
+                for write in unsynced {
+                    if self.coin() {
+                        survivors.push(write);
+                    }
+                }
+                survivors
+            }
+        };
+        for write in survivors {
+            let torn = self.config.tear && self.coin();
+            match write {
+                Unsynced::Page(page_id, mut page) => {
+                    if torn {
+                        let mut old = Page::default();
+                        if self.inner.read(&page_id, &mut old).is_err() {
+                            old = Page::default();
+                        }
+                        self.tear(&mut old[..], &page[..]);
+                        page = old;
+                    }
+                    let _ = self.inner.write(&page_id, &page);
+                }
+                Unsynced::Superblock(file, offset, mut data) => {
+                    if torn {
+                        let mut old = vec![0; data.len()];
+                        if file.read_exact_at(&mut old, offset).is_err() {
+                            old.fill(0);
+                        }
+                        self.tear(&mut old, &data);
+                        data = old;
+                    }
+                    let _ = file.write_all_at(&data, offset);
+                    let _ = file.sync_all();
+                }
+            }
+        }
+        let _ = self.inner.flush();
+    }
+
+    /// Mix the new content of a write into what is on disk, sector by sector
+    fn tear(&mut self, old: &mut [u8], new: &[u8]) {
+        for (old, new) in old.chunks_mut(SECTOR_SIZE).zip(new.chunks(SECTOR_SIZE)) {
+            if self.coin() {
+                old.copy_from_slice(new);
+            }
+        }
+    }
+}
+
+impl<P: Pager> Pager for FaultPager<P> {
+    fn new<Q: AsRef<Path>>(path: Q) -> Result<Self, Error> {
+        Ok(Self::wrap(P::new(path)?))
+    }
+
+    fn open<Q: AsRef<Path>>(path: Q) -> Result<Self, Error> {
+        Ok(Self::wrap(P::open(path)?))
+    }
+
+    fn read(&self, page_id: &PageId, page: &mut Page) -> Result<(), Error> {
+        if self.crashed {
+            return Err(Self::dead());
+        }
+        let unsynced = self.unsynced.iter().rev().find_map(|write| match write {
+            Unsynced::Page(p, data) if p == page_id => Some(data),
+            _ => None,
+        });
+        if let Some(data) = unsynced {
+            page.copy_from_slice(&data[..]);
+            return Ok(());
+        }
+        self.inner.read(page_id, page)
+    }
+
+    fn write(&mut self, page_id: &PageId, data: &Page) -> Result<(), Error> {
+        self.operation(true)?;
+        self.unsynced.push(Unsynced::Page(*page_id, data.clone()));
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.operation(false)?;
+        let (pages, rest) = core::mem::take(&mut self.unsynced)
+            .into_iter()
+            .partition(|write| matches!(write, Unsynced::Page(..)));
+        self.unsynced = rest;
+        for write in pages {
+            if let Unsynced::Page(page_id, page) = write {
+                self.inner.write(&page_id, &page)?;
+            }
+        }
+        self.inner.flush()
+    }
+
+    fn write_superblock(&mut self, file: &File, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.operation(true)?;
+        let write = Unsynced::Superblock(file.try_clone()?, offset, data.to_vec());
+        self.unsynced.push(write);
+        Ok(())
+    }
+
+    fn sync_superblock(&mut self, file: &File) -> Result<(), Error> {
+        self.operation(false)?;
+        let (writes, rest) = core::mem::take(&mut self.unsynced)
+            .into_iter()
+            .partition(|write| matches!(write, Unsynced::Superblock(..)));
+        self.unsynced = rest;
+        for write in writes {
+            if let Unsynced::Superblock(file, offset, data) = write {
+                file.write_all_at(&data, offset)?;
+            }
+        }
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl<P: Pager> Drop for FaultPager<P> {
+    // Without a crash, the kernel would write back the unsynced writes eventually
+    fn drop(&mut self) {
+        if !self.crashed {
+            for write in core::mem::take(&mut self.unsynced) {
+                match write {
+                    Unsynced::Page(page_id, page) => {
+                        let _ = self.inner.write(&page_id, &page);
+                    }
+                    Unsynced::Superblock(file, offset, data) => {
+                        let _ = file.write_all_at(&data, offset);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Contents of the tree as of the last flush that returned, and as of the flush in progress
+#[cfg(test)]
+#[derive(Default)]
+struct Committed {
+    done: std::collections::BTreeMap<Vec<u8>, Vec<u8>>,
+    flushing: Option<std::collections::BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+#[cfg(test)]
+fn crash_workload(
+    path: &str,
+    config: FaultConfig,
+    committed: &mut Committed,
+) -> Result<u64, Error> {
+    use crate::betree::DEFAULT_TREE;
+    use crate::pager::SimplePager;
+    use crate::Betree;
+    let mut tree = Betree::<FaultPager<SimplePager>>::new_with_pager(path);
+    tree.pager_mut().arm(config);
+    let mut model = committed.done.clone();
+    for i in 0..600u64 {
+        let k = (i.wrapping_mul(0x9e3779b97f4a7c15) >> 40)
+            .to_be_bytes()
+            .to_vec();
+        let v = vec![i as u8; 48];
+        tree.insert_into(DEFAULT_TREE, k.clone(), v.clone())?;
+        model.insert(k, v);
+        if i % 150 == 149 {
+            // Once the superblock write is issued, a crash may or may not keep it
+            committed.flushing = Some(model.clone());
+            tree.flush()?;
+            committed.done = model.clone();
+            committed.flushing = None;
+        }
+    }
+    Ok(tree.pager_mut().operations())
+}
+
+#[test]
+fn crash_at_every_operation() {
+    use crate::testing::TempPath;
+    use crate::Betree;
+    let temp = TempPath::new("crash");
+    let total = crash_workload(
+        temp.path(),
+        FaultConfig::default(),
+        &mut Committed::default(),
+    )
+    .unwrap();
+    assert!(total > 0);
+    let modes = [
+        CrashMode::DropUnsynced,
+        CrashMode::Prefix,
+        CrashMode::Reorder,
+    ];
+    // Every write, storage fsync, superblock write and superblock sync is a crash point
+    for crash_at in 0..total {
+        let mode = modes[crash_at as usize % modes.len()];
+        let config = FaultConfig {
+            seed: crash_at,
+            crash_at: Some(crash_at),
+            mode,
+            tear: true,
+            ..Default::default()
+        };
+        let mut committed = Committed::default();
+        let temp = TempPath::new("crash");
+        let path = temp.path();
+        let res = crash_workload(path, config, &mut committed);
+        assert!(res.is_err(), "No crash at operation {}", crash_at);
+
+        let mut tree = Betree::open(path).unwrap();
+        let found: std::collections::BTreeMap<Vec<u8>, Vec<u8>> =
+            tree.scan(..).into_iter().collect();
+        assert!(
+            found == committed.done || Some(&found) == committed.flushing.as_ref(),
+            "Crash at operation {} with {:?}",
+            crash_at,
+            mode
+        );
+    }
+}
+
+#[test]
+fn failed_writes_surface_and_retry() {
+    use crate::pager::SimplePager;
+    use crate::testing::{key, TempPath};
+    use crate::Betree;
+    let temp = TempPath::new("fail_writes");
+    let path = temp.path();
+    {
+        let mut tree = Betree::<FaultPager<SimplePager>>::new_with_pager(path);
+        for i in 0..300 {
+            tree.insert(key(i), vec![1; 48]);
+        }
+        tree.flush().unwrap();
+        tree.pager_mut().arm(FaultConfig {
+            fail_writes: [0, 2].into(),
+            ..Default::default()
+        });
+        for i in 300..600 {
+            tree.insert(key(i), vec![2; 48]);
+        }
+        assert!(tree.flush().is_err());
+        assert!(!tree.pager_mut().crashed());
+        // The failed flush lost nothing in memory and the next one writes it all
+        assert_eq!(tree.get(&key(450)), Some(vec![2; 48]));
+        assert!(tree.flush().is_err());
+        tree.flush().unwrap();
+    }
+    let mut tree = Betree::open(path).unwrap();
+    for i in 0..600 {
+        let byte = if i < 300 { 1 } else { 2 };
+        assert_eq!(tree.get(&key(i)), Some(vec![byte; 48]), "Key {}", i);
+    }
+}
@@ -4,9 +4,11 @@
 #[macro_use]
 extern crate log;
 mod allocator;
+mod batch;
 mod mini_allocator;
 
 mod betree;
+mod bloom;
 use std::sync::OnceLock;
 
 pub use betree::*;
@@ -14,16 +16,59 @@ mod memtable;
 #[macro_use]
 mod types;
 mod data;
+mod diff;
+#[cfg(target_os = "linux")]
+mod direct_pager;
 mod error;
+mod extent;
+mod fault_pager;
+mod mmap_pager;
 mod node;
+mod ordered;
 mod page;
 mod pager;
 mod pool;
+mod range_delete;
+mod reader;
+mod scan;
+mod seal;
+mod snapshot;
 mod superblock;
+#[cfg(test)]
+mod testing;
+mod transaction;
+mod ttl;
+mod typed;
+mod upgrade;
+#[cfg(feature = "io-uring")]
+mod uring_pager;
+mod view;
 mod wal;
 
 mod args;
 pub use args::Args;
+pub use batch::WriteBatch;
+pub use diff::{Change, Diff};
+#[cfg(target_os = "linux")]
+pub use direct_pager::DirectPager;
+pub use error::Error;
+pub use fault_pager::{CrashMode, FaultConfig, FaultPager};
+pub use mmap_pager::MmapPager;
+pub use ordered::{from_key, to_key};
+pub use page::{Page, PAGESIZE};
+pub use pager::{PageId, Pager, SimplePager};
+pub use reader::Reader;
+pub use seal::Key;
+pub use snapshot::Snapshot;
+pub use superblock::Checkpoint;
+pub use transaction::Transaction;
+pub use typed::TypedBetree;
+pub use types::Comparator;
+pub use upgrade::upgrade;
+#[cfg(feature = "encryption")]
+pub use upgrade::upgrade_encrypted;
+#[cfg(feature = "io-uring")]
+pub use uring_pager::UringPager;
 
 pub(crate) static CFG: OnceLock<Args> = OnceLock::new();
 
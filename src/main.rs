@@ -2,7 +2,19 @@ use b_epsilon_tree::*;
 
 #[macro_use]
 extern crate log;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum PagerKind {
+    Simple,
+    Mmap,
+    /// O_DIRECT, bypassing the kernel page cache
+    #[cfg(target_os = "linux")]
+    Direct,
+    /// io_uring
+    #[cfg(feature = "io-uring")]
+    Uring,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -15,9 +27,17 @@ pub struct TestArgs {
     #[arg(short, long, default_value_t = 34)]
     pub buffer_size: usize,
 
+    /// Bloom filter bits per key of internal message buffers, 0 for none
+    #[arg(long, default_value_t = 0)]
+    pub bloom_bits: u8,
+
     /// Flush superblock
     #[arg(short, long, default_value_t = false)]
     pub flush_superblock: bool,
+
+    /// Pager backend
+    #[arg(short, long, value_enum, default_value_t = PagerKind::Simple)]
+    pub pager: PagerKind,
 }
 
 impl From<&TestArgs> for Args {
@@ -25,6 +45,7 @@ impl From<&TestArgs> for Args {
         Args {
             eps: value.eps,
             buffer_size: value.buffer_size,
+            bloom_bits: value.bloom_bits,
         }
     }
 }
@@ -34,15 +55,22 @@ fn main() {
     let args = TestArgs::parse();
     info!("config: {:?}", args);
     init_cfg(Some((&args).into()));
-    test_btree();
+    match args.pager {
+        PagerKind::Simple => test_btree::<SimplePager>(),
+        PagerKind::Mmap => test_btree::<MmapPager>(),
+        #[cfg(target_os = "linux")]
+        PagerKind::Direct => test_btree::<DirectPager>(),
+        #[cfg(feature = "io-uring")]
+        PagerKind::Uring => test_btree::<UringPager>(),
+    }
 }
 
-pub fn test_btree() {
+pub fn test_btree<P: Pager>() {
     use std::time::Instant;
     // use rand::prelude::*;
     // use rand_chacha::ChaCha8Rng;
     // let mut rng = StdRng::seed_from_u64(69420);
-    let mut betree = Betree::open("/tmp/test_betree");
+    let mut betree = Betree::<P>::open_with_pager("/tmp/test_betree").unwrap();
     // betree.print_tree();
     // println!("Superblock root: {}", betree.superblock.last_flushed_root);
     // let test_cap = 18010;
@@ -79,7 +107,7 @@ pub fn test_btree() {
         betree.insert(k, v);
     }
 
-    betree.flush();
+    betree.flush().unwrap();
     let elapsed = time.elapsed();
     pb.finish_and_clear();
     info!(
@@ -0,0 +1,112 @@
+use crate::error::Error;
+use crate::page::{Page, PAGESIZE};
+use crate::pager::{PageId, Pager};
+use memmap2::{MmapMut, MmapOptions};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+/// The mapping grows by at least this many pages at a time
+const MIN_GROWTH_PAGES: u64 = 256;
+
+/// A `Pager` over a shared writable mapping of the storage file.
+/// Clean pages are decoded straight from the mapping, `flush` is an `msync` plus `fsync`.
+pub struct MmapPager {
+    file: File,
+    map: MmapMut,
+}
+
+impl MmapPager {
+    fn map(file: File) -> Result<Self, Error> {
+        if file.metadata()?.len() < MIN_GROWTH_PAGES * PAGESIZE {
+            file.set_len(MIN_GROWTH_PAGES * PAGESIZE)?;
+        }
+        let map = unsafe { MmapOptions::new().map_mut(&file)? };
+        Ok(Self { file, map })
+    }
+
+    fn range(page_id: &PageId, len: usize) -> core::ops::Range<usize> {
+        let start = (page_id * PAGESIZE) as usize;
+        start..start + len
+    }
+
+    /// Extend the file and remap it so that `page_id` is addressable
+    fn grow_to(&mut self, page_id: &PageId) -> Result<(), Error> {
+        let needed = (page_id + 1) * PAGESIZE;
+        let mapped = self.map.len() as u64;
+        if needed <= mapped {
+            return Ok(());
+        }
+        let new_len = needed.max(mapped * 2).max(mapped + MIN_GROWTH_PAGES * PAGESIZE);
+        // Dirty pages of the old mapping live in the shared page cache, remapping keeps them
+        self.file.set_len(new_len)?;
+        self.map = unsafe { MmapOptions::new().map_mut(&self.file)? };
+        Ok(())
+    }
+}
+
+impl Pager for MmapPager {
+    fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let fd = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Self::map(fd)
+    }
+
+    fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let fd = OpenOptions::new()
+            .create(false)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        Self::map(fd)
+    }
+
+    fn read(&self, page_id: &PageId, page: &mut Page) -> Result<(), Error> {
+        let src = self
+            .page_slice(page_id, PAGESIZE as usize)
+            .ok_or_else(|| Error::UnexpectedError(format!("Page {} out of file", page_id)))?;
+        page.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write(&mut self, page_id: &PageId, data: &Page) -> Result<(), Error> {
+        self.grow_to(page_id)?;
+        self.map[Self::range(page_id, PAGESIZE as usize)].copy_from_slice(&data[..]);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.map.flush()?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    fn page_slice(&self, page_id: &PageId, len: usize) -> Option<&[u8]> {
+        self.map.get(Self::range(page_id, len))
+    }
+}
+
+#[test]
+fn test_mmap_persist() {
+    use crate::testing::TempPath;
+    let temp = TempPath::new("mmap");
+    let path = temp.path();
+    let mut pager = MmapPager::new(path).unwrap();
+    let mut a = Page::default();
+    a.fill(9);
+    // Past the initial mapping
+    let page_id = MIN_GROWTH_PAGES * 3;
+    pager.write(&page_id, &a).unwrap();
+    pager.flush().unwrap();
+    core::mem::drop(pager);
+
+    let pager = MmapPager::open(path).unwrap();
+    let mut b = Page::default();
+    pager.read(&page_id, &mut b).unwrap();
+    assert_eq!(&a[..], &b[..]);
+    assert_eq!(pager.page_slice(&page_id, PAGESIZE as usize).unwrap(), &a[..]);
+}
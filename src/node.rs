@@ -1,71 +1,238 @@
-use crate::types::{MessageData, MessageType, Serializable};
+use crate::bloom;
+use crate::range_delete;
+use crate::ttl;
+use crate::types::{
+    layout, with_layout, MessageData, MessageType, PrefixKey, Serializable, FORMAT_VERSION,
+};
 use crate::CFG;
 use core::panic;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::Debug;
+use std::ops::RangeBounds;
 
 use crate::error::Error;
-use crate::page::{Page, PAGESIZE};
+use crate::page::{Page, NODE_PAYLOAD, PAGESIZE};
 use crate::pager::PageId;
-use crate::types::{BTreeMapOnDisk, OnDiskKey, OnDiskValue, PageOffset, SizedOnDisk};
+use crate::types::{
+    with_comparator, BTreeMapOnDisk, Comparator, OnDiskKey, OnDiskValue, PageOffset, SizedOnDisk,
+};
 use ser_derive::SizedOnDisk;
 pub type ChildId = PageId;
 pub type MsgBuffer = BTreeMap<OnDiskKey, MessageData>;
 pub type MsgBufferOnDisk = BTreeMapOnDisk<OnDiskKey, MessageData>;
 pub type PivotMap = BTreeMap<OnDiskKey, ChildId>;
 pub type PivotMapOnDisk = BTreeMapOnDisk<OnDiskKey, ChildId>;
+/// Pivots a node split at and the right siblings above each of them, in key order
+pub type Pivots = Vec<(OnDiskKey, ChildId)>;
 pub type KVOnDisk = BTreeMapOnDisk<OnDiskKey, OnDiskValue>;
+pub type ExpiriesOnDisk = BTreeMapOnDisk<OnDiskKey, u64>;
 
 // const MAX_MSG_SIZE: PageOffset = PAGESIZE as PageOffset / 128;
 pub const MAX_KEY_SIZE: PageOffset = PAGESIZE as PageOffset / 128;
 // const MAX_VAL_SIZE: PageOffset = PAGESIZE as PageOffset / 128;
 
 // type PivotsLength = u16;
-const MAGIC: u64 = 0x18728742b91b43b;
+pub(crate) const MAGIC: u64 = 0x18728742b91b43b;
 
-#[derive(SizedOnDisk, Clone, Debug)]
+/// On disk: map, versions(from format 3), expiries(from format 5)
+#[derive(Clone, Debug)]
 pub struct LeafNode {
+    /// Values as of the oldest reader
     map: KVOnDisk,
+    /// Messages newer than the oldest reader, with the versions readers still see
+    versions: MsgBufferOnDisk,
+    /// When the values in `map` inserted with a time to live expire
+    expiries: ExpiriesOnDisk,
 }
 
 impl LeafNode {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             map: KVOnDisk::new(),
+            versions: MsgBufferOnDisk::new(),
+            expiries: ExpiriesOnDisk::new(),
+        }
+    }
+
+    /// Value of `key` seen by a reader at `seq`
+    pub fn get(&self, key: &OnDiskKey, seq: u64) -> Option<&[u8]> {
+        match self.versions.get(key).and_then(|m| m.at(seq)) {
+            Some(msg) => msg.value(),
+            None => self
+                .map
+                .get(key)
+                .filter(|_| !self.expired(key, ttl::now()))
+                .map(|v| v.as_slice()),
+        }
+    }
+
+    /// Pairs whose keys are in `range`, in key order, as of the oldest reader
+    pub fn range<R: RangeBounds<OnDiskKey>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = (&OnDiskKey, &[u8])> {
+        let now = ttl::now();
+        self.map
+            .range(range)
+            .filter(move |(k, _)| !self.expired(k, now))
+            .map(|(k, v)| (k, v.as_slice()))
+    }
+
+    fn expired(&self, key: &OnDiskKey, now: u64) -> bool {
+        self.expiries.get(key).is_some_and(|&expiry| expiry <= now)
+    }
+
+    /// Add `key`, which comes after every key of the leaf, unless the leaf would overflow, in
+    /// which case the pair is handed back
+    pub fn push(
+        &mut self,
+        key: OnDiskKey,
+        value: OnDiskValue,
+    ) -> Result<(), (OnDiskKey, OnDiskValue)> {
+        self.map.insert(key, value);
+        if self.is_node_full() {
+            return Err(self.map.pop_last().unwrap());
         }
+        Ok(())
+    }
+
+    /// Remove the values that expired by `now`
+    pub fn expire(&mut self, now: u64) {
+        let expired: Vec<OnDiskKey> = self
+            .expiries
+            .iter()
+            .filter(|(_, &expiry)| expiry <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in expired {
+            self.map.remove(&key);
+            self.expiries.remove(&key);
+        }
+    }
+
+    /// Expiry of each value in the map inserted with a time to live
+    pub fn expiries(&self) -> impl Iterator<Item = (&OnDiskKey, u64)> {
+        self.expiries.iter().map(|(k, &expiry)| (k, expiry))
     }
 
-    pub fn get(&self, key: &OnDiskKey) -> Option<&[u8]> {
-        self.map.get(key).map(|v| v.as_slice())
+    /// Messages in `range` newer than the oldest reader
+    pub fn versions<R: RangeBounds<OnDiskKey>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = (&OnDiskKey, &MessageData)> {
+        self.versions.range(range)
     }
 
     fn get_meta_size(&self) -> PageOffset {
-        true.size() + COM.size() + MAGIC.size()
+        true.size()
+            + COM.size()
+            + MAGIC.size()
+            + FORMAT_VERSION.size()
+            + Comparator::Bytewise.size()
     }
 
     pub fn get_kv_capacity(&self) -> PageOffset {
-        PAGESIZE as PageOffset - self.get_meta_size()
+        NODE_PAYLOAD as PageOffset - self.get_meta_size()
     }
 
     pub fn is_node_full(&self) -> bool {
         self.size() > self.get_kv_capacity()
     }
 
-    pub fn apply(&mut self, key: OnDiskKey, msg: MessageData) {
-        let MessageData { ty, val } = msg;
+    /// Apply `msg` as far as every reader in `readers`(sorted) sees it, and keep it as a version
+    /// otherwise
+    pub fn apply(&mut self, key: OnDiskKey, mut msg: MessageData, readers: &[u64]) {
+        if let Some(old) = self.versions.remove(&key) {
+            msg.push_older(old);
+        }
+        self.settle(key, msg, readers);
+    }
+
+    /// Delete the keys `deletes` cover, but for those in `msgs`, which carry the deletes
+    /// themselves
+    pub fn delete_ranges(&mut self, deletes: &MsgBuffer, msgs: &MsgBuffer, readers: &[u64]) {
+        let mut keys = BTreeSet::new();
+        for (start, msg) in deletes {
+            let comparator = start.comparator;
+            let covered = |k: &&OnDiskKey| {
+                msg.versions().any(|v| {
+                    comparator
+                        .compare(k.as_slice(), range_delete::end(v))
+                        .is_lt()
+                })
+            };
+            keys.extend(
+                self.map
+                    .range(start..)
+                    .map(|(k, _)| k)
+                    .take_while(covered)
+                    .cloned(),
+            );
+            keys.extend(
+                self.versions
+                    .range(start..)
+                    .map(|(k, _)| k)
+                    .take_while(covered)
+                    .cloned(),
+            );
+        }
+        for key in keys {
+            if !msgs.contains_key(&key) {
+                let tombstones = range_delete::tombstones(deletes, &key).unwrap();
+                self.apply(key, tombstones, readers);
+            }
+        }
+    }
+
+    /// Drop or apply the versions no reader in `readers`(sorted) needs any more
+    pub fn collect(&mut self, readers: &[u64]) {
+        let horizon = readers.first().copied().unwrap_or(u64::MAX);
+        let stale: Vec<OnDiskKey> = self
+            .versions
+            .iter()
+            .filter(|(_, m)| {
+                m.versions().last().is_some_and(|v| v.seq <= horizon) || m.prunable(readers)
+            })
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in stale {
+            let msg = self.versions.remove(&key).unwrap();
+            self.settle(key, msg, readers);
+        }
+    }
+
+    fn settle(&mut self, key: OnDiskKey, mut msg: MessageData, readers: &[u64]) {
+        msg.prune(readers);
+        let (settled, newer) = msg.settle(readers.first().copied().unwrap_or(u64::MAX));
+        if let Some(newer) = newer {
+            self.versions.insert(key.clone(), newer);
+        }
+        let Some(MessageData { ty, val, .. }) = settled else {
+            return;
+        };
         match ty {
             MessageType::Insert => {
+                self.expiries.remove(&key);
                 self.map.insert(key, val);
             }
+            MessageType::InsertWithTtl => {
+                let bytes = val.as_slice();
+                self.expiries.insert(key.clone(), ttl::expiry(bytes));
+                self.map
+                    .insert(key, OnDiskValue::new(ttl::value(bytes).to_vec()));
+            }
             MessageType::Delete => {
                 debug_assert!(val.is_empty());
                 self.map.remove(&key);
+                self.expiries.remove(&key);
                 // TODO: Need merging
             }
             MessageType::Upsert => {
                 // TODO: Byte slice addition?
                 unimplemented!()
             }
+            // Range deletes reach leaves as deletes of the keys they cover
+            MessageType::RangeDelete => unreachable!(),
         }
     }
 
@@ -75,19 +242,62 @@ impl LeafNode {
 
     fn merge(&mut self, other: Self) {
         self.map.append(&mut other.map.to_inner());
+        self.versions.append(&mut other.versions.to_inner());
+        self.expiries.append(&mut other.expiries.to_inner());
+    }
+
+    /// The smallest key with a value or versions
+    fn first_key(&self) -> Option<&OnDiskKey> {
+        let first = self.map.first_key_value().map(|(k, _)| k);
+        let versions = self.versions.first_key_value().map(|(k, _)| k);
+        match (first, versions) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            _ => first.or(versions),
+        }
+    }
+
+    /// The largest key with a value or versions
+    fn last_key(&self) -> Option<&OnDiskKey> {
+        let last = self.map.last_key_value().map(|(k, _)| k);
+        last.max(self.versions.last_key_value().map(|(k, _)| k))
     }
 
     pub fn split(&mut self) -> (Node, OnDiskKey) {
         let mut right_leaf = Self::new();
-        while !right_leaf.is_node_full() && self.map.size() > self.get_kv_capacity() / 2 {
-            if let Some((key, value)) = self.map.pop_last() {
+        while self.size() > self.get_kv_capacity() / 2 {
+            let Some(last) = self.last_key().cloned() else {
+                break;
+            };
+            // A value and the versions of its key go to the same side
+            if self.map.last_key_value().is_some_and(|(k, _)| *k == last) {
+                let (key, value) = self.map.pop_last().unwrap();
+                if let Some(expiry) = self.expiries.remove(&key) {
+                    right_leaf.expiries.insert(key.clone(), expiry);
+                }
                 right_leaf.map.insert(key, value);
-            } else {
+            }
+            if let Some(msg) = self.versions.remove(&last) {
+                right_leaf.versions.insert(last.clone(), msg);
+            }
+            if right_leaf.is_node_full() {
+                // The key does not fit, so it stays
+                if let Some(value) = right_leaf.map.remove(&last) {
+                    self.map.insert(last.clone(), value);
+                }
+                if let Some(expiry) = right_leaf.expiries.remove(&last) {
+                    self.expiries.insert(last.clone(), expiry);
+                }
+                if let Some(msg) = right_leaf.versions.remove(&last) {
+                    self.versions.insert(last, msg);
+                }
                 break;
             }
         }
 
-        let new_node_first = right_leaf.map.first_key_value().unwrap().0.clone();
+        let new_node_first = right_leaf
+            .first_key()
+            .expect("The versions of one key outgrew a leaf")
+            .clone();
         let new_node = Node {
             common_data: NodeCommon {
                 root: false,
@@ -95,31 +305,85 @@ impl LeafNode {
             },
             node_inner: NodeType::Leaf(right_leaf),
         };
+        // The left side may still be full, to be split again
         debug_assert!(new_node.well_formed());
-        debug_assert!(self.well_formed());
         (new_node, new_node_first)
         // (self.clone(), OnDiskKey::new(vec![]))
     }
 }
 
+impl SizedOnDisk for LeafNode {
+    fn size(&self) -> PageOffset {
+        let versions = if layout() < 3 {
+            0
+        } else {
+            self.versions.size()
+        };
+        let expiries = if layout() < 5 {
+            0
+        } else {
+            self.expiries.size()
+        };
+        self.map.size() + versions + expiries
+    }
+}
+
 impl Serializable for LeafNode {
     fn serialize(&self, destination: &mut [u8]) {
         debug_assert!(self.well_formed());
-        serialize!(self.map, destination);
+        let mut _cursor = 0;
+        serialize!(self.map, destination, _cursor);
+        if layout() >= 3 {
+            serialize!(self.versions, destination, _cursor);
+        }
+        if layout() >= 5 {
+            serialize!(self.expiries, destination, _cursor);
+        }
     }
 
     fn deserialize(src: &[u8]) -> Self {
-        deserialize_with_var!(map, BTreeMap<OnDiskKey, OnDiskValue>, src);
-        Self { map: map.into() }
+        let mut _cursor = 0;
+        let map = deserialize!(KVOnDisk, src, _cursor);
+        let versions = if layout() < 3 {
+            MsgBufferOnDisk::new()
+        } else {
+            deserialize!(MsgBufferOnDisk, src, _cursor)
+        };
+        let expiries = if layout() < 5 {
+            ExpiriesOnDisk::new()
+        } else {
+            deserialize!(ExpiriesOnDisk, src, _cursor)
+        };
+        Self {
+            map,
+            versions,
+            expiries,
+        }
     }
 }
 
-#[derive(SizedOnDisk, Clone, Debug)]
+/// On disk: epsilon, pivots, rightmost child, message buffer, Bloom filter bits and filter, then
+/// range deletes(from format 4)
+#[derive(Clone, Debug)]
 pub struct InternalNode {
     pub pivot_map: PivotMapOnDisk,
     pub rightmost_child: ChildId,
     pub msg_buffer: MsgBufferOnDisk,
     epsilon: f32,
+    /// Bits per key of the Bloom filter stored after the message buffer, 0 for none
+    pub(crate) bloom_bits: u8,
+    /// Range deletes buffered with the messages, stored after the Bloom filter
+    pub range_deletes: MsgBufferOnDisk,
+}
+
+impl SizedOnDisk for InternalNode {
+    fn size(&self) -> PageOffset {
+        self.epsilon.size()
+            + self.pivot_map.size()
+            + self.rightmost_child.size()
+            + self.get_msg_buffer_size()
+            + self.bloom_bits.size()
+    }
 }
 
 impl InternalNode {
@@ -127,10 +391,43 @@ impl InternalNode {
         (self.get_data_size() as f32 * self.epsilon) as PageOffset
     }
 
-    pub fn merge_buffers(&mut self, mut msgs: MsgBuffer) {
+    /// Add `msgs`, newer than the buffered ones, keeping the versions `readers`(sorted) see
+    pub fn merge_buffers(&mut self, mut msgs: MsgBuffer, readers: &[u64]) {
+        for (key, msg) in msgs.iter_mut() {
+            if let Some(old) = self.msg_buffer.remove(key) {
+                msg.push_older(old);
+            }
+            msg.prune(readers);
+        }
         self.msg_buffer.append(&mut msgs);
     }
 
+    /// Add range deletes newer than everything buffered here, except for `msgs` which arrive
+    /// with them, and drop what they hide from every reader in `readers`(sorted)
+    pub fn merge_deletes(&mut self, deletes: MsgBuffer, msgs: &MsgBuffer, readers: &[u64]) {
+        if deletes.is_empty() {
+            return;
+        }
+        let hidden: Vec<OnDiskKey> = self
+            .msg_buffer
+            .keys()
+            .filter(|k| !msgs.contains_key(k))
+            .filter(|k| range_delete::covering(&deletes, k).next().is_some())
+            .cloned()
+            .collect();
+        for key in hidden {
+            let tombstones = range_delete::tombstones(&deletes, &key).unwrap();
+            let mut msg = self.msg_buffer.remove(&key).unwrap().merge(tombstones);
+            msg.prune(readers);
+            // Otherwise the range delete buffered here stands for the message
+            if !msg.older.is_empty() {
+                self.msg_buffer.insert(key, msg);
+            }
+        }
+        let old = std::mem::replace(&mut self.range_deletes, MsgBufferOnDisk::new());
+        self.range_deletes = range_delete::merge(old.to_inner(), deletes, readers).into();
+    }
+
     pub fn well_formed(&self) -> bool {
         !self.is_pivots_full() && !self.is_msg_buffer_full()
     }
@@ -148,15 +445,32 @@ impl InternalNode {
     }
 
     fn get_meta_size(&self) -> PageOffset {
-        true.size() + self.epsilon.size() + MAGIC.size()
+        true.size()
+            + self.epsilon.size()
+            + self.bloom_bits.size()
+            + MAGIC.size()
+            + FORMAT_VERSION.size()
+            + Comparator::Bytewise.size()
     }
 
     fn get_data_size(&self) -> PageOffset {
-        PAGESIZE as PageOffset - COM.size() - self.get_meta_size()
+        NODE_PAYLOAD as PageOffset - COM.size() - self.get_meta_size()
+    }
+
+    /// Bytes of the message buffer, its Bloom filter and the range deletes
+    pub fn get_msg_buffer_size(&self) -> PageOffset {
+        let range_deletes = if layout() < 4 {
+            0
+        } else {
+            self.range_deletes.size()
+        };
+        self.msg_buffer.size()
+            + bloom::filter_len(self.msg_buffer.len(), self.bloom_bits)
+            + range_deletes
     }
 
     pub fn is_msg_buffer_full(&self) -> bool {
-        self.get_msg_buffer_capacity() < self.msg_buffer.size()
+        self.get_msg_buffer_capacity() < self.get_msg_buffer_size()
     }
 
     pub fn is_pivots_full(&self) -> bool {
@@ -164,6 +478,23 @@ impl InternalNode {
         // self.get_pivots_avail() < MAX_KEY_SIZE + size_of::<ChildId>()
     }
 
+    /// Add `child_id` right of every child, holding the keys from `pivot` on, unless the pivots
+    /// would overflow, in which case both are handed back
+    pub fn push(
+        &mut self,
+        pivot: OnDiskKey,
+        child_id: ChildId,
+    ) -> Result<(), (OnDiskKey, ChildId)> {
+        let left = std::mem::replace(&mut self.rightmost_child, child_id);
+        self.pivot_map.insert(pivot, left);
+        if self.is_pivots_full() {
+            let (pivot, _) = self.pivot_map.pop_last().unwrap();
+            self.rightmost_child = left;
+            return Err((pivot, child_id));
+        }
+        Ok(())
+    }
+
     pub fn insert_msg(&mut self, key: OnDiskKey, msg: MessageData) {
         self.msg_buffer.insert(key, msg);
         // debug_assert!(self.msg_buffer.size() <= self.get_msg_buffer_capacity());
@@ -194,32 +525,51 @@ impl InternalNode {
         )
     }
 
-    // Return msgs to flush, from right to left
-    pub fn prepare_msg_flush(&mut self) -> Vec<(ChildId, MsgBuffer)> {
+    // Return msgs and range deletes to flush, from right to left
+    pub fn prepare_msg_flush(&mut self) -> Vec<(ChildId, MsgBuffer, MsgBuffer)> {
+        let deletes = std::mem::replace(&mut self.range_deletes, MsgBufferOnDisk::new()).to_inner();
         let mut buffers = Vec::with_capacity(self.pivot_map.len());
         let mut pre_child = self.rightmost_child;
+        let mut upper = None;
         for (key, child) in self.pivot_map.iter().rev() {
             let new_buffer = self.msg_buffer.split_off(key);
-            if !new_buffer.is_empty() {
+            let clipped = range_delete::within(&deletes, Some(key), upper);
+            if !new_buffer.is_empty() || !clipped.is_empty() {
                 // map.insert(pre_child, new_buffer);
-                buffers.push((pre_child, new_buffer));
+                buffers.push((pre_child, new_buffer, clipped));
             }
             pre_child = *child;
+            upper = Some(key);
         }
-        if !self.msg_buffer.is_empty() {
+        let clipped = range_delete::within(&deletes, None, upper);
+        if !self.msg_buffer.is_empty() || !clipped.is_empty() {
             let mut leftmost_map = MsgBufferOnDisk::new();
             std::mem::swap(&mut leftmost_map, &mut self.msg_buffer);
             // map.insert(pre_child, leftmost_map.to_inner());
-            buffers.push((pre_child, leftmost_map.to_inner()));
+            buffers.push((pre_child, leftmost_map.to_inner(), clipped));
         }
         buffers
     }
 
-    pub fn get(&self, key: &OnDiskKey) -> Result<&[u8], ChildId> {
-        if let Some(slice) = self.msg_buffer.get(key).map(|m| m.val.as_slice()) {
-            Ok(slice)
-        } else {
-            Err(self.find_child_with_key(key))
+    /// Pivots around `child`, which holds the keys from the first(inclusive) up to the second
+    pub fn bounds(&self, child: ChildId) -> (Option<&OnDiskKey>, Option<&OnDiskKey>) {
+        let mut lower = None;
+        for (pivot, c) in self.pivot_map.iter() {
+            if *c == child {
+                return (lower, Some(pivot));
+            }
+            lower = Some(pivot);
+        }
+        (lower, None)
+    }
+
+    /// Value of `key` buffered here for a reader at `seq`, or the child to look in
+    pub fn get(&self, key: &OnDiskKey, seq: u64) -> Result<Option<&[u8]>, ChildId> {
+        let msg = self.msg_buffer.get(key).and_then(|m| m.at(seq));
+        match (msg, range_delete::deleted_at(&self.range_deletes, key, seq)) {
+            (Some(msg), deleted) if deleted.is_none_or(|d| msg.seq > d) => Ok(msg.value()),
+            (_, Some(_)) => Ok(None),
+            _ => Err(self.find_child_with_key(key)),
         }
     }
 
@@ -249,17 +599,13 @@ impl InternalNode {
             rightmost_child,
             msg_buffer: MsgBufferOnDisk::new(),
             epsilon: CFG.get().unwrap().eps,
+            bloom_bits: CFG.get().unwrap().bloom_bits,
+            range_deletes: MsgBufferOnDisk::new(),
         }
     }
 
-    pub fn update_pivots(
-        &mut self,
-        old_child: ChildId,
-        child_id: ChildId,
-        new_pivots: Option<(OnDiskKey, ChildId)>,
-    ) {
-        // assert!(new_pivots.len() <= 1);
-        if new_pivots.is_none() {
+    pub fn update_pivots(&mut self, old_child: ChildId, child_id: ChildId, new_pivots: Pivots) {
+        if new_pivots.is_empty() {
             if old_child == child_id {
                 return;
             }
@@ -274,7 +620,11 @@ impl InternalNode {
                     .unwrap();
                 self.pivot_map.insert(k, child_id);
             }
-        } else if let Some((key, right)) = new_pivots {
+            return;
+        }
+        // Each pivot splits the keys of the child before it with its right sibling
+        let (mut old_child, mut child_id) = (old_child, child_id);
+        for (key, right) in new_pivots {
             // let (mut pivot_map, rightmost_child) = convert_pivot(child_id, new_pivots);
             let cursor = self.pivot_map.lower_bound(std::ops::Bound::Excluded(&key));
             if let Some((k, v)) = cursor.key_value().map(|(k, v)| (k.clone(), *v)) {
@@ -287,6 +637,7 @@ impl InternalNode {
             }
             self.pivot_map.insert(key, child_id);
             // self.pivot_map.append(&mut pivot_map);
+            (old_child, child_id) = (right, right);
         }
     }
 
@@ -300,18 +651,23 @@ impl InternalNode {
         let mut msgs = self.msg_buffer.split_off(&median_key);
         let msg = msgs.remove_entry(&median_key);
         msg.map(|(k, m)| msgs.insert(k, m));
+        let deletes = std::mem::replace(&mut self.range_deletes, MsgBufferOnDisk::new()).to_inner();
+        self.range_deletes = range_delete::within(&deletes, None, Some(&median_key)).into();
+        let right_deletes = range_delete::within(&deletes, Some(&median_key), None);
         let original_rightmost = self.rightmost_child;
         self.rightmost_child = rightmost_child;
         let new_node = Node {
             common_data: COM,
             node_inner: NodeType::Internal(InternalNode::new(
                 self.epsilon,
+                self.bloom_bits,
                 new_pivots.into(),
                 original_rightmost,
                 msgs.into(),
+                right_deletes,
             )),
         };
-        debug_assert!(self.well_formed());
+        // The left side may still be full, to be split again
         debug_assert!(new_node.well_formed());
         (new_node, median_key)
         // (self.clone(), OnDiskKey::new(vec![]))
@@ -322,9 +678,17 @@ impl InternalNode {
             pivot_map,
             rightmost_child,
             msg_buffer,
+            range_deletes,
             ..
         } = other;
         self.msg_buffer.append(&mut msg_buffer.to_inner());
+        let mut deletes =
+            std::mem::replace(&mut self.range_deletes, MsgBufferOnDisk::new()).to_inner();
+        range_deletes
+            .to_inner()
+            .into_iter()
+            .for_each(|(start, msg)| range_delete::insert(&mut deletes, start, msg));
+        self.range_deletes = deletes.into();
         // assert pivots non-overlapping
         self.pivot_map.append(&mut pivot_map.to_inner());
         self.rightmost_child = self.rightmost_child.max(rightmost_child);
@@ -332,15 +696,19 @@ impl InternalNode {
 
     fn new(
         epsilon: f32,
+        bloom_bits: u8,
         pivot_map: PivotMap,
         rightmost_child: ChildId,
         msg_buffer: MsgBuffer,
+        range_deletes: MsgBuffer,
     ) -> Self {
         Self {
             epsilon,
+            bloom_bits,
             msg_buffer: msg_buffer.into(),
             pivot_map: pivot_map.into(),
             rightmost_child,
+            range_deletes: range_deletes.into(),
         }
     }
 }
@@ -353,19 +721,41 @@ impl Serializable for InternalNode {
         serialize!(self.pivot_map, destination, _cursor);
         serialize!(self.rightmost_child, destination, _cursor);
         serialize!(self.msg_buffer, destination, _cursor);
+        serialize!(self.bloom_bits, destination, _cursor);
+        let filter = bloom::build(
+            self.msg_buffer.keys().map(|k| k.key_bytes()),
+            self.msg_buffer.len(),
+            self.bloom_bits,
+        );
+        destination[_cursor.._cursor + filter.len()].copy_from_slice(&filter);
+        _cursor += filter.len();
+        if layout() >= 4 {
+            serialize!(self.range_deletes, destination, _cursor);
+        }
     }
 
     fn deserialize(src: &[u8]) -> Self {
         let mut _cursor = 0;
         let epsilon = deserialize!(f32, src, _cursor);
-        let pivot_map = deserialize!(PivotMap, src, _cursor);
+        // Sizes come from disk, maps are only ordered bytewise until the comparator is set
+        let pivot_map = deserialize!(PivotMapOnDisk, src, _cursor);
         let rightmost_child = deserialize!(ChildId, src, _cursor);
-        let msg_buffer = deserialize!(MsgBuffer, src, _cursor);
+        let msg_buffer = deserialize!(MsgBufferOnDisk, src, _cursor);
+        // The filter is only read by views
+        let bloom_bits = deserialize!(u8, src, _cursor);
+        _cursor += bloom::filter_len(msg_buffer.len(), bloom_bits);
+        let range_deletes = if layout() < 4 {
+            MsgBufferOnDisk::new()
+        } else {
+            deserialize!(MsgBufferOnDisk, src, _cursor)
+        };
         let new_node = Self {
             epsilon,
-            pivot_map: pivot_map.into(),
+            bloom_bits,
+            pivot_map,
             rightmost_child,
-            msg_buffer: msg_buffer.into(),
+            msg_buffer,
+            range_deletes,
         };
         debug_assert!(new_node.well_formed());
         new_node
@@ -446,14 +836,13 @@ pub struct Node {
 }
 
 impl Node {
-    pub fn new_internel_root(child_id: ChildId, pivots: Option<(OnDiskKey, ChildId)>) -> Self {
+    pub fn new_internel_root(child_id: ChildId, pivots: Pivots) -> Self {
         let mut pivot_map = PivotMap::new();
-        let rightmost_child = if let Some((key, right)) = pivots {
-            pivot_map.insert(key, child_id);
-            right
-        } else {
-            child_id
-        };
+        let mut rightmost_child = child_id;
+        for (key, right) in pivots {
+            pivot_map.insert(key, rightmost_child);
+            rightmost_child = right;
+        }
         //  convert_pivot(child_id, pivots);
         Self {
             common_data: NodeCommon {
@@ -479,10 +868,18 @@ impl Node {
         self.common_data.dirty = false;
     }
 
-    pub fn to_page(&self) -> Result<Page, Error> {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
         self.try_into()
     }
 
+    /// Node that is not a root, built outside a tree
+    pub fn new(node_inner: NodeType) -> Self {
+        Self {
+            common_data: COM,
+            node_inner,
+        }
+    }
+
     pub fn new_empty_leaf(root: bool) -> Self {
         Self {
             common_data: NodeCommon { root, dirty: true },
@@ -490,6 +887,47 @@ impl Node {
         }
     }
 
+    /// Comparator of the tree, taken from any key of the node
+    fn comparator(&self) -> Comparator {
+        let key = match &self.node_inner {
+            NodeType::Leaf(leaf) => leaf.map.keys().chain(leaf.versions.keys()).next(),
+            NodeType::Internal(i) => i
+                .pivot_map
+                .keys()
+                .chain(i.msg_buffer.keys())
+                .chain(i.range_deletes.keys())
+                .next(),
+            _ => unimplemented!(),
+        };
+        key.map_or(Comparator::Bytewise, |k| k.comparator)
+    }
+
+    /// Reorder the maps of a node read from disk by the comparator of its tree
+    fn set_comparator(&mut self, comparator: Comparator) {
+        if comparator == Comparator::Bytewise {
+            return;
+        }
+        match &mut self.node_inner {
+            NodeType::Leaf(leaf) => {
+                let map = std::mem::replace(&mut leaf.map, KVOnDisk::new());
+                leaf.map = with_comparator(map.to_inner(), comparator).into();
+                let versions = std::mem::replace(&mut leaf.versions, MsgBufferOnDisk::new());
+                leaf.versions = with_comparator(versions.to_inner(), comparator).into();
+                let expiries = std::mem::replace(&mut leaf.expiries, ExpiriesOnDisk::new());
+                leaf.expiries = with_comparator(expiries.to_inner(), comparator).into();
+            }
+            NodeType::Internal(i) => {
+                let pivots = std::mem::replace(&mut i.pivot_map, PivotMapOnDisk::new());
+                i.pivot_map = with_comparator(pivots.to_inner(), comparator).into();
+                let msgs = std::mem::replace(&mut i.msg_buffer, MsgBufferOnDisk::new());
+                i.msg_buffer = with_comparator(msgs.to_inner(), comparator).into();
+                let deletes = std::mem::replace(&mut i.range_deletes, MsgBufferOnDisk::new());
+                i.range_deletes = with_comparator(deletes.to_inner(), comparator).into();
+            }
+            _ => unimplemented!(),
+        }
+    }
+
     pub fn is_root(&self) -> bool {
         self.common_data.root
     }
@@ -498,26 +936,8 @@ impl Node {
         self.common_data.root = false;
     }
 
-    pub fn get_key(&self, key: &OnDiskKey) -> Option<&OnDiskValue> {
-        match &self.node_inner {
-            NodeType::Internal(internal) => {
-                internal
-                    .msg_buffer
-                    .get(key)
-                    .and_then(|MessageData { ty, val }| match ty {
-                        MessageType::Insert => Some(val),
-                        MessageType::Delete => None,
-                        MessageType::Upsert => {
-                            // Need to flush this msg
-                            unimplemented!()
-                        }
-                    })
-            }
-            NodeType::Leaf(leaf) => leaf.map.get(key),
-            _ => {
-                unimplemented!()
-            }
-        }
+    pub fn set_root(&mut self) {
+        self.common_data.root = true;
     }
 
     pub fn merging_possible(&self) -> bool {
@@ -525,7 +945,7 @@ impl Node {
         match &self.node_inner {
             NodeType::Internal(internal) => {
                 internal.get_pivots_size() <= internal.get_pivots_capacity() / SPLIT_THRESHOLD
-                    && internal.msg_buffer.size()
+                    && internal.get_msg_buffer_size()
                         <= internal.get_msg_buffer_capacity() / SPLIT_THRESHOLD
             }
             NodeType::Leaf(leaf) => leaf.size() <= leaf.get_kv_capacity() / SPLIT_THRESHOLD,
@@ -580,18 +1000,43 @@ impl Node {
 
 const NODE_META_OFFSET: usize = 0;
 
-impl TryFrom<&Page> for Node {
+/// Bytes before the node type flag: MAGIC, format version, comparator and common data
+pub(crate) fn header_size() -> PageOffset {
+    NODE_META_OFFSET
+        + MAGIC.size()
+        + FORMAT_VERSION.size()
+        + Comparator::Bytewise.size()
+        + COM.size()
+}
+
+impl TryFrom<&[u8]> for Node {
     type Error = Error;
-    fn try_from(value: &Page) -> Result<Self, Self::Error> {
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
         let mut _cursor = NODE_META_OFFSET;
         deserialize_with_var!(magic, u64, value, _cursor);
-        assert_eq!(magic, MAGIC);
+        if magic != MAGIC {
+            return Err(Error::UnexpectedError("Not a node".to_owned()));
+        }
+        deserialize_with_var!(version, u32, value, _cursor);
+        if !(2..=FORMAT_VERSION).contains(&version) {
+            return Err(Error::IncompatibleFormat(version));
+        }
+        deserialize_with_var!(comparator, Comparator, value, _cursor);
         let common_data = deserialize!(NodeCommon, value, _cursor);
-        let node_inner = deserialize!(NodeType, value, _cursor);
-        Ok(Node {
+        let node_inner = with_layout(version, || deserialize!(NodeType, value, _cursor));
+        let mut node = Node {
             common_data,
             node_inner,
-        })
+        };
+        node.set_comparator(comparator);
+        Ok(node)
+    }
+}
+
+impl TryFrom<&Page> for Node {
+    type Error = Error;
+    fn try_from(value: &Page) -> Result<Self, Self::Error> {
+        <&[u8]>::from(value).try_into()
     }
 }
 
@@ -602,16 +1047,18 @@ impl TryFrom<Page> for Node {
     }
 }
 
-impl TryFrom<&Node> for Page {
+impl TryFrom<&Node> for Vec<u8> {
     type Error = Error;
     fn try_from(value: &Node) -> Result<Self, Self::Error> {
-        let mut page = Page::default();
+        let mut bytes = vec![0; NODE_PAYLOAD as usize];
         let mut _cursor = NODE_META_OFFSET;
         debug_assert!(value.well_formed());
         // assert!(value.size() <= PAGESIZE as usize, "{:?}", value);
-        serialize!(MAGIC, page, _cursor);
-        serialize!(value.common_data, page, _cursor);
-        serialize!(value.node_inner, page, _cursor);
-        Ok(page)
+        serialize!(MAGIC, bytes, _cursor);
+        serialize!(layout(), bytes, _cursor);
+        serialize!(value.comparator(), bytes, _cursor);
+        serialize!(value.common_data, bytes, _cursor);
+        serialize!(value.node_inner, bytes, _cursor);
+        Ok(bytes)
     }
 }
@@ -0,0 +1,636 @@
+use crate::error::Error;
+use core::mem::size_of;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+/// Order preserving encoding of keys: encoded keys compare bytewise like the keys themselves.
+/// Integers are big endian with the sign bit flipped, floats are ordered by their bits,
+/// strings and byte arrays escape 0x00 as 0x00 0xff and end with 0x00 0x00, options,
+/// sequences and maps mark every element with 1 and end with 0, enums start with the index of
+/// their variant. Tuples and structs are their fields in order.
+pub fn to_key<K: Serialize + ?Sized>(key: &K) -> Result<Vec<u8>, Error> {
+    let mut serializer = KeySerializer { out: vec![] };
+    key.serialize(&mut serializer)?;
+    Ok(serializer.out)
+}
+
+/// Decode a key encoded by `to_key`
+pub fn from_key<K: DeserializeOwned>(bytes: &[u8]) -> Result<K, Error> {
+    let mut deserializer = KeyDeserializer { src: bytes };
+    let key = K::deserialize(&mut deserializer)?;
+    if !deserializer.src.is_empty() {
+        return Err(Error::SerdeError("Trailing bytes after the key".to_owned()));
+    }
+    Ok(key)
+}
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xff;
+const END: u8 = 0x00;
+const MORE: u8 = 1;
+
+struct KeySerializer {
+    out: Vec<u8>,
+}
+
+impl KeySerializer {
+    fn escaped(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.out.push(b);
+            if b == ESCAPE {
+                self.out.push(ESCAPED_ZERO);
+            }
+        }
+        self.out.extend_from_slice(&[ESCAPE, END]);
+    }
+
+    fn variant(&mut self, index: u32) {
+        self.out.extend_from_slice(&index.to_be_bytes());
+    }
+}
+
+macro_rules! serialize_unsigned {
+    ($name:ident, $ty:ty) => {
+        fn $name(self, v: $ty) -> Result<(), Error> {
+            self.out.extend_from_slice(&v.to_be_bytes());
+            Ok(())
+        }
+    };
+}
+
+macro_rules! serialize_signed {
+    ($name:ident, $ty:ty, $unsigned:ty) => {
+        fn $name(self, v: $ty) -> Result<(), Error> {
+            let flipped = (v as $unsigned) ^ (1 << (<$unsigned>::BITS - 1));
+            self.out.extend_from_slice(&flipped.to_be_bytes());
+            Ok(())
+        }
+    };
+}
+
+impl ser::Serializer for &mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.out.push(v as u8);
+        Ok(())
+    }
+
+    serialize_unsigned!(serialize_u8, u8);
+    serialize_unsigned!(serialize_u16, u16);
+    serialize_unsigned!(serialize_u32, u32);
+    serialize_unsigned!(serialize_u64, u64);
+    serialize_unsigned!(serialize_u128, u128);
+    serialize_signed!(serialize_i8, i8, u8);
+    serialize_signed!(serialize_i16, i16, u16);
+    serialize_signed!(serialize_i32, i32, u32);
+    serialize_signed!(serialize_i64, i64, u64);
+    serialize_signed!(serialize_i128, i128, u128);
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        let bits = v.to_bits();
+        let bits = if bits >> 31 == 1 {
+            !bits
+        } else {
+            bits | 1 << 31
+        };
+        self.serialize_u32(bits)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        let bits = v.to_bits();
+        let bits = if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits | 1 << 63
+        };
+        self.serialize_u64(bits)
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.escaped(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.escaped(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.out.push(END);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        self.out.push(MORE);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), Error> {
+        self.variant(variant_index);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.variant(variant_index);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, Error> {
+        self.variant(variant_index);
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, Error> {
+        self.variant(variant_index);
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.out.push(MORE);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.out.push(END);
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.out.push(MORE);
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.out.push(END);
+        Ok(())
+    }
+}
+
+/// Tuples, structs and their variants are their fields one after another
+macro_rules! serialize_fields {
+    ($tr:path, $method:ident $(, $key:ident)?) => {
+        impl $tr for &mut KeySerializer {
+            type Ok = ();
+            type Error = Error;
+
+            fn $method<T: Serialize + ?Sized>(
+                &mut self,
+                $($key: &'static str,)?
+                value: &T,
+            ) -> Result<(), Error> {
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+    };
+}
+
+serialize_fields!(ser::SerializeTuple, serialize_element);
+serialize_fields!(ser::SerializeTupleStruct, serialize_field);
+serialize_fields!(ser::SerializeTupleVariant, serialize_field);
+serialize_fields!(ser::SerializeStruct, serialize_field, _key);
+serialize_fields!(ser::SerializeStructVariant, serialize_field, _key);
+
+struct KeyDeserializer<'de> {
+    src: &'de [u8],
+}
+
+impl<'de> KeyDeserializer<'de> {
+    fn take(&mut self, n: usize) -> Result<&'de [u8], Error> {
+        if self.src.len() < n {
+            return Err(Error::SerdeError("Truncated key".to_owned()));
+        }
+        let (head, rest) = self.src.split_at(n);
+        self.src = rest;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn escaped(&mut self) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![];
+        loop {
+            match self.byte()? {
+                ESCAPE => match self.byte()? {
+                    ESCAPED_ZERO => bytes.push(ESCAPE),
+                    END => return Ok(bytes),
+                    b => return Err(Error::SerdeError(format!("Bad escape: {:#x}", b))),
+                },
+                b => bytes.push(b),
+            }
+        }
+    }
+
+    /// Whether another element of a sequence or map follows
+    fn more(&mut self) -> Result<bool, Error> {
+        match self.byte()? {
+            MORE => Ok(true),
+            END => Ok(false),
+            b => Err(Error::SerdeError(format!("Bad element marker: {:#x}", b))),
+        }
+    }
+}
+
+macro_rules! deserialize_unsigned {
+    ($name:ident, $ty:ty, $visit:ident) => {
+        fn $name<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            let bytes = self.take(size_of::<$ty>())?;
+            visitor.$visit(<$ty>::from_be_bytes(bytes.try_into().unwrap()))
+        }
+    };
+}
+
+macro_rules! deserialize_signed {
+    ($name:ident, $ty:ty, $unsigned:ty, $visit:ident) => {
+        fn $name<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            let bytes = self.take(size_of::<$ty>())?;
+            let flipped = <$unsigned>::from_be_bytes(bytes.try_into().unwrap());
+            visitor.$visit((flipped ^ (1 << (<$unsigned>::BITS - 1))) as $ty)
+        }
+    };
+}
+
+impl<'de> de::Deserializer<'de> for &mut KeyDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error::SerdeError(
+            "Keys are not self-describing, the type is needed to decode them".to_owned(),
+        ))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_bool(self.byte()? != 0)
+    }
+
+    deserialize_unsigned!(deserialize_u8, u8, visit_u8);
+    deserialize_unsigned!(deserialize_u16, u16, visit_u16);
+    deserialize_unsigned!(deserialize_u32, u32, visit_u32);
+    deserialize_unsigned!(deserialize_u64, u64, visit_u64);
+    deserialize_unsigned!(deserialize_u128, u128, visit_u128);
+    deserialize_signed!(deserialize_i8, i8, u8, visit_i8);
+    deserialize_signed!(deserialize_i16, i16, u16, visit_i16);
+    deserialize_signed!(deserialize_i32, i32, u32, visit_i32);
+    deserialize_signed!(deserialize_i64, i64, u64, visit_i64);
+    deserialize_signed!(deserialize_i128, i128, u128, visit_i128);
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let bits = u32::from_be_bytes(self.take(4)?.try_into().unwrap());
+        let bits = if bits >> 31 == 1 {
+            bits & !(1 << 31)
+        } else {
+            !bits
+        };
+        visitor.visit_f32(f32::from_bits(bits))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let bits = u64::from_be_bytes(self.take(8)?.try_into().unwrap());
+        let bits = if bits >> 63 == 1 {
+            bits & !(1 << 63)
+        } else {
+            !bits
+        };
+        visitor.visit_f64(f64::from_bits(bits))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let code = u32::from_be_bytes(self.take(4)?.try_into().unwrap());
+        let c = char::from_u32(code).ok_or(Error::UTF8Error)?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let s = String::from_utf8(self.escaped()?).map_err(|_| Error::UTF8Error)?;
+        visitor.visit_string(s)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_byte_buf(self.escaped()?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.more()? {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Marked { de: self })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Fields {
+            de: self,
+            left: len,
+        })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(Marked { de: self })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+}
+
+/// Elements of sequences and maps, each preceded by a marker
+struct Marked<'a, 'de> {
+    de: &'a mut KeyDeserializer<'de>,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for Marked<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if !self.de.more()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+}
+
+impl<'de, 'a> de::MapAccess<'de> for Marked<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        de::SeqAccess::next_element_seed(self, seed)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+/// Fields of tuples and structs, whose number is known from the type
+struct Fields<'a, 'de> {
+    de: &'a mut KeyDeserializer<'de>,
+    left: usize,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for Fields<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut KeyDeserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let index = u32::from_be_bytes(self.take(4)?.try_into().unwrap());
+        let value = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(index))?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut KeyDeserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+#[test]
+fn test_ordered_keys() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, PartialEq, PartialOrd, Debug, Clone)]
+    enum Id {
+        User(u32),
+        Group { name: String, id: i16 },
+        Anonymous,
+    }
+
+    fn check<K: Serialize + DeserializeOwned + PartialOrd + std::fmt::Debug>(keys: &[K]) {
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1]);
+            assert!(
+                to_key(&pair[0]).unwrap() < to_key(&pair[1]).unwrap(),
+                "{:?}",
+                pair
+            );
+        }
+        for k in keys {
+            assert_eq!(&from_key::<K>(&to_key(k).unwrap()).unwrap(), k);
+        }
+    }
+
+    check(&[0u64, 1, 255, 256, u64::MAX]);
+    check(&[i64::MIN, -256, -1, 0, 1, i64::MAX]);
+    check(&[f64::NEG_INFINITY, -1.5, -0.5, 0.0, 2.5, f64::INFINITY]);
+    check(&["", "\0", "\0\0", "a", "a\0", "ab", "b"].map(String::from));
+    check(&[None, Some(0u8), Some(1)]);
+    check(&[vec![], vec![0u16], vec![0, 0], vec![1]]);
+    check(&[
+        (1u8, "b".to_owned()),
+        (1, "ba".to_owned()),
+        (2, "a".to_owned()),
+    ]);
+    check(&[
+        Id::User(7),
+        Id::User(300),
+        Id::Group {
+            name: "a".to_owned(),
+            id: -3,
+        },
+        Id::Group {
+            name: "a".to_owned(),
+            id: 2,
+        },
+        Id::Anonymous,
+    ]);
+    assert!(from_key::<u32>(&[1, 2]).is_err());
+    assert!(from_key::<u8>(&[1, 2]).is_err());
+}
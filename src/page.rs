@@ -1,6 +1,16 @@
 use core::ops::{Deref, DerefMut};
 pub const PAGESIZE: u64 = 4096;
 
+/// Pages reserved for every node; compressed nodes take fewer of them on disk
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+pub const NODE_PAGES: u64 = 4;
+#[cfg(not(any(feature = "lz4", feature = "zstd")))]
+pub const NODE_PAGES: u64 = 1;
+/// Logical size of a node, which capacity checks are based on
+pub const NODE_SIZE: u64 = NODE_PAGES * PAGESIZE;
+/// Bytes available to a serialized node, the rest of its extent is kept for encryption
+pub const NODE_PAYLOAD: u64 = NODE_SIZE - crate::seal::SEAL_SIZE as u64;
+
 pub type PageType = [u8; PAGESIZE as usize];
 
 #[repr(C, align(4096))]
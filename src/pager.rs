@@ -1,19 +1,54 @@
 pub type PageId = u64;
 use crate::page::{Page, PAGESIZE};
 use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::io::{Read, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
 use std::path::Path;
 
 use crate::error::Error;
 
+/// IOV_MAX on Linux
+const MAX_IOVECS: usize = 1024;
+
 pub trait Pager: Sized {
     // const DEFAULT_PATH: &'static str = "/tmp/dbtest";
     fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error>;
     fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error>;
-    fn read(&mut self, page_id: &PageId, page: &mut Page) -> Result<(), Error>;
+    fn read(&self, page_id: &PageId, page: &mut Page) -> Result<(), Error>;
     fn write(&mut self, page_id: &PageId, data: &Page) -> Result<(), Error>;
     fn flush(&mut self) -> Result<(), Error>;
+
+    /// Read a batch of pages; pagers may keep several reads in flight
+    fn read_many(&self, pages: &mut [(PageId, Page)]) -> Result<(), Error> {
+        pages
+            .iter_mut()
+            .try_for_each(|(page_id, page)| self.read(page_id, page))
+    }
+
+    /// Write a batch of pages in any order; pagers may coalesce adjacent ids
+    fn write_many(&mut self, pages: &[(PageId, Page)]) -> Result<(), Error> {
+        pages
+            .iter()
+            .try_for_each(|(page_id, page)| self.write(page_id, page))
+    }
+
+    /// Write `data` at `offset` of `file`, the superblock file of the tree. The superblock is the
+    /// commit point, so pagers that inject faults intercept it along with the storage writes
+    fn write_superblock(&mut self, file: &File, offset: u64, data: &[u8]) -> Result<(), Error> {
+        file.write_all_at(data, offset)?;
+        Ok(())
+    }
+
+    /// Make the writes to the superblock file `file` durable
+    fn sync_superblock(&mut self, file: &File) -> Result<(), Error> {
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Borrow `len` on-disk bytes starting at a page if the pager can do it without copying
+    fn page_slice(&self, _page_id: &PageId, _len: usize) -> Option<&[u8]> {
+        None
+    }
 }
 
 pub struct SimplePager {
@@ -47,23 +82,81 @@ impl Pager for SimplePager {
         Ok(Self { file: fd })
     }
 
-    fn read(&mut self, page_id: &PageId, page: &mut Page) -> Result<(), Error> {
-        self.file.seek(SeekFrom::Start(page_id * PAGESIZE))?;
-        self.file.read_exact(page.into())?;
+    fn read(&self, page_id: &PageId, page: &mut Page) -> Result<(), Error> {
+        self.file.read_exact_at(page.into(), page_id * PAGESIZE)?;
         Ok(())
     }
     fn write(&mut self, page_id: &PageId, data: &Page) -> Result<(), Error> {
-        self.file.seek(SeekFrom::Start(page_id * PAGESIZE))?;
-        self.file.write_all(data.into())?;
+        self.file.write_all_at(data.into(), page_id * PAGESIZE)?;
         Ok(())
     }
 
+    fn write_many(&mut self, pages: &[(PageId, Page)]) -> Result<(), Error> {
+        write_runs(&self.file, pages)
+    }
+
     fn flush(&mut self) -> Result<(), Error> {
         self.file.sync_all()?;
         Ok(())
     }
 }
 
+/// Sort pages by id and write every run of adjacent ids with `pwritev`
+pub(crate) fn write_runs(file: &File, pages: &[(PageId, Page)]) -> Result<(), Error> {
+    let mut sorted: Vec<_> = pages.iter().collect();
+    sorted.sort_unstable_by_key(|(page_id, _)| *page_id);
+    let mut start = 0;
+    for end in 1..=sorted.len() {
+        if end == sorted.len() || sorted[end].0 != sorted[end - 1].0 + 1 {
+            let run: Vec<&Page> = sorted[start..end].iter().map(|(_, p)| p).collect();
+            pwritev_all(file, &run, sorted[start].0 * PAGESIZE)?;
+            start = end;
+        }
+    }
+    Ok(())
+}
+
+fn pwritev_all(file: &File, mut pages: &[&Page], mut offset: u64) -> Result<(), Error> {
+    while !pages.is_empty() {
+        let iovecs: Vec<_> = pages[..pages.len().min(MAX_IOVECS)]
+            .iter()
+            .map(|p| libc::iovec {
+                iov_base: p.as_ptr() as *mut libc::c_void,
+                iov_len: PAGESIZE as usize,
+            })
+            .collect();
+        let written = unsafe {
+            libc::pwritev(
+                file.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+                offset as libc::off_t,
+            )
+        };
+        if written < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e.into());
+        } else if written == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into());
+        }
+        let written = written as u64;
+        let full = (written / PAGESIZE) as usize;
+        offset += full as u64 * PAGESIZE;
+        pages = &pages[full..];
+        // Finish a partially written page before the next batch
+        let partial = (written % PAGESIZE) as usize;
+        if partial > 0 {
+            file.write_all_at(&pages[0][partial..], offset + partial as u64)?;
+            offset += PAGESIZE;
+            pages = &pages[1..];
+        }
+    }
+    Ok(())
+}
+
 #[test]
 fn test_persist() {
     let mut pager = if let Ok(p) = SimplePager::open("/tmp/dbtest_p") {
@@ -82,3 +175,26 @@ fn test_persist() {
     let b1: &[u8] = (&b).into();
     assert_eq!(a1, b1);
 }
+
+#[test]
+fn test_write_many() {
+    use crate::testing::TempPath;
+    let temp = TempPath::new("write_many");
+    let path = temp.path();
+    let mut pager = SimplePager::new(path).unwrap();
+    let ids = [7, 3, 4, 5, 9, 8, 1];
+    let pages: Vec<_> = ids
+        .iter()
+        .map(|&id| {
+            let mut page = Page::default();
+            page.fill(id as u8);
+            (id, page)
+        })
+        .collect();
+    pager.write_many(&pages).unwrap();
+    for (id, page) in pages {
+        let mut b = Page::default();
+        pager.read(&id, &mut b).unwrap();
+        assert_eq!(&page[..], &b[..]);
+    }
+}
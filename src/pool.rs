@@ -1,87 +1,179 @@
 use derive_more::Deref;
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::{num::NonZeroUsize, path::Path};
 
 use lru::LruCache;
 
 use crate::{
+    error::Error,
+    extent,
     node::Node,
-    page::Page,
+    page::{Page, NODE_SIZE},
     pager::{PageId, Pager, SimplePager},
+    seal::Seal,
+    types::{layout, FORMAT_VERSION},
+    view::{NodeRef, NodeView},
 };
 
 #[derive(Deref)]
-pub struct NodeCache {
+pub struct NodeCache<P: Pager = SimplePager> {
     #[deref]
     cache: LruCache<PageId, Node>,
-    pager: SimplePager,
+    /// Serialized clean nodes that were only looked up, decoded once they are needed
+    raw: LruCache<PageId, Vec<u8>>,
+    pager: P,
     taken: HashSet<PageId>,
+    /// Encrypts nodes on their way to the pager when the tree has a key
+    seal: Option<Seal>,
 }
 
-impl NodeCache {
-    pub fn acquire(&mut self, page_id: &PageId) -> Node {
-        self.get(page_id);
+impl<P: Pager> NodeCache<P> {
+    pub fn acquire(&mut self, page_id: &PageId) -> Result<Node, Error> {
+        self.get(page_id)?;
         let node = self.cache.pop(page_id).unwrap();
         self.taken.insert(*page_id);
-        node
+        Ok(node)
     }
 
-    pub fn release(&mut self, page_id: PageId, mut node: Node) {
+    pub fn release(&mut self, page_id: PageId, mut node: Node) -> Result<(), Error> {
         node.dirt();
         self.taken.remove(&page_id);
-        self.put(page_id, node);
+        self.put(page_id, node)
     }
 
-    pub fn new<P: AsRef<Path>>(path: P, create: bool, cap: NonZeroUsize) -> Self {
+    pub fn new<Q: AsRef<Path>>(
+        path: Q,
+        create: bool,
+        cap: NonZeroUsize,
+        seal: Option<Seal>,
+    ) -> Self {
         let cache = LruCache::new(cap);
         let pager = if create {
-            SimplePager::new(path)
+            P::new(path)
         } else {
-            SimplePager::open(path)
+            P::open(path)
         }
         .unwrap();
         Self {
             cache,
+            raw: LruCache::new(cap),
             pager,
             taken: HashSet::new(),
+            seal,
         }
     }
 
-    pub fn get<'a>(&'a mut self, page_id: &PageId) -> &'a Node {
+    pub fn pager_mut(&mut self) -> &mut P {
+        &mut self.pager
+    }
+
+    pub fn seal(&self) -> Option<&Seal> {
+        self.seal.as_ref()
+    }
+
+    pub fn get<'a>(&'a mut self, page_id: &PageId) -> Result<&'a Node, Error> {
         debug_assert!(!self.taken.contains(page_id));
         if self.cache.contains(&page_id) {
-            return self.cache.get(&page_id).unwrap();
+            return Ok(self.cache.get(&page_id).unwrap());
         } else {
-            let mut page = Page::default();
-            self.pager
-                .read(page_id, &mut page)
-                .expect(&format!("Failed to page: {}", page_id));
-            self.evict_one_if_full();
-            let node: Node = page.try_into().unwrap();
+            let node: Node = self.load(page_id)?;
+            self.evict_one_if_full()?;
             assert!(!node.dirty());
             self.cache.put(*page_id, node);
-            self.cache.get(page_id).unwrap()
+            Ok(self.cache.get(page_id).unwrap())
+        }
+    }
+
+    /// The node at `page_id` for lookups, viewed in its serialized form unless it is decoded
+    pub fn view(&mut self, page_id: &PageId) -> Result<NodeRef<'_>, Error> {
+        debug_assert!(!self.taken.contains(page_id));
+        // Views only read the current format, older ones are decoded
+        if self.cache.contains(page_id) || layout() != FORMAT_VERSION {
+            return self.get(page_id).map(NodeRef::Node);
+        }
+        if !self.raw.contains(page_id) {
+            let seal = self.seal.as_ref();
+            let bytes = match self.pager.page_slice(page_id, NODE_SIZE as usize) {
+                Some(extent) => match extent::node_bytes(page_id, extent, seal)? {
+                    // Plain nodes are searched in place in the mapping
+                    Cow::Borrowed(bytes) => return Ok(NodeRef::View(NodeView::new(bytes))),
+                    Cow::Owned(bytes) => bytes,
+                },
+                None => {
+                    let mut page = Page::default();
+                    self.pager.read(page_id, &mut page)?;
+                    extent::read_node_bytes(&self.pager, page_id, page, seal)?
+                }
+            };
+            self.raw.put(*page_id, bytes);
         }
+        Ok(NodeRef::View(NodeView::new(self.raw.get(page_id).unwrap())))
     }
 
     #[allow(dead_code)]
-    pub fn get_mut<'a>(&'a mut self, page_id: &PageId) -> &'a mut Node {
+    pub fn get_mut<'a>(&'a mut self, page_id: &PageId) -> Result<&'a mut Node, Error> {
         debug_assert!(!self.taken.contains(page_id));
         let r = if self.cache.contains(&page_id) {
-            return self.cache.get_mut(&page_id).unwrap();
+            return Ok(self.cache.get_mut(&page_id).unwrap());
         } else {
-            let mut page = Page::default();
-            self.pager.read(page_id, &mut page).unwrap();
-            self.evict_one_if_full();
-            let node: Node = page.try_into().unwrap();
+            let node: Node = self.load(page_id)?;
+            self.evict_one_if_full()?;
             self.cache.put(*page_id, node);
             self.cache.get_mut(&page_id).unwrap()
         };
         r.dirt();
-        r
+        Ok(r)
+    }
+
+    /// Drop the cached copies of a released page
+    pub fn forget(&mut self, page_id: &PageId) {
+        debug_assert!(!self.taken.contains(page_id));
+        self.cache.pop(page_id);
+        self.raw.pop(page_id);
     }
 
-    fn evict_one_if_full(&mut self) {
+    /// Load the uncached pages among `page_ids` with one batched read, which pagers may keep in
+    /// flight together, before they are visited
+    pub fn prefetch(&mut self, page_ids: &[PageId]) -> Result<(), Error> {
+        // Leave room so prefetched nodes do not evict each other
+        let room = <NonZeroUsize as Into<usize>>::into(self.cache.cap()) / 2;
+        let mut pages: Vec<(PageId, Page)> = page_ids
+            .iter()
+            .filter(|p| {
+                !self.cache.contains(p) && !self.raw.contains(p) && !self.taken.contains(p)
+            })
+            // Mapped pages are read in place instead
+            .filter(|p| self.pager.page_slice(p, NODE_SIZE as usize).is_none())
+            .take(room)
+            .map(|p| (*p, Page::default()))
+            .collect();
+        if pages.len() < 2 {
+            return Ok(());
+        }
+        self.pager.read_many(&mut pages)?;
+        for (page_id, page) in pages {
+            let node = extent::read_node(&self.pager, &page_id, page, self.seal.as_ref())?;
+            self.evict_one_if_full()?;
+            self.raw.pop(&page_id);
+            self.cache.put(page_id, node);
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, page_id: &PageId) -> Result<Node, Error> {
+        if let Some(bytes) = self.raw.pop(page_id) {
+            return bytes.as_slice().try_into();
+        }
+        if let Some(bytes) = self.pager.page_slice(page_id, NODE_SIZE as usize) {
+            return extent::decode(page_id, bytes, self.seal.as_ref());
+        }
+        let mut page = Page::default();
+        self.pager.read(page_id, &mut page)?;
+        extent::read_node(&self.pager, page_id, page, self.seal.as_ref())
+    }
+
+    fn evict_one_if_full(&mut self) -> Result<(), Error> {
         let len = self.cache.len();
         let cap = <NonZeroUsize as Into<usize>>::into(self.cache.cap());
         debug_assert!(len <= cap);
@@ -89,48 +181,64 @@ impl NodeCache {
             let (page_id, node) = self.cache.pop_lru().unwrap();
             assert!(node.well_formed());
             if node.dirty() {
-                let page: Page = (&node).try_into().unwrap();
                 // TODO flush all dirty children
-                self.pager.write(&page_id, &page).unwrap();
+                self.pager
+                    .write_many(&extent::encode(page_id, &node, self.seal.as_ref()))?;
             }
         }
+        Ok(())
     }
 
     /// Call put before write through
-    pub fn write_through(&mut self, page_id: &PageId) {
+    pub fn write_through(&mut self, page_id: &PageId) -> Result<(), Error> {
         debug_assert!(!self.taken.contains(page_id));
-        let page = if let Some(node) = self.cache.get_mut(page_id).filter(|n| n.dirty()) {
-            let page: Page = (&*node).try_into().unwrap();
-            node.clear();
-            Some(page)
-        } else {
-            None
-        };
-        if let Some(page) = page {
-            self.pager.write(page_id, &page).unwrap();
-            self.flush();
+        let pages = self
+            .cache
+            .peek(page_id)
+            .filter(|n| n.dirty())
+            .map(|node| extent::encode(*page_id, node, self.seal.as_ref()));
+        if let Some(pages) = pages {
+            self.pager.write_many(&pages)?;
+            self.flush()?;
         }
+        Ok(())
+    }
+
+    /// Write nodes that are not cached straight to the pager, adjacent ones in one run
+    pub fn write_uncached(&mut self, nodes: &[(PageId, Node)]) -> Result<(), Error> {
+        let seal = self.seal.as_ref();
+        let pages: Vec<(PageId, Page)> = nodes
+            .iter()
+            .inspect(|(p, _)| debug_assert!(!self.cache.contains(p)))
+            .flat_map(|(p, n)| extent::encode(*p, n, seal))
+            .collect();
+        self.pager.write_many(&pages)
     }
 
-    pub fn put(&mut self, page_id: PageId, mut node: Node) {
+    pub fn put(&mut self, page_id: PageId, mut node: Node) -> Result<(), Error> {
         debug_assert!(!self.taken.contains(&page_id));
         debug_assert!(node.well_formed());
         debug_assert!(!self.cache.contains(&page_id));
         node.dirt();
-        self.evict_one_if_full();
+        self.evict_one_if_full()?;
+        self.raw.pop(&page_id);
         self.cache.put(page_id, node);
+        Ok(())
     }
 
-    pub fn flush(&mut self) {
-        self.cache
-            .iter_mut()
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let seal = self.seal.as_ref();
+        let pages: Vec<(PageId, Page)> = self
+            .cache
+            .iter()
             .filter(|(_, node)| node.dirty())
-            .for_each(|(p, n)| {
-                let data = (&*n).try_into().unwrap();
-                // flush from large page id to small id
-                self.pager.write(p, &data).unwrap();
-                n.clear();
-            });
-        self.pager.flush().unwrap();
+            .flat_map(|(p, n)| extent::encode(*p, n, seal))
+            .collect();
+        // Runs of adjacent page ids go out in one vectored write
+        self.pager.write_many(&pages)?;
+        self.pager.flush()?;
+        // Nodes stay dirty until they are on disk, so a failed flush can be retried
+        self.cache.iter_mut().for_each(|(_, node)| node.clear());
+        Ok(())
     }
 }
@@ -0,0 +1,178 @@
+use crate::node::MsgBuffer;
+use crate::types::{reader_between, MessageData, MessageType, OnDiskKey};
+
+// Range deletes are kept apart from the message buffer, keyed by their first key. A message
+// holds every range delete starting at that key as its versions, each with its own end
+
+/// First key past the keys a range delete covers
+pub fn end(msg: &MessageData) -> &[u8] {
+    msg.val.as_slice()
+}
+
+/// Versions of the range deletes in `deletes` that cover `key`
+pub fn covering<'a>(
+    deletes: &'a MsgBuffer,
+    key: &'a OnDiskKey,
+) -> impl Iterator<Item = &'a MessageData> + 'a {
+    deletes
+        .range::<OnDiskKey, _>(..=key)
+        .flat_map(|(_, msg)| msg.versions())
+        .filter(move |v| key.comparator.compare(key.as_slice(), end(v)).is_lt())
+}
+
+/// Sequence number of the newest range delete in `deletes` that hides `key` from a reader at
+/// `seq`
+pub fn deleted_at(deletes: &MsgBuffer, key: &OnDiskKey, seq: u64) -> Option<u64> {
+    covering(deletes, key)
+        .map(|v| v.seq)
+        .filter(|&v| v <= seq)
+        .max()
+}
+
+/// Deletes of `key` at the sequence numbers of the range deletes covering it
+pub fn tombstones(deletes: &MsgBuffer, key: &OnDiskKey) -> Option<MessageData> {
+    covering(deletes, key)
+        .map(|v| MessageData::with_seq(MessageType::Delete, vec![], v.seq))
+        .reduce(MessageData::merge)
+}
+
+/// Add a range delete, keeping those already starting at `start`
+pub fn insert(deletes: &mut MsgBuffer, start: OnDiskKey, msg: MessageData) {
+    let msg = match deletes.remove(&start) {
+        Some(old) => msg.merge(old),
+        None => msg,
+    };
+    deletes.insert(start, msg);
+}
+
+/// The range deletes in `deletes` cut down to the keys from `lower`(inclusive) up to `upper`
+pub fn within(
+    deletes: &MsgBuffer,
+    lower: Option<&OnDiskKey>,
+    upper: Option<&OnDiskKey>,
+) -> MsgBuffer {
+    let mut clipped = MsgBuffer::new();
+    for (start, msg) in deletes {
+        if upper.is_some_and(|u| start >= u) {
+            break;
+        }
+        let start = match lower {
+            Some(l) if l > start => l,
+            _ => start,
+        };
+        let comparator = start.comparator;
+        let versions = msg.versions().filter_map(|v| {
+            let end = match upper {
+                Some(u) if comparator.compare(u.as_slice(), end(v)).is_lt() => u.as_slice(),
+                _ => end(v),
+            };
+            comparator
+                .compare(start.as_slice(), end)
+                .is_lt()
+                .then(|| MessageData::with_seq(MessageType::RangeDelete, end.to_vec(), v.seq))
+        });
+        if let Some(msg) = versions.reduce(MessageData::merge) {
+            insert(&mut clipped, start.clone(), msg);
+        }
+    }
+    clipped
+}
+
+/// Whether a range delete in `deletes` every reader sees covers all keys from `lower` up to
+/// `upper`
+pub fn spans(deletes: &MsgBuffer, lower: &OnDiskKey, upper: &OnDiskKey, horizon: u64) -> bool {
+    deletes.get(lower).is_some_and(|msg| {
+        msg.versions()
+            .any(|v| v.seq <= horizon && lower.comparator.compare(end(v), upper.as_slice()).is_ge())
+    })
+}
+
+/// Add `newer` to `deletes`, dropping the older versions it covers that no reader in
+/// `readers`(sorted) still needs
+pub fn merge(deletes: MsgBuffer, newer: MsgBuffer, readers: &[u64]) -> MsgBuffer {
+    let mut merged = MsgBuffer::new();
+    for (start, mut msg) in deletes {
+        let comparator = start.comparator;
+        let needed = |v: &MessageData| {
+            !newer.range::<OnDiskKey, _>(..=&start).any(|(_, n)| {
+                n.versions().any(|w| {
+                    comparator.compare(end(w), end(v)).is_ge()
+                        && !reader_between(readers, v.seq, w.seq)
+                })
+            })
+        };
+        let mut versions = vec![];
+        let older = std::mem::take(&mut msg.older);
+        for v in std::iter::once(msg).chain(older) {
+            if needed(&v) {
+                versions.push(v);
+            }
+        }
+        if let Some(msg) = versions.into_iter().reduce(MessageData::merge) {
+            insert(&mut merged, start, msg);
+        }
+    }
+    newer
+        .into_iter()
+        .for_each(|(start, msg)| insert(&mut merged, start, msg));
+    merged
+}
+
+#[test]
+fn test_range_delete() {
+    use crate::testing::{key, TempPath};
+    use crate::Betree;
+    let temp = TempPath::new("range_delete");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    for i in 0..5000 {
+        tree.insert(key(i), b"a".to_vec());
+    }
+    tree.checkpoint("before").unwrap();
+    let reader = tree.reader();
+
+    // Hidden as soon as the delete is buffered, and writes after it are seen
+    tree.delete_range(key(1000), key(4000));
+    tree.insert(key(2000), b"b".to_vec());
+    assert_eq!(tree.get(&key(999)), Some(b"a".to_vec()));
+    assert_eq!(tree.get(&key(1000)), None);
+    assert_eq!(tree.get(&key(2000)), Some(b"b".to_vec()));
+    assert_eq!(tree.get(&key(3999)), None);
+    assert_eq!(tree.get(&key(4000)), Some(b"a".to_vec()));
+    assert_eq!(tree.scan(key(990)..key(4010)).len(), 10 + 1 + 10);
+
+    // Still so once the delete is flushed down to the leaves
+    for i in 5000..20000 {
+        tree.insert(key(i), b"c".to_vec());
+    }
+    tree.flush().unwrap();
+    assert_eq!(tree.scan(..key(5000)).len(), 1000 + 1 + 1000);
+    assert_eq!(tree.get(&key(1500)), None);
+    assert_eq!(tree.get(&key(2000)), Some(b"b".to_vec()));
+    assert_eq!(reader.get(&mut tree, &key(1500)), Some(b"a".to_vec()));
+    assert_eq!(reader.scan(&mut tree, ..key(5000)).len(), 5000);
+    drop(reader);
+
+    // Deleting most of the tree replaces the subtrees it covers
+    tree.delete_range(key(100), key(19900));
+    for i in 20000..30000 {
+        tree.insert(key(i), b"d".to_vec());
+    }
+    tree.flush().unwrap();
+    let left: Vec<Vec<u8>> = tree
+        .scan(..key(20000))
+        .into_iter()
+        .map(|(k, _)| k)
+        .collect();
+    let expected: Vec<Vec<u8>> = (0..100).chain(19900..20000).map(key).collect();
+    assert_eq!(left, expected);
+    assert_eq!(tree.scan(..).len(), 200 + 10000);
+
+    tree.checkpoint("after").unwrap();
+    let removed = tree
+        .diff_checkpoints("before", "after")
+        .unwrap()
+        .filter(|c| matches!(c, crate::Change::Removed { .. }))
+        .count();
+    assert_eq!(removed, 4900);
+}
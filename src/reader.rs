@@ -0,0 +1,115 @@
+use crate::pager::Pager;
+use crate::Betree;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+/// A consistent view of the default tree as of the write before it was taken. The versions it
+/// sees are kept through later writes and flushes until it is dropped
+pub struct Reader {
+    seq: Arc<u64>,
+}
+
+impl Reader {
+    pub(crate) fn new(seq: Arc<u64>) -> Self {
+        Self { seq }
+    }
+
+    /// Sequence number of the last write this reader sees
+    pub fn seq(&self) -> u64 {
+        *self.seq
+    }
+
+    pub fn get<P: Pager>(&self, tree: &mut Betree<P>, key: &[u8]) -> Option<Vec<u8>> {
+        tree.get_at(key, *self.seq)
+            .expect("Failed to read the tree")
+    }
+
+    /// Keys and values in `range`, in key order
+    pub fn scan<P: Pager, R: RangeBounds<Vec<u8>>>(
+        &self,
+        tree: &mut Betree<P>,
+        range: R,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        tree.scan_at(range, *self.seq)
+            .expect("Failed to read the tree")
+    }
+}
+
+#[test]
+fn test_reader() {
+    use crate::testing::{key, TempPath};
+    let temp = TempPath::new("reader");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    for i in 0..2000 {
+        tree.insert(key(i), b"a".to_vec());
+    }
+    let first = tree.reader();
+    for i in 0..2000 {
+        tree.insert(key(i), b"b".to_vec());
+    }
+    tree.flush().unwrap();
+    let second = tree.reader();
+    for i in (0..2000).step_by(3) {
+        tree.insert(key(i), b"c".to_vec());
+    }
+    for i in 2000..2100 {
+        tree.insert(key(i), b"c".to_vec());
+    }
+    tree.flush().unwrap();
+
+    // Each reader keeps its view through writes that reach the leaves
+    for i in (0..2000).step_by(7) {
+        assert_eq!(first.get(&mut tree, &key(i)), Some(b"a".to_vec()));
+        let expected = if i % 3 == 0 { b"c" } else { b"b" };
+        assert_eq!(second.get(&mut tree, &key(i)), Some(b"b".to_vec()));
+        assert_eq!(tree.get(&key(i)), Some(expected.to_vec()));
+    }
+    assert_eq!(first.get(&mut tree, &key(2050)), None);
+    let scanned = first.scan(&mut tree, ..);
+    assert_eq!(scanned.len(), 2000);
+    assert!(scanned.iter().all(|(_, v)| v == b"a"));
+    assert_eq!(second.scan(&mut tree, key(1990)..).len(), 10);
+    assert_eq!(tree.scan(key(1990)..).len(), 110);
+
+    // Once the readers are gone their versions are dropped as writes go by
+    drop(first);
+    drop(second);
+    for i in 0..2100 {
+        tree.insert(key(i), b"d".to_vec());
+    }
+    let reader = tree.reader();
+    assert_eq!(reader.get(&mut tree, &key(5)), Some(b"d".to_vec()));
+    assert!(reader.scan(&mut tree, ..).iter().all(|(_, v)| v == b"d"));
+}
+
+#[test]
+fn test_readers_on_hot_key() {
+    use crate::testing::{key, TempPath};
+    let temp = TempPath::new("reader_hot");
+    let path = temp.path();
+    let value = |i: u64, len: u64| vec![i as u8; len as usize];
+    let payload = crate::page::NODE_PAYLOAD;
+    let mut tree = Betree::new(path);
+    for i in (0..4).chain(10..14) {
+        tree.insert(key(i), value(0, payload / 11));
+    }
+
+    // Readers pin the versions of a key between two halves of a leaf
+    let mut readers = vec![];
+    for i in 0..10 {
+        tree.insert(key(5), value(i, payload / 110));
+        readers.push(tree.reader());
+    }
+    // One more version leaves the key too large for either side of a single split
+    tree.insert(key(5), value(10, payload * 3 / 5));
+    tree.flush().unwrap();
+    for (i, reader) in readers.iter().enumerate() {
+        let expected = value(i as u64, payload / 110);
+        assert_eq!(reader.get(&mut tree, &key(5)), Some(expected));
+        assert_eq!(reader.scan(&mut tree, ..).len(), 9);
+    }
+    assert_eq!(tree.get(&key(5)), Some(value(10, payload * 3 / 5)));
+    assert_eq!(tree.get(&key(3)), Some(value(0, payload / 11)));
+    assert_eq!(tree.get(&key(10)), Some(value(0, payload / 11)));
+}
@@ -0,0 +1,279 @@
+use crate::error::Error;
+use crate::node::{ChildId, InternalNode, NodeType};
+use crate::pager::Pager;
+use crate::pool::NodeCache;
+use crate::range_delete;
+use crate::types::{Comparator, MessageData, OnDiskKey};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::{Bound, RangeBounds};
+
+/// Bounds of a scan, as keys of the tree being scanned
+pub type KeyRange = (Bound<OnDiskKey>, Bound<OnDiskKey>);
+
+/// Sequence number of reads that see every write
+pub const LATEST: u64 = u64::MAX;
+
+/// Keys and values found by a scan, in key order
+pub type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// First and end key of the ranges deleted, for a reader, by range deletes buffered above a
+/// subtree
+pub type Hidden = Vec<(OnDiskKey, Vec<u8>)>;
+
+/// Value of `key` for a reader at `seq` in the tree rooted at `root`
+pub fn lookup<P: Pager>(
+    pool: &mut NodeCache<P>,
+    root: ChildId,
+    key: &OnDiskKey,
+    seq: u64,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut page = root;
+    loop {
+        // Clean nodes are searched in their serialized form
+        match pool.view(&page)?.search(key, seq) {
+            Ok(value) => return Ok(value.map(<[u8]>::to_vec)),
+            Err(child_id) => page = child_id,
+        }
+    }
+}
+
+/// Values of `keys` for a reader at `seq` in the tree rooted at `root`, in the order of `keys`.
+/// The keys descend together in key order, so each node on their paths is searched once
+pub fn lookup_many<P: Pager>(
+    pool: &mut NodeCache<P>,
+    root: ChildId,
+    keys: &[OnDiskKey],
+    seq: u64,
+) -> Result<Vec<Option<Vec<u8>>>, Error> {
+    let mut order: Vec<usize> = (0..keys.len()).collect();
+    order.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+    let mut values = vec![None; keys.len()];
+    lookup_sorted(pool, root, keys, &order, seq, &mut values)?;
+    Ok(values)
+}
+
+/// Look the keys at `order`, sorted, up in the subtree rooted at `page`, partitioning the ones
+/// it does not resolve between its children
+fn lookup_sorted<P: Pager>(
+    pool: &mut NodeCache<P>,
+    page: ChildId,
+    keys: &[OnDiskKey],
+    order: &[usize],
+    seq: u64,
+    values: &mut [Option<Vec<u8>>],
+) -> Result<(), Error> {
+    let mut children: Vec<(ChildId, Vec<usize>)> = vec![];
+    let view = pool.view(&page)?;
+    for &i in order {
+        match view.search(&keys[i], seq) {
+            Ok(value) => values[i] = value.map(<[u8]>::to_vec),
+            // Sorted keys reach each child in one run
+            Err(child_id) => match children.last_mut() {
+                Some((last, run)) if *last == child_id => run.push(i),
+                _ => children.push((child_id, vec![i])),
+            },
+        }
+    }
+    let child_ids: Vec<ChildId> = children.iter().map(|(child_id, _)| *child_id).collect();
+    pool.prefetch(&child_ids)?;
+    for (child_id, run) in children {
+        lookup_sorted(pool, child_id, keys, &run, seq, values)?;
+    }
+    Ok(())
+}
+
+/// Keys and values in `range` for a reader at `seq` of the tree rooted at `root`, in key order
+pub fn scan<P: Pager>(
+    pool: &mut NodeCache<P>,
+    root: ChildId,
+    range: &KeyRange,
+    seq: u64,
+) -> Result<Pairs, Error> {
+    let mut found = BTreeMap::new();
+    if is_empty(range) {
+        return Ok(vec![]);
+    }
+    scan_subtree(pool, root, range, seq, &Hidden::new(), &mut found)?;
+    Ok(found
+        .into_iter()
+        .filter_map(|(k, v)| v.map(|v| (k.to_vec(), v)))
+        .collect())
+}
+
+/// Value a buffered message leaves its key with, None for a delete
+pub fn state(msg: &MessageData) -> Option<Vec<u8>> {
+    msg.value().map(<[u8]>::to_vec)
+}
+
+/// Whether `hidden` covers `key`
+pub fn is_hidden(hidden: &Hidden, key: &OnDiskKey) -> bool {
+    hidden
+        .iter()
+        .any(|(start, end)| start <= key && key.comparator.compare(key, end).is_lt())
+}
+
+/// Add the state of every key in `range` buffered in `internal` for a reader at `seq` below
+/// those already found, then the ranges its range deletes hide to `hidden`
+pub fn buffered(
+    internal: &InternalNode,
+    range: &KeyRange,
+    seq: u64,
+    hidden: &mut Hidden,
+    found: &mut BTreeMap<OnDiskKey, Option<Vec<u8>>>,
+) {
+    internal.msg_buffer.range(range.clone()).for_each(|(k, m)| {
+        if let Some(m) = m.at(seq) {
+            let deleted = is_hidden(hidden, k)
+                || range_delete::deleted_at(&internal.range_deletes, k, seq)
+                    .is_some_and(|d| d > m.seq);
+            found
+                .entry(k.clone())
+                .or_insert_with(|| if deleted { None } else { state(m) });
+        }
+    });
+    for (start, msg) in internal.range_deletes.iter() {
+        hidden.extend(
+            msg.versions()
+                .filter(|v| v.seq <= seq)
+                .map(|v| (start.clone(), range_delete::end(v).to_vec())),
+        );
+    }
+}
+
+/// Collect the state of every key in `range` below `page` for a reader at `seq`, None for
+/// deleted keys, given the ranges `hidden` by range deletes above it. Messages closer to the
+/// root are newer, so a key already found is never overwritten.
+pub fn scan_subtree<P: Pager>(
+    pool: &mut NodeCache<P>,
+    page: ChildId,
+    range: &KeyRange,
+    seq: u64,
+    hidden: &Hidden,
+    found: &mut BTreeMap<OnDiskKey, Option<Vec<u8>>>,
+) -> Result<(), Error> {
+    let mut hidden = hidden.clone();
+    let children = match &pool.get(&page)?.node_inner {
+        NodeType::Leaf(leaf) => {
+            leaf.versions(range.clone()).for_each(|(k, m)| {
+                if let Some(m) = m.at(seq) {
+                    let state = (!is_hidden(&hidden, k)).then(|| state(m)).flatten();
+                    found.entry(k.clone()).or_insert(state);
+                }
+            });
+            leaf.range(range.clone()).for_each(|(k, v)| {
+                let state = (!is_hidden(&hidden, k)).then(|| v.to_vec());
+                found.entry(k.clone()).or_insert(state);
+            });
+            return Ok(());
+        }
+        NodeType::Internal(internal) => {
+            buffered(internal, range, seq, &mut hidden, found);
+            // A child holds the keys from the pivot before it up to its own pivot
+            let mut lower = None;
+            let mut children = vec![];
+            for (pivot, child) in internal.pivot_map.iter() {
+                if overlaps(range, lower, Some(pivot)) {
+                    children.push(*child);
+                }
+                lower = Some(pivot);
+            }
+            if overlaps(range, lower, None) {
+                children.push(internal.rightmost_child);
+            }
+            children
+        }
+        _ => unimplemented!(),
+    };
+    pool.prefetch(&children)?;
+    children
+        .into_iter()
+        .try_for_each(|child| scan_subtree(pool, child, range, seq, &hidden, found))
+}
+
+/// Add the expiry of the newest write of every key below `page` not found above it, None for
+/// writes that never expire
+pub fn expiries<P: Pager>(
+    pool: &mut NodeCache<P>,
+    page: ChildId,
+    found: &mut BTreeMap<OnDiskKey, Option<u64>>,
+) -> Result<(), Error> {
+    let children = match &pool.get(&page)?.node_inner {
+        NodeType::Leaf(leaf) => {
+            leaf.versions(..).for_each(|(k, m)| {
+                found.entry(k.clone()).or_insert(m.expiry());
+            });
+            leaf.expiries().for_each(|(k, expiry)| {
+                found.entry(k.clone()).or_insert(Some(expiry));
+            });
+            return Ok(());
+        }
+        NodeType::Internal(internal) => {
+            internal.msg_buffer.iter().for_each(|(k, m)| {
+                found.entry(k.clone()).or_insert(m.expiry());
+            });
+            let mut children: Vec<ChildId> = internal.pivot_map.values().copied().collect();
+            children.push(internal.rightmost_child);
+            children
+        }
+        _ => unimplemented!(),
+    };
+    pool.prefetch(&children)?;
+    children
+        .into_iter()
+        .try_for_each(|child| expiries(pool, child, found))
+}
+
+/// Whether `range` meets the keys from `lower`(inclusive) to `upper`(exclusive)
+fn overlaps(range: &KeyRange, lower: Option<&OnDiskKey>, upper: Option<&OnDiskKey>) -> bool {
+    let below_upper = match (&range.0, upper) {
+        (Bound::Included(s) | Bound::Excluded(s), Some(u)) => s < u,
+        _ => true,
+    };
+    let above_lower = match (&range.1, lower) {
+        (Bound::Included(e), Some(l)) => e >= l,
+        (Bound::Excluded(e), Some(l)) => e > l,
+        _ => true,
+    };
+    below_upper && above_lower
+}
+
+/// Whether no key can be in `range`, which `BTreeMap::range` would panic on
+pub fn is_empty(range: &KeyRange) -> bool {
+    match range {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+            s >= e
+        }
+        _ => false,
+    }
+}
+
+/// `range` over the keys of a tree ordered by `comparator`
+pub fn key_range<R: RangeBounds<Vec<u8>>>(range: &R, comparator: Comparator) -> KeyRange {
+    let bound = |b: Bound<&Vec<u8>>| match b {
+        Bound::Included(k) => Bound::Included(OnDiskKey::with_comparator(k.clone(), comparator)),
+        Bound::Excluded(k) => Bound::Excluded(OnDiskKey::with_comparator(k.clone(), comparator)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (bound(range.start_bound()), bound(range.end_bound()))
+}
+
+/// Add the pages of the tree rooted at `root` to `pages`, without descending into subtrees
+/// whose root is already there
+pub fn reachable<P: Pager>(
+    pool: &mut NodeCache<P>,
+    root: ChildId,
+    pages: &mut HashSet<ChildId>,
+) -> Result<(), Error> {
+    let mut stack = vec![root];
+    while let Some(page) = stack.pop() {
+        if !pages.insert(page) {
+            continue;
+        }
+        if let NodeType::Internal(internal) = &pool.get(&page)?.node_inner {
+            stack.extend(internal.pivot_map.values());
+            stack.push(internal.rightmost_child);
+        }
+    }
+    Ok(())
+}
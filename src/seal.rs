@@ -0,0 +1,200 @@
+use crate::error::Error;
+use crate::pager::PageId;
+use crate::types::{Serializable, SizedOnDisk};
+
+/// On disk represenation of an encrypted extent:
+/// SEALED_MAGIC: 8 bytes
+/// epoch: 8 bytes, bumped in the superblock every time the tree is opened
+/// counter: 8 bytes, writes sealed since the tree was opened
+/// len: 4 bytes, length of the ciphertext without the tag
+/// ciphertext of the raw or compressed extent
+/// tag: 16 bytes
+/// The nonce is page id, epoch and counter, so it never repeats for one key
+pub const SEALED_MAGIC: u64 = 0x3c0e9a6d71f25b8;
+const SEAL_HEADER: usize = 8 + 8 + 8 + 4;
+const TAG_SIZE: usize = 16;
+
+/// Bytes of every node extent kept for the encryption envelope
+#[cfg(feature = "encryption")]
+pub const SEAL_SIZE: usize = SEAL_HEADER + TAG_SIZE;
+#[cfg(not(feature = "encryption"))]
+pub const SEAL_SIZE: usize = 0;
+
+/// Length in bytes of the sealed extent whose first page is `first`
+pub fn sealed_len(first: &[u8]) -> usize {
+    let mut _cursor = SEAL_HEADER - 4;
+    deserialize_with_var!(len, u32, first, _cursor);
+    SEAL_HEADER + len as usize + TAG_SIZE
+}
+
+#[cfg(feature = "encryption")]
+pub type Key = [u8; 32];
+/// Encryption is compiled out, so no key can be supplied
+#[cfg(not(feature = "encryption"))]
+pub enum Key {}
+
+#[cfg(feature = "encryption")]
+pub struct Seal {
+    cipher: chacha20poly1305::XChaCha20Poly1305,
+    epoch: u64,
+    counter: core::cell::Cell<u64>,
+}
+
+#[cfg(not(feature = "encryption"))]
+pub enum Seal {}
+
+#[cfg(feature = "encryption")]
+impl Seal {
+    pub fn new(key: &Key, epoch: u64) -> Self {
+        use chacha20poly1305::KeyInit;
+        Self {
+            cipher: chacha20poly1305::XChaCha20Poly1305::new(key.into()),
+            epoch,
+            counter: core::cell::Cell::new(0),
+        }
+    }
+
+    /// A seal with the same key for caches that only read. It must never seal, as its nonces
+    /// would repeat those of `self`.
+    pub fn opener(&self) -> Self {
+        Self {
+            cipher: self.cipher.clone(),
+            epoch: self.epoch,
+            counter: self.counter.clone(),
+        }
+    }
+
+    fn nonce(page_id: PageId, epoch: u64, counter: u64) -> chacha20poly1305::XNonce {
+        let mut nonce = chacha20poly1305::XNonce::default();
+        nonce[..8].copy_from_slice(&page_id.to_le_bytes());
+        nonce[8..16].copy_from_slice(&epoch.to_le_bytes());
+        nonce[16..].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+
+    /// Encrypt the extent stored at `page_id`
+    pub fn seal(&self, page_id: PageId, plaintext: &[u8]) -> Vec<u8> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        let counter = self.counter.get();
+        self.counter.set(counter + 1);
+        let mut bytes = vec![0; SEAL_HEADER];
+        let mut _cursor = 0;
+        let len = plaintext.len() as u32;
+        serialize!(SEALED_MAGIC, bytes, _cursor);
+        serialize!(self.epoch, bytes, _cursor);
+        serialize!(counter, bytes, _cursor);
+        serialize!(len, bytes, _cursor);
+        let nonce = Self::nonce(page_id, self.epoch, counter);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &bytes,
+        };
+        let ciphertext = self.cipher.encrypt(&nonce, payload).unwrap();
+        bytes.extend_from_slice(&ciphertext);
+        bytes
+    }
+
+    /// Decrypt the extent read from `page_id`; a bad tag means the page is corrupted,
+    /// was written elsewhere or the key is wrong
+    pub fn open(&self, page_id: PageId, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        let mut _cursor = 0;
+        deserialize_with_var!(magic, u64, bytes, _cursor);
+        deserialize_with_var!(epoch, u64, bytes, _cursor);
+        deserialize_with_var!(counter, u64, bytes, _cursor);
+        let end = sealed_len(bytes);
+        if magic != SEALED_MAGIC || end > bytes.len() {
+            return Err(Error::ChecksumMismatch(page_id));
+        }
+        let nonce = Self::nonce(page_id, epoch, counter);
+        let payload = Payload {
+            msg: &bytes[SEAL_HEADER..end],
+            aad: &bytes[..SEAL_HEADER],
+        };
+        self.cipher
+            .decrypt(&nonce, payload)
+            .map_err(|_| Error::ChecksumMismatch(page_id))
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Seal {
+    pub fn new(key: &Key, _epoch: u64) -> Self {
+        match *key {}
+    }
+
+    pub fn opener(&self) -> Self {
+        match *self {}
+    }
+
+    pub fn seal(&self, _page_id: PageId, _plaintext: &[u8]) -> Vec<u8> {
+        match *self {}
+    }
+
+    pub fn open(&self, _page_id: PageId, _bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_seal() {
+    let seal = Seal::new(&[7; 32], 1);
+    let plaintext = vec![42u8; 1000];
+    let mut bytes = seal.seal(5, &plaintext);
+    assert_eq!(bytes.len(), plaintext.len() + SEAL_SIZE);
+    assert_eq!(sealed_len(&bytes), bytes.len());
+    assert_eq!(seal.open(5, &bytes).unwrap(), plaintext);
+    // Same content, fresh nonce
+    assert_ne!(seal.seal(5, &plaintext), bytes);
+
+    // Moved to another page
+    assert!(matches!(
+        seal.open(6, &bytes),
+        Err(Error::ChecksumMismatch(6))
+    ));
+    // Wrong key
+    let other = Seal::new(&[8; 32], 1);
+    assert!(other.open(5, &bytes).is_err());
+    // Bit flip
+    bytes[SEAL_HEADER + 10] ^= 1;
+    assert!(seal.open(5, &bytes).is_err());
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encrypted_tree() {
+    use crate::testing::TempPath;
+    use crate::Betree;
+    let temp = TempPath::new("seal");
+    let path = temp.path();
+    let key = [3; 32];
+    let value = b"plaintext customer record".to_vec();
+    {
+        let mut tree = Betree::open_encrypted(path, &key).unwrap();
+        for i in 0..2000u64 {
+            tree.insert(i.to_be_bytes().to_vec(), value.clone());
+        }
+        tree.flush().unwrap();
+    }
+    let storage = std::fs::read(format!("{}.storage", path)).unwrap();
+    assert!(!storage.windows(value.len()).any(|w| w == &value[..]));
+
+    let mut tree = Betree::open_encrypted(path, &key).unwrap();
+    for i in 0..2000u64 {
+        assert_eq!(tree.get(&i.to_be_bytes()), Some(value.clone()));
+    }
+    drop(tree);
+    assert!(matches!(
+        Betree::open_encrypted(path, &[4; 32]),
+        Err(Error::ChecksumMismatch(_))
+    ));
+    assert!(matches!(Betree::open(path), Err(Error::KeyMismatch)));
+    assert!(matches!(
+        crate::Snapshot::open_checkpoint(path, "none"),
+        Err(Error::KeyMismatch)
+    ));
+    // The failed opens left the tree as it was
+    let mut tree = Betree::open_encrypted(path, &key).unwrap();
+    assert_eq!(tree.get(&0u64.to_be_bytes()), Some(value));
+}
@@ -0,0 +1,222 @@
+use crate::error::Error;
+use crate::node::ChildId;
+use crate::pager::{Pager, SimplePager};
+use crate::pool::NodeCache;
+use crate::scan::{self, LATEST};
+use crate::seal::{Key, Seal};
+use crate::superblock::Superblock;
+use crate::types::{Comparator, OnDiskKey, FORMAT_VERSION};
+use crate::CFG;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Read-only view of a tree as of a flush, unaffected by later writes
+pub struct Snapshot<P: Pager = SimplePager> {
+    /// Reads through its own cache, so it never sees dirty nodes of the live tree
+    pool: NodeCache<P>,
+    root: ChildId,
+    comparator: Comparator,
+    /// Keeps the pages of `root` from being reclaimed while the snapshot is alive
+    _pin: Arc<ChildId>,
+}
+
+impl Snapshot {
+    /// Open the tree at `path` read-only as of its checkpoint `name`, which must not be deleted
+    /// while the snapshot is in use
+    pub fn open_checkpoint<Q: AsRef<Path>>(path: Q, name: &str) -> Result<Self, Error> {
+        Self::open_checkpoint_with_pager(path, name)
+    }
+
+    /// Open the encrypted tree at `path` read-only as of its checkpoint `name`
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted_checkpoint<Q: AsRef<Path>>(
+        path: Q,
+        name: &str,
+        key: &Key,
+    ) -> Result<Self, Error> {
+        Self::open_encrypted_checkpoint_with_pager(path, name, key)
+    }
+}
+
+impl<P: Pager> Snapshot<P> {
+    /// Open the tree at `path` read-only as of its checkpoint `name` through `P`
+    pub fn open_checkpoint_with_pager<Q: AsRef<Path>>(path: Q, name: &str) -> Result<Self, Error> {
+        Self::load(path, name, None)
+    }
+
+    /// Open the encrypted tree at `path` read-only as of its checkpoint `name` through `P`
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted_checkpoint_with_pager<Q: AsRef<Path>>(
+        path: Q,
+        name: &str,
+        key: &Key,
+    ) -> Result<Self, Error> {
+        Self::load(path, name, Some(key))
+    }
+
+    fn load<Q: AsRef<Path>>(path: Q, name: &str, key: Option<&Key>) -> Result<Self, Error> {
+        let cfg = CFG.get_or_init(|| crate::Args::default());
+        let version = Superblock::format_version(&path)?;
+        if version != FORMAT_VERSION {
+            return Err(Error::IncompatibleFormat(version));
+        }
+        let superblock = Superblock::open(path);
+        superblock.check_extents()?;
+        if superblock.encrypted != key.is_some() {
+            return Err(Error::KeyMismatch);
+        }
+        let root = superblock
+            .checkpoints
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| c.root)
+            .ok_or_else(|| Error::CheckpointNotFound(name.to_owned()))?;
+        // Nodes carry the epoch they were sealed in, so any epoch opens them
+        let pool = NodeCache::new(
+            &superblock.storage_filename,
+            false,
+            cfg.buffer_size.try_into().unwrap(),
+            key.map(|key| Seal::new(key, superblock.epoch)),
+        );
+        Ok(Self::new(pool, root, superblock.comparator, Arc::new(root)))
+    }
+
+    pub(crate) fn new(
+        pool: NodeCache<P>,
+        root: ChildId,
+        comparator: Comparator,
+        pin: Arc<ChildId>,
+    ) -> Self {
+        Self {
+            pool,
+            root,
+            comparator,
+            _pin: pin,
+        }
+    }
+
+    pub fn root(&self) -> ChildId {
+        self.root
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let key = OnDiskKey::with_comparator(key.to_vec(), self.comparator);
+        scan::lookup(&mut self.pool, self.root, &key, LATEST).expect("Failed to read the tree")
+    }
+
+    /// Keys and values in `range`, in key order
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let range = scan::key_range(&range, self.comparator);
+        scan::scan(&mut self.pool, self.root, &range, LATEST).expect("Failed to read the tree")
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    use crate::testing::{key, TempPath};
+    use crate::Betree;
+    let temp = TempPath::new("snapshot");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    for i in 0..2000 {
+        tree.insert(key(i), format!("old{}", i).into_bytes());
+    }
+    tree.flush().unwrap();
+    let mut snapshot = tree.snapshot();
+    assert_eq!(tree.pinned_roots(), vec![snapshot.root()]);
+    for i in 1000..3000 {
+        tree.insert(key(i), format!("new{}", i).into_bytes());
+    }
+    tree.flush().unwrap();
+
+    assert_eq!(snapshot.get(&key(1500)), Some(b"old1500".to_vec()));
+    assert_eq!(snapshot.get(&key(2500)), None);
+    assert_eq!(tree.get(&key(1500)), Some(b"new1500".to_vec()));
+    let old = snapshot.scan(key(990)..key(1010));
+    assert_eq!(old.len(), 20);
+    assert_eq!(old[0], (key(990), b"old990".to_vec()));
+    assert_eq!(old[19], (key(1009), b"old1009".to_vec()));
+    assert_eq!(snapshot.scan(key(1990)..).len(), 10);
+    let new = tree.scan(key(990)..=key(1010));
+    assert_eq!(new.len(), 21);
+    assert_eq!(new[0].1, b"old990".to_vec());
+    assert_eq!(new[20].1, b"new1010".to_vec());
+    assert_eq!(tree.scan(..).len(), 3000);
+
+    drop(snapshot);
+    assert!(tree.pinned_roots().is_empty());
+}
+
+#[test]
+fn test_checkpoints() {
+    use crate::testing::{key, TempPath};
+    use crate::Betree;
+    let temp = TempPath::new("checkpoint");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    for i in 0..2000 {
+        tree.insert(key(i), b"v1".to_vec());
+    }
+    tree.checkpoint("v1").unwrap();
+    for i in 0..2000 {
+        tree.insert(key(i), b"v2".to_vec());
+    }
+    tree.checkpoint("v2").unwrap();
+    assert!(matches!(
+        tree.checkpoint("v1"),
+        Err(Error::CheckpointAlreadyExists(_))
+    ));
+    assert!(matches!(
+        tree.checkpoint(&"v".repeat(5000)),
+        Err(Error::SuperblockFull)
+    ));
+    for i in 0..2000 {
+        tree.insert(key(i), b"v3".to_vec());
+    }
+    tree.flush().unwrap();
+    drop(tree);
+
+    // Checkpoints survive a restart and are readable without a writer
+    let mut v1 = Snapshot::open_checkpoint(path, "v1").unwrap();
+    assert_eq!(v1.get(&key(7)), Some(b"v1".to_vec()));
+    assert!(matches!(
+        Snapshot::open_checkpoint(path, "v0"),
+        Err(Error::CheckpointNotFound(_))
+    ));
+    drop(v1);
+    let mut tree = Betree::open(path).unwrap();
+    let names: Vec<&str> = tree.checkpoints().iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, ["v1", "v2"]);
+    assert_eq!(
+        tree.at_checkpoint("v2").unwrap().get(&key(7)),
+        Some(b"v2".to_vec())
+    );
+
+    // A live snapshot keeps the pages of a deleted checkpoint
+    let mut v1 = tree.at_checkpoint("v1").unwrap();
+    assert_eq!(tree.delete_checkpoint("v1").unwrap(), 0);
+    assert_eq!(v1.get(&key(7)), Some(b"v1".to_vec()));
+    drop(v1);
+    let released = tree.delete_checkpoint("v2").unwrap();
+    assert!(released > 0);
+    assert!(matches!(
+        tree.delete_checkpoint("v2"),
+        Err(Error::CheckpointNotFound(_))
+    ));
+
+    // Released pages are reused by later writes instead of growing the file
+    let storage = format!("{}.storage", path);
+    let len = std::fs::metadata(&storage).unwrap().len();
+    for i in 0..200 {
+        tree.insert(key(i), b"v4".to_vec());
+    }
+    tree.flush().unwrap();
+    assert_eq!(std::fs::metadata(&storage).unwrap().len(), len);
+    assert_eq!(tree.get(&key(7)), Some(b"v4".to_vec()));
+    assert_eq!(tree.get(&key(1999)), Some(b"v3".to_vec()));
+    drop(tree);
+    let mut tree = Betree::open(path).unwrap();
+    assert_eq!(tree.get(&key(7)), Some(b"v4".to_vec()));
+    assert_eq!(tree.get(&key(1999)), Some(b"v3".to_vec()));
+}
@@ -1,38 +1,165 @@
 use crate::{
-    allocator::{PageAllocator, SimpleAllocator},
+    allocator::{FreeList, PageAllocator, SimpleAllocator},
     error::Error,
+    extent::CODEC,
     node::ChildId,
     page::Page,
-    page::PAGESIZE,
-    pager::PageId,
-    types::{Serializable, SizedOnDisk},
+    page::{NODE_PAGES, PAGESIZE},
+    pager::{PageId, Pager},
+    types::{
+        fnv1a, layout, with_layout, Comparator, PageOffset, Serializable, SizedOnDisk, Varint,
+        FORMAT_VERSION,
+    },
     wal::Wal,
 };
-use std::io::{Read, Seek, SeekFrom};
+use derive_more::{Deref, DerefMut};
+use std::collections::{BTreeMap, HashSet};
+use std::os::unix::fs::FileExt;
 use std::{
     fs::{File, OpenOptions},
-    io::Write,
     path::Path,
 };
 
-const MAGIC: u64 = 0x12f81ac;
+/// Changed along with the version field
+const MAGIC: u64 = 0x5b1e7c02;
+/// Generation and checksum ending every slot from format 8
+const TAIL_SIZE: usize = 16;
+/// Magic of format 1 superblocks, which carry no version
+pub const LEGACY_MAGIC: u64 = 0x12f81ac;
 /// On disk represenation(little endian):
 /// MAGIC: 8 bytes
+/// FORMAT_VERSION: 4 bytes
 /// root: 8 bytes
 /// last_checkpoint: 8 bytes
 /// storage_filename
 /// allocator
 /// Wal
+/// encrypted: 1 byte
+/// epoch: 8 bytes
+/// comparator: 1 byte
+/// catalog: number of named trees: varint, then name and root of each
+/// checkpoints: number of checkpoints: varint, then name, root and timestamp of each
+/// seq: 8 bytes, from format 3
+/// free list: first page in this file: 1 byte, length: varint, from format 6
+/// pages per node: 1 byte, codec: 1 byte, from format 7
+/// The free list has no bound, so it takes every other page of this file from its first page on.
+/// A flush writes it to the pages the superblock on disk does not point to.
+/// From format 8 the superblock alternates between two slots, pages 0 and 1, each ending with a
+/// generation: 8 bytes and a FNV-1a checksum of the rest of the page: 8 bytes. The valid slot
+/// of the highest generation is current, so a torn write leaves the previous one in place. The
+/// free list of slot s starts at page 2 + s.
 #[allow(dead_code)]
 pub struct Superblock {
     pub root: PageId,
     pub last_flushed_root: PageId,
+    /// Roots of the named trees as of the last flush
+    pub last_flushed_catalog: Catalog,
+    /// Every page up to this id belongs to a flushed tree and must be copied before writing
+    last_flushed_page: PageId,
+    /// Released pages below `last_flushed_page` handed out again since the last flush
+    reused: HashSet<PageId>,
     last_checkpoint: u64,
     wal: Wal,
     pub storage_filename: String,
     fd: File,
     page: Page,
     pub allocator: SimpleAllocator,
+    /// Nodes are sealed with a key supplied at open
+    pub encrypted: bool,
+    /// Bumped and flushed on every open of an encrypted tree, part of the node nonces
+    pub epoch: u64,
+    /// Order of the keys, fixed when the tree is created
+    pub comparator: Comparator,
+    /// Roots of the named trees sharing this superblock, besides the default one at `root`
+    pub catalog: Catalog,
+    /// Named versions of the default tree kept across restarts, oldest first
+    pub checkpoints: Vec<Checkpoint>,
+    /// Sequence number of the last message written
+    pub seq: u64,
+    /// First page and length of the free list written with the superblock, 0 before any
+    free_list: (u8, usize),
+    /// Pages reserved for every node, NODE_PAGES of the build that created the tree
+    pub node_pages: u8,
+    /// Codec nodes are compressed with, CODEC of the build that created the tree
+    pub codec: u8,
+    /// Slot of the superblock on disk and its generation
+    slot: u8,
+    generation: u64,
+}
+
+/// A flushed root of the default tree kept under a name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub name: String,
+    pub root: PageId,
+    /// Seconds since the Unix epoch when the checkpoint was taken
+    pub timestamp: u64,
+}
+
+impl SizedOnDisk for Checkpoint {
+    fn size(&self) -> PageOffset {
+        self.name.size() + self.root.size() + self.timestamp.size()
+    }
+}
+
+impl Serializable for Checkpoint {
+    fn serialize(&self, destination: &mut [u8]) {
+        let mut _cursor = 0;
+        serialize!(self.name, destination, _cursor);
+        serialize!(self.root, destination, _cursor);
+        serialize!(self.timestamp, destination, _cursor);
+    }
+
+    fn deserialize(src: &[u8]) -> Self {
+        let mut _cursor = 0;
+        deserialize_with_var!(name, String, src, _cursor);
+        deserialize_with_var!(root, PageId, src, _cursor);
+        deserialize_with_var!(timestamp, u64, src, _cursor);
+        Self {
+            name,
+            root,
+            timestamp,
+        }
+    }
+}
+
+/// Named trees of a database and their roots
+#[derive(Default, Clone, Deref, DerefMut)]
+pub struct Catalog(BTreeMap<String, PageId>);
+
+impl SizedOnDisk for Catalog {
+    fn size(&self) -> PageOffset {
+        Varint::from(self.len()).size()
+            + self
+                .iter()
+                .map(|(name, root)| name.size() + root.size())
+                .sum::<PageOffset>()
+    }
+}
+
+impl Serializable for Catalog {
+    fn serialize(&self, destination: &mut [u8]) {
+        let mut _cursor = 0;
+        serialize!(Varint::from(self.len()), destination, _cursor);
+        self.iter().for_each(|(name, root)| {
+            serialize!(name, destination, _cursor);
+            serialize!(root, destination, _cursor);
+        });
+    }
+
+    fn deserialize(src: &[u8]) -> Self {
+        let mut _cursor = 0;
+        deserialize_with_var!(len, Varint, src, _cursor);
+        Self(
+            (0..usize::from(len))
+                .map(|_| {
+                    deserialize_with_var!(name, String, src, _cursor);
+                    deserialize_with_var!(root, PageId, src, _cursor);
+                    (name, root)
+                })
+                .collect(),
+        )
+    }
 }
 
 const META_EXT: &str = ".storage";
@@ -41,17 +168,177 @@ pub const SB_PAGE_ID: u64 = 0;
 
 impl Superblock {
     pub fn alloc(&mut self) -> PageId {
-        self.allocator.alloc()
+        let page_id = self.allocator.alloc();
+        if page_id <= self.last_flushed_page {
+            self.reused.insert(page_id);
+        }
+        page_id
+    }
+
+    /// Reserve a node past every other one, see `SimpleAllocator::alloc_fresh`
+    pub fn alloc_fresh(&mut self) -> PageId {
+        self.allocator.alloc_fresh()
+    }
+
+    /// Whether the serialized superblock fits in its page
+    pub fn fits(&self) -> bool {
+        let size = MAGIC.size()
+            + FORMAT_VERSION.size()
+            + self.root.size()
+            + self.last_checkpoint.size()
+            + self.storage_filename.size()
+            + self.allocator.size()
+            + self.wal.size()
+            + self.encrypted.size()
+            + self.epoch.size()
+            + self.comparator.size()
+            + self.catalog.size()
+            + Varint::from(self.checkpoints.len()).size()
+            + self
+                .checkpoints
+                .iter()
+                .map(|c| c.size())
+                .sum::<PageOffset>();
+        let seq = if layout() < 3 { 0 } else { self.seq.size() };
+        let free_list = if layout() < 6 {
+            0
+        } else {
+            let len = Varint::from(self.allocator.free_list().size());
+            self.free_list.0.size() + len.size()
+        };
+        let extents = if layout() < 7 {
+            0
+        } else {
+            self.node_pages.size() + self.codec.size()
+        };
+        let tail = if layout() < 8 { 0 } else { TAIL_SIZE };
+        size + seq + free_list + extents + tail <= PAGESIZE as PageOffset
+    }
+
+    /// Write the free list of the allocator for the superblock in `slot`, to pages the
+    /// superblock on disk does not point to, durably before the superblock page is written
+    fn write_free_list<P: Pager>(&mut self, pager: &mut P, slot: u8) -> Result<(), Error> {
+        let free_list = self.allocator.free_list();
+        if free_list == FreeList::default() {
+            self.free_list = (0, 0);
+            return Ok(());
+        }
+        let first = match (layout() < 8, self.free_list.0) {
+            (false, _) => 2 + slot,
+            (true, 1) => 2,
+            (true, _) => 1,
+        };
+        let mut bytes = vec![0; free_list.size()];
+        free_list.serialize(&mut bytes);
+        for (i, chunk) in bytes.chunks(PAGESIZE as usize).enumerate() {
+            let page_id = first as u64 + 2 * i as u64;
+            pager.write_superblock(&self.fd, page_id * PAGESIZE, chunk)?;
+        }
+        pager.sync_superblock(&self.fd)?;
+        self.free_list = (first, bytes.len());
+        Ok(())
+    }
+
+    /// Read the free list of `len` bytes from `first` on
+    fn read_free_list(fd: &File, (first, len): (u8, usize)) -> FreeList {
+        if len == 0 {
+            return FreeList::default();
+        }
+        let mut bytes = vec![0; len];
+        for (i, chunk) in bytes.chunks_mut(PAGESIZE as usize).enumerate() {
+            let page_id = first as u64 + 2 * i as u64;
+            fd.read_exact_at(chunk, page_id * PAGESIZE).unwrap();
+        }
+        FreeList::deserialize(&bytes)
     }
 
     fn serialize(&mut self) {
         let mut _cursor = 0;
         let destination: &mut [u8] = (&mut self.page).into();
         serialize!(MAGIC, destination, _cursor);
+        serialize!(layout(), destination, _cursor);
         serialize!(self.root, destination, _cursor);
         serialize!(self.last_checkpoint, destination, _cursor);
         serialize!(self.storage_filename, destination, _cursor);
         serialize!(self.allocator, destination, _cursor);
+        serialize!(self.wal, destination, _cursor);
+        serialize!(self.encrypted, destination, _cursor);
+        serialize!(self.epoch, destination, _cursor);
+        serialize!(self.comparator, destination, _cursor);
+        serialize!(self.catalog, destination, _cursor);
+        serialize!(Varint::from(self.checkpoints.len()), destination, _cursor);
+        self.checkpoints.iter().for_each(|checkpoint| {
+            serialize!(checkpoint, destination, _cursor);
+        });
+        if layout() >= 3 {
+            serialize!(self.seq, destination, _cursor);
+        }
+        if layout() >= 6 {
+            serialize!(self.free_list.0, destination, _cursor);
+            serialize!(Varint::from(self.free_list.1), destination, _cursor);
+        }
+        if layout() >= 7 {
+            serialize!(self.node_pages, destination, _cursor);
+            serialize!(self.codec, destination, _cursor);
+        }
+        if layout() >= 8 {
+            let mut _cursor = PAGESIZE as usize - TAIL_SIZE;
+            serialize!(self.generation + 1, destination, _cursor);
+            let checksum = fnv1a(&destination[.._cursor]);
+            serialize!(checksum, destination, _cursor);
+        }
+    }
+
+    /// Generation of a slot written in format 8 or later, if its checksum matches
+    fn slot_generation(page: &Page) -> Option<u64> {
+        let src: &[u8] = page.into();
+        let mut _cursor = 0;
+        deserialize_with_var!(magic, u64, src, _cursor);
+        deserialize_with_var!(version, u32, src, _cursor);
+        if magic != MAGIC || version < 8 {
+            return None;
+        }
+        let mut _cursor = PAGESIZE as usize - TAIL_SIZE;
+        deserialize_with_var!(generation, u64, src, _cursor);
+        let checksum = fnv1a(&src[.._cursor]);
+        deserialize_with_var!(stored, u64, src, _cursor);
+        (stored == checksum).then_some(generation)
+    }
+
+    /// The current superblock page of `fd` with its slot and generation: the valid slot of the
+    /// highest generation from format 8, page 0 before
+    fn current_page(fd: &File) -> Result<(Page, u8, u64), Error> {
+        let mut current: Option<(Page, u8, u64)> = None;
+        for slot in 0..2 {
+            let mut page = Page::default();
+            // A tree flushed once has no second slot yet
+            if fd
+                .read_exact_at((&mut page).into(), slot as u64 * PAGESIZE)
+                .is_err()
+            {
+                continue;
+            }
+            if let Some(generation) = Self::slot_generation(&page) {
+                if current.as_ref().is_none_or(|c| generation > c.2) {
+                    current = Some((page, slot, generation));
+                }
+            }
+        }
+        if let Some(current) = current {
+            return Ok(current);
+        }
+        let mut page = Page::default();
+        fd.read_exact_at((&mut page).into(), SB_PAGE_ID * PAGESIZE)?;
+        let src: &[u8] = (&page).into();
+        let mut _cursor = 0;
+        deserialize_with_var!(magic, u64, src, _cursor);
+        deserialize_with_var!(version, u32, src, _cursor);
+        if magic == MAGIC && version >= 8 {
+            return Err(Error::UnexpectedError(
+                "No superblock slot passes its checksum".to_owned(),
+            ));
+        }
+        Ok((page, 0, 0))
     }
 
     fn deserialize(page: Page, fd: File) -> Self {
@@ -59,56 +346,140 @@ impl Superblock {
         let mut _cursor = 0;
         deserialize_with_var!(magic, u64, src, _cursor);
         assert_eq!(magic, MAGIC);
+        deserialize_with_var!(version, u32, src, _cursor);
+        assert!(
+            (2..=FORMAT_VERSION).contains(&version),
+            "Unsupported format version"
+        );
         deserialize_with_var!(root, PageId, src, _cursor);
         deserialize_with_var!(last_checkpoint, u64, src, _cursor);
         deserialize_with_var!(storage_filename, String, src, _cursor);
-        deserialize_with_var!(allocator, SimpleAllocator, src, _cursor);
+        let mut allocator = with_layout(version, || deserialize!(SimpleAllocator, src, _cursor));
         info!("root: {}, Deseri: {:?}", root, allocator);
         deserialize_with_var!(wal, Wal, src, _cursor);
+        deserialize_with_var!(encrypted, bool, src, _cursor);
+        deserialize_with_var!(epoch, u64, src, _cursor);
+        deserialize_with_var!(comparator, Comparator, src, _cursor);
+        deserialize_with_var!(catalog, Catalog, src, _cursor);
+        deserialize_with_var!(checkpoints, Varint, src, _cursor);
+        let checkpoints = (0..usize::from(checkpoints))
+            .map(|_| {
+                deserialize_with_var!(checkpoint, Checkpoint, src, _cursor);
+                checkpoint
+            })
+            .collect();
+        let seq = if version < 3 {
+            0
+        } else {
+            deserialize_with_var!(seq, u64, src, _cursor);
+            seq
+        };
+        let free_list = if version < 6 {
+            (0, 0)
+        } else {
+            deserialize_with_var!(first, u8, src, _cursor);
+            deserialize_with_var!(len, Varint, src, _cursor);
+            allocator.set_free_list(Self::read_free_list(&fd, (first, len.into())));
+            (first, len.into())
+        };
+        // Older trees were written by a build with the same extents, or could not be read
+        let (node_pages, codec) = if version < 7 {
+            (NODE_PAGES as u8, CODEC)
+        } else {
+            deserialize_with_var!(node_pages, u8, src, _cursor);
+            deserialize_with_var!(codec, u8, src, _cursor);
+            (node_pages, codec)
+        };
         Self {
             root,
             last_checkpoint,
             last_flushed_root: root,
+            last_flushed_catalog: catalog.clone(),
+            last_flushed_page: allocator.last_allocated(),
+            reused: HashSet::new(),
             storage_filename,
             allocator,
             wal,
             fd,
             page,
+            encrypted,
+            epoch,
+            comparator,
+            catalog,
+            checkpoints,
+            seq,
+            free_list,
+            node_pages,
+            codec,
+            slot: 0,
+            generation: 0,
         }
     }
 
+    /// Fail with `Error::IncompatibleBuild` unless the nodes were written with the extent size
+    /// and codec of this build
+    pub fn check_extents(&self) -> Result<(), Error> {
+        if self.node_pages as u64 != NODE_PAGES || self.codec != CODEC {
+            return Err(Error::IncompatibleBuild {
+                node_pages: self.node_pages,
+                codec: self.codec,
+            });
+        }
+        Ok(())
+    }
+
     pub fn set_root(&mut self, root: PageId) {
         self.root = root;
     }
 
-    pub fn flush_sb(&mut self) -> Result<(), Error> {
-        self.fd.seek(SeekFrom::Start(SB_PAGE_ID * PAGESIZE))?;
+    /// Write the superblock through `pager` to the slot the one on disk is not in
+    pub fn flush_sb<P: Pager>(&mut self, pager: &mut P) -> Result<(), Error> {
+        if !self.fits() {
+            return Err(Error::SuperblockFull);
+        }
+        let slot = if layout() < 8 { 0 } else { 1 - self.slot };
+        if layout() >= 6 {
+            self.write_free_list(pager, slot)?;
+        }
         self.serialize();
-        self.fd.write_all((&self.page).into()).unwrap();
+        pager.write_superblock(&self.fd, slot as u64 * PAGESIZE, (&self.page).into())?;
         // flush != fsync, flush only flushes the data from current process to the kernel
-        self.fd.sync_all().unwrap();
+        pager.sync_superblock(&self.fd)?;
+        self.slot = slot;
+        self.generation += 1;
         self.last_flushed_root = self.root;
+        self.last_flushed_catalog = self.catalog.clone();
+        self.last_flushed_page = self.allocator.last_allocated();
+        self.reused.clear();
         Ok(())
     }
 
-    // Precondition: node ID always increments
+    // Precondition: node ID always increments, but for released pages handed out again
+    // Children copied after the root get larger ids than it, so compare against the allocator
     pub fn safe_to_overwrite_in_place(&self, node: ChildId) -> bool {
-        node > self.last_flushed_root
+        node > self.last_flushed_page || self.reused.contains(&node)
     }
 
     pub fn flush_wal(&mut self) {}
 
-    fn load_superblock(mut fd: File) -> Superblock {
-        fd.seek(SeekFrom::Start(SB_PAGE_ID * PAGESIZE)).unwrap();
-        let mut page = Page::default();
-        fd.read_exact((&mut page).into()).unwrap();
-        Self::deserialize(page, fd)
+    fn load_superblock(fd: File) -> Superblock {
+        let (page, slot, generation) = Self::current_page(&fd).unwrap();
+        let mut superblock = Self::deserialize(page, fd);
+        superblock.slot = slot;
+        superblock.generation = generation;
+        superblock
     }
 
-    pub fn new<P: AsRef<Path>>(path: P) -> Self {
-        let allocator = SimpleAllocator::default();
+    /// Name of the storage file of a new tree whose superblock is at `path`
+    pub fn storage_path<P: AsRef<Path>>(path: P) -> String {
         let mut storage_filename = path.as_ref().to_str().unwrap().to_owned();
         storage_filename.push_str(META_EXT);
+        storage_filename
+    }
+
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let allocator = SimpleAllocator::default();
+        let storage_filename = Self::storage_path(&path);
         let fd = OpenOptions::new()
             .create_new(true)
             .read(true)
@@ -121,10 +492,25 @@ impl Superblock {
             wal,
             root: 0,
             last_flushed_root: 0,
+            last_flushed_catalog: Catalog::default(),
+            last_flushed_page: 0,
+            reused: HashSet::new(),
             last_checkpoint: 0,
             storage_filename,
             fd,
             page: Page::default(),
+            encrypted: false,
+            epoch: 0,
+            comparator: Comparator::Bytewise,
+            catalog: Catalog::default(),
+            checkpoints: vec![],
+            seq: 0,
+            free_list: (0, 0),
+            node_pages: NODE_PAGES as u8,
+            codec: CODEC,
+            // The first flush goes to slot 0
+            slot: 1,
+            generation: 0,
         }
     }
 
@@ -139,7 +525,118 @@ impl Superblock {
         Self::load_superblock(fd)
     }
 
+    /// Format version of the tree whose superblock is at `path`
+    pub fn format_version<P: AsRef<Path>>(path: P) -> Result<u32, Error> {
+        let (page, _, _) = Self::current_page(&File::open(path)?)?;
+        let src: &[u8] = (&page).into();
+        let mut _cursor = 0;
+        deserialize_with_var!(magic, u64, src, _cursor);
+        match magic {
+            LEGACY_MAGIC => Ok(1),
+            MAGIC => {
+                deserialize_with_var!(version, u32, src, _cursor);
+                Ok(version)
+            }
+            _ => Err(Error::UnexpectedError(format!(
+                "Unknown superblock magic: {:#x}",
+                magic
+            ))),
+        }
+    }
+
     pub fn exists<P: AsRef<Path>>(p: &P) -> bool {
         p.as_ref().try_exists().unwrap_or(false)
     }
 }
+
+#[test]
+fn test_free_list_beyond_a_page() {
+    use crate::pager::SimplePager;
+    use crate::testing::TempPath;
+    let temp = TempPath::new("free_list");
+    let path = temp.path();
+    let mut superblock = Superblock::new(path);
+    let mut pager = SimplePager::new(&superblock.storage_filename).unwrap();
+    let pages: Vec<PageId> = (0..6000).map(|_| superblock.alloc()).collect();
+    // No two free nodes are adjacent, so each takes a run of its own
+    pages
+        .iter()
+        .step_by(2)
+        .for_each(|page_id| superblock.allocator.dealloc(*page_id));
+    assert!(superblock.allocator.free_list().size() > PAGESIZE as PageOffset);
+    assert!(superblock.fits());
+    superblock.flush_sb(&mut pager).unwrap();
+    // Flushed again into the other pages
+    superblock.allocator.dealloc(pages[1]);
+    superblock.flush_sb(&mut pager).unwrap();
+    let last = superblock.allocator.last_allocated();
+
+    let mut superblock = Superblock::open(path);
+    let mut free: Vec<PageId> = pages.iter().step_by(2).copied().collect();
+    free.insert(1, pages[1]);
+    for page_id in free {
+        assert_eq!(superblock.alloc(), page_id);
+    }
+    assert_eq!(superblock.alloc(), last + 1);
+}
+
+#[test]
+fn test_extents_of_another_build() {
+    use crate::pager::SimplePager;
+    use crate::testing::TempPath;
+    use crate::{Betree, Snapshot};
+    let temp = TempPath::new("extents");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    tree.insert(b"k".to_vec(), b"v".to_vec());
+    tree.checkpoint("first").unwrap();
+    drop(tree);
+    let mut pager = SimplePager::open(format!("{}.storage", path)).unwrap();
+    for (node_pages, codec) in [(NODE_PAGES as u8 + 1, CODEC), (NODE_PAGES as u8, CODEC + 1)] {
+        let mut superblock = Superblock::open(path);
+        superblock.node_pages = node_pages;
+        superblock.codec = codec;
+        superblock.flush_sb(&mut pager).unwrap();
+        assert!(matches!(
+            Betree::open(path),
+            Err(Error::IncompatibleBuild { node_pages: n, codec: c }) if (n, c) == (node_pages, codec)
+        ));
+        assert!(matches!(
+            Snapshot::open_checkpoint(path, "first"),
+            Err(Error::IncompatibleBuild { .. })
+        ));
+        // Upgrading cannot help
+        assert!(!crate::upgrade(path).unwrap());
+    }
+    let mut superblock = Superblock::open(path);
+    superblock.node_pages = NODE_PAGES as u8;
+    superblock.codec = CODEC;
+    superblock.flush_sb(&mut pager).unwrap();
+    assert_eq!(Betree::open(path).unwrap().get(b"k"), Some(b"v".to_vec()));
+}
+
+#[test]
+fn test_torn_slot() {
+    use crate::testing::TempPath;
+    use crate::Betree;
+    let temp = TempPath::new("torn_slot");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    tree.insert(b"k".to_vec(), b"v1".to_vec());
+    tree.flush().unwrap();
+    tree.insert(b"k".to_vec(), b"v2".to_vec());
+    tree.flush().unwrap();
+    drop(tree);
+    assert_eq!(Betree::open(path).unwrap().get(b"k"), Some(b"v2".to_vec()));
+
+    // A torn write of the newest slot leaves the one before it current
+    let slot = Superblock::open(path).slot;
+    let fd = OpenOptions::new().write(true).open(path).unwrap();
+    fd.write_all_at(&[0xff; 512], slot as u64 * PAGESIZE + 1024)
+        .unwrap();
+    assert_eq!(Superblock::open(path).slot, 1 - slot);
+    assert_eq!(Betree::open(path).unwrap().get(b"k"), Some(b"v1".to_vec()));
+    fd.write_all_at(&[0xff; 512], (1 - slot) as u64 * PAGESIZE + 1024)
+        .unwrap();
+    assert!(Betree::open(path).is_err());
+}
@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A path in the temp dir no other test uses; the files of the tree there are removed on drop
+pub(crate) struct TempPath(String);
+
+impl TempPath {
+    pub(crate) fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let file = format!(
+            "betree_{}_{}_{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = Self(
+            std::env::temp_dir()
+                .join(file)
+                .to_str()
+                .unwrap()
+                .to_string(),
+        );
+        path.remove();
+        path
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.0
+    }
+
+    /// Remove the file at the path and those next to it with a suffix, like `.storage`
+    fn remove(&self) {
+        let path = std::path::Path::new(&self.0);
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let Ok(entries) = std::fs::read_dir(path.parent().unwrap()) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let file = entry.file_name();
+            let file = file.to_string_lossy();
+            if file == name || file.starts_with(&format!("{}.", name)) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// Keys that sort as the numbers they encode
+pub(crate) fn key(i: u64) -> Vec<u8> {
+    i.to_be_bytes().to_vec()
+}
@@ -0,0 +1,210 @@
+use crate::batch::WriteBatch;
+use crate::node::MsgBuffer;
+use crate::pager::Pager;
+use crate::types::{Comparator, MessageData, MessageType, OnDiskKey};
+use crate::{Betree, DEFAULT_TREE};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Weak};
+
+/// Writes to the default tree of one database, buffered until `Betree::commit`, which fails if
+/// a key the transaction read was written in the meantime
+pub struct Transaction {
+    /// Sequence number of the last write before the transaction began
+    start: Arc<u64>,
+    comparator: Comparator,
+    writes: MsgBuffer,
+    /// Keys read from the tree rather than from `writes`
+    reads: HashSet<Vec<u8>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(start: Arc<u64>, comparator: Comparator) -> Self {
+        Self {
+            start,
+            comparator,
+            writes: MsgBuffer::new(),
+            reads: HashSet::new(),
+        }
+    }
+
+    /// Value of `key`, as written by this transaction or else as stored in `tree`
+    pub fn get<P: Pager>(&mut self, tree: &mut Betree<P>, key: &[u8]) -> Option<Vec<u8>> {
+        let on_disk = OnDiskKey::with_comparator(key.to_vec(), self.comparator);
+        if let Some(msg) = self.writes.get(&on_disk) {
+            return Some(msg.val.as_slice().to_vec());
+        }
+        self.reads.insert(key.to_vec());
+        tree.get(key)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, val: Vec<u8>) {
+        let key = OnDiskKey::with_comparator(key, self.comparator);
+        self.writes
+            .insert(key, MessageData::new(MessageType::Insert, val));
+    }
+
+    /// The buffered writes as a batch, or the first key read that has been written since the
+    /// transaction began
+    pub(crate) fn into_batch(self, log: &WriteLog) -> Result<WriteBatch, Vec<u8>> {
+        let comparator = self.comparator;
+        if let Some(key) = self.reads.into_iter().find(|k| {
+            let key = OnDiskKey::with_comparator(k.clone(), comparator);
+            log.written_since(&key, *self.start)
+        }) {
+            return Err(key);
+        }
+        let mut batch = WriteBatch::new();
+        for (key, msg) in self.writes {
+            batch.insert(DEFAULT_TREE, key.to_vec(), msg.val.as_slice().to_vec());
+        }
+        Ok(batch)
+    }
+}
+
+/// Sequence numbers of the writes to the default tree, kept while transactions are open. They
+/// are those of the superblock, which every write takes the next of.
+#[derive(Default)]
+pub(crate) struct WriteLog {
+    /// Start of every transaction still alive, oldest first
+    open: Vec<Weak<u64>>,
+    /// Writes are only kept if they came after this, the start of the oldest open transaction
+    oldest: u64,
+    last_write: BTreeMap<OnDiskKey, u64>,
+    /// First and end key of each range delete, with its sequence number
+    ranges: Vec<(OnDiskKey, OnDiskKey, u64)>,
+    /// Sequence number of the last write replacing every key
+    replaced: u64,
+}
+
+impl WriteLog {
+    /// Begin a transaction after the write with sequence number `seq`
+    pub fn begin(&mut self, seq: u64) -> Arc<u64> {
+        let start = Arc::new(seq);
+        self.open.push(Arc::downgrade(&start));
+        start
+    }
+
+    pub fn record(&mut self, key: OnDiskKey, seq: u64) {
+        if self.any_open() {
+            self.last_write.insert(key, seq);
+        }
+    }
+
+    pub fn record_range(&mut self, start: &OnDiskKey, end: &OnDiskKey, seq: u64) {
+        if self.any_open() {
+            self.ranges.push((start.clone(), end.clone(), seq));
+        }
+    }
+
+    pub fn record_all(&mut self, seq: u64) {
+        if self.any_open() {
+            self.replaced = seq;
+        }
+    }
+
+    fn any_open(&mut self) -> bool {
+        self.open.retain(|start| start.strong_count() > 0);
+        // No transaction can conflict with what was written before it began
+        match self.open.first().and_then(Weak::upgrade) {
+            None => {
+                self.last_write.clear();
+                self.ranges.clear();
+                self.replaced = 0;
+                self.oldest = 0;
+                false
+            }
+            Some(oldest) => {
+                if *oldest > self.oldest {
+                    self.oldest = *oldest;
+                    self.last_write.retain(|_, seq| *seq > *oldest);
+                    self.ranges.retain(|(_, _, seq)| *seq > *oldest);
+                }
+                true
+            }
+        }
+    }
+
+    fn written_since(&self, key: &OnDiskKey, start: u64) -> bool {
+        self.replaced > start
+            || self.last_write.get(key).is_some_and(|&seq| seq > start)
+            || self
+                .ranges
+                .iter()
+                .any(|(first, end, seq)| *seq > start && first <= key && key < end)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.last_write.len()
+    }
+}
+
+#[test]
+fn test_transaction() {
+    use crate::testing::TempPath;
+    use crate::Error;
+    let temp = TempPath::new("transaction");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    tree.insert(b"alice".to_vec(), 100u64.to_be_bytes().to_vec());
+    tree.insert(b"bob".to_vec(), 50u64.to_be_bytes().to_vec());
+    let balance = |v: Option<Vec<u8>>| u64::from_be_bytes(v.unwrap().try_into().unwrap());
+
+    // Read-your-writes, committed as a whole
+    let mut txn = tree.transaction();
+    let alice = balance(txn.get(&mut tree, b"alice"));
+    txn.insert(b"alice".to_vec(), (alice - 30).to_be_bytes().to_vec());
+    assert_eq!(balance(txn.get(&mut tree, b"alice")), 70);
+    let bob = balance(txn.get(&mut tree, b"bob"));
+    txn.insert(b"bob".to_vec(), (bob + 30).to_be_bytes().to_vec());
+    assert_eq!(balance(tree.get(b"alice")), 100);
+    tree.commit(txn).unwrap();
+    assert_eq!(balance(tree.get(b"alice")), 70);
+    assert_eq!(balance(tree.get(b"bob")), 80);
+
+    // A key read by a transaction is written before it commits
+    let mut first = tree.transaction();
+    let mut second = tree.transaction();
+    let alice = balance(first.get(&mut tree, b"alice"));
+    first.insert(b"alice".to_vec(), (alice + 1).to_be_bytes().to_vec());
+    let alice = balance(second.get(&mut tree, b"alice"));
+    second.insert(b"alice".to_vec(), (alice + 2).to_be_bytes().to_vec());
+    second.insert(b"carol".to_vec(), 1u64.to_be_bytes().to_vec());
+    tree.commit(first).unwrap();
+    assert!(matches!(
+        tree.commit(second),
+        Err(Error::TransactionConflict(key)) if key == b"alice"
+    ));
+    assert_eq!(balance(tree.get(b"alice")), 71);
+    assert_eq!(tree.get(b"carol"), None);
+
+    // Writes to keys it did not read do not conflict
+    let mut txn = tree.transaction();
+    txn.get(&mut tree, b"bob");
+    txn.insert(b"carol".to_vec(), 1u64.to_be_bytes().to_vec());
+    tree.insert(b"alice".to_vec(), 0u64.to_be_bytes().to_vec());
+    tree.commit(txn).unwrap();
+    assert_eq!(balance(tree.get(b"carol")), 1);
+}
+
+#[test]
+fn test_write_log_pruning() {
+    let key = |i: u64| OnDiskKey::new(i.to_be_bytes().to_vec());
+    let mut log = WriteLog::default();
+    let old = log.begin(0);
+    for i in 1..=100 {
+        log.record(key(i), i);
+    }
+    assert_eq!(log.len(), 100);
+    let new = log.begin(100);
+    assert!(log.written_since(&key(7), *old));
+    drop(old);
+    // Nothing written before the oldest open transaction began is kept
+    log.record(key(7), 101);
+    assert_eq!(log.len(), 1);
+    assert!(log.written_since(&key(7), *new));
+    assert!(!log.written_since(&key(8), *new));
+    drop(new);
+    log.record(key(9), 102);
+    assert_eq!(log.len(), 0);
+}
@@ -0,0 +1,107 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// An expiring insert stores its expiry, in milliseconds since the Unix epoch, as 8 big endian
+// bytes in front of the value
+
+const EXPIRY_LEN: usize = 8;
+
+#[cfg(test)]
+thread_local! {
+    /// Milliseconds tests moved the clock of their thread forward by
+    static SKEW: core::cell::Cell<u64> = const { core::cell::Cell::new(0) };
+}
+
+/// Move the clock `now` reads on this thread forward by `by`
+#[cfg(test)]
+pub fn advance(by: std::time::Duration) {
+    SKEW.with(|skew| skew.set(skew.get() + by.as_millis() as u64));
+}
+
+/// Milliseconds since the Unix epoch
+pub fn now() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    #[cfg(test)]
+    let now = now + SKEW.with(|skew| skew.get());
+    now
+}
+
+/// Message value of an insert of `val` expiring at `expiry`
+pub fn encode(val: Vec<u8>, expiry: u64) -> Vec<u8> {
+    let mut bytes = expiry.to_be_bytes().to_vec();
+    bytes.extend(val);
+    bytes
+}
+
+pub fn expiry(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes[..EXPIRY_LEN].try_into().unwrap())
+}
+
+/// The value of an expiring insert
+pub fn value(bytes: &[u8]) -> &[u8] {
+    &bytes[EXPIRY_LEN..]
+}
+
+/// The value of an expiring insert, unless it has expired
+pub fn live(bytes: &[u8]) -> Option<&[u8]> {
+    (expiry(bytes) > now()).then(|| value(bytes))
+}
+
+#[test]
+fn test_ttl() {
+    use crate::testing::{key, TempPath};
+    use crate::Betree;
+    use std::time::Duration;
+    let temp = TempPath::new("ttl");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    for i in 0..3000 {
+        tree.insert(key(i), b"a".to_vec());
+    }
+    for i in (0..3000).step_by(3) {
+        tree.insert_with_ttl(key(i), b"short".to_vec(), Duration::from_secs(60));
+    }
+    tree.insert_with_ttl(key(1), b"long".to_vec(), Duration::from_secs(3600));
+    assert_eq!(tree.get(&key(0)), Some(b"short".to_vec()));
+    assert_eq!(tree.get(&key(1)), Some(b"long".to_vec()));
+    assert_eq!(tree.scan(..).len(), 3000);
+
+    // Expired entries are gone, whether still buffered or already in leaves
+    advance(Duration::from_secs(120));
+    assert_eq!(tree.get(&key(0)), None);
+    assert_eq!(tree.get(&key(2)), Some(b"a".to_vec()));
+    assert_eq!(tree.scan(..).len(), 2000);
+    for i in 3000..10000 {
+        tree.insert(key(i), b"b".to_vec());
+    }
+    tree.flush().unwrap();
+    assert_eq!(tree.get(&key(2997)), None);
+    assert_eq!(tree.get(&key(1)), Some(b"long".to_vec()));
+    assert_eq!(tree.scan(..key(3000)).len(), 2000);
+
+    // A plain insert clears the expiry
+    tree.insert(key(0), b"c".to_vec());
+    tree.insert_with_ttl(key(3), b"d".to_vec(), Duration::from_secs(3600));
+    tree.insert(key(3), b"e".to_vec());
+    tree.flush().unwrap();
+    assert_eq!(tree.get(&key(0)), Some(b"c".to_vec()));
+    assert_eq!(tree.get(&key(3)), Some(b"e".to_vec()));
+}
+
+#[test]
+fn test_ttl_past_the_clock() {
+    use crate::testing::TempPath;
+    use crate::Betree;
+    use std::time::Duration;
+    let temp = TempPath::new("ttl_max");
+    let path = temp.path();
+    let mut tree = Betree::new(path);
+    // Expiries past what the clock can count saturate instead of wrapping into the past
+    tree.insert_with_ttl(b"k".to_vec(), b"v".to_vec(), Duration::MAX);
+    tree.flush().unwrap();
+    advance(Duration::from_secs(3600 * 24 * 365));
+    assert_eq!(tree.get(b"k"), Some(b"v".to_vec()));
+    assert_eq!(tree.expiries(None).unwrap()[&b"k".to_vec()], u64::MAX);
+}
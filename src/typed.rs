@@ -0,0 +1,146 @@
+use crate::error::Error;
+use crate::node::MAX_KEY_SIZE;
+use crate::ordered::to_key;
+use crate::pager::{Pager, SimplePager};
+use crate::types::{Comparator, OnDiskKey};
+use crate::Betree;
+use core::marker::PhantomData;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+
+/// A tree of typed keys and values on top of `Betree`. Keys are encoded by `to_key`, so they
+/// keep their order as bytes, and values are CBOR.
+pub struct TypedBetree<K, V, P: Pager = SimplePager> {
+    tree: Betree<P>,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Serialize, V: Serialize + DeserializeOwned> TypedBetree<K, V> {
+    pub fn new<Q: AsRef<Path>>(path: Q) -> Self {
+        Self::wrap(Betree::new(path))
+    }
+
+    pub fn open<Q: AsRef<Path>>(path: Q) -> Result<Self, Error> {
+        Betree::open(path)?.try_into()
+    }
+}
+
+impl<K: Serialize, V: Serialize + DeserializeOwned, P: Pager> TypedBetree<K, V, P> {
+    pub fn new_with_pager<Q: AsRef<Path>>(path: Q) -> Self {
+        Self::wrap(Betree::new_with_pager(path))
+    }
+
+    pub fn open_with_pager<Q: AsRef<Path>>(path: Q) -> Result<Self, Error> {
+        Betree::open_with_pager(path)?.try_into()
+    }
+
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<(), Error> {
+        let key = Self::encode_key(key)?;
+        let mut bytes = vec![];
+        ciborium::into_writer(value, &mut bytes).map_err(|e| Error::SerdeError(e.to_string()))?;
+        self.tree.insert(key, bytes);
+        Ok(())
+    }
+
+    pub fn get(&mut self, key: &K) -> Result<Option<V>, Error> {
+        let key = Self::encode_key(key)?;
+        self.tree
+            .get(&key)
+            .map(|bytes| {
+                ciborium::from_reader(bytes.as_slice())
+                    .map_err(|e| Error::SerdeError(e.to_string()))
+            })
+            .transpose()
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.tree.flush()
+    }
+
+    /// The untyped tree underneath
+    pub fn inner_mut(&mut self) -> &mut Betree<P> {
+        &mut self.tree
+    }
+
+    fn encode_key(key: &K) -> Result<Vec<u8>, Error> {
+        let key = to_key(key)?;
+        if OnDiskKey::size_of_len(key.len()) > MAX_KEY_SIZE {
+            return Err(Error::KeyOverflowError);
+        }
+        Ok(key)
+    }
+}
+
+impl<K, V, P: Pager> TypedBetree<K, V, P> {
+    /// Wrap a new tree, whose keys are ordered bytewise
+    fn wrap(tree: Betree<P>) -> Self {
+        debug_assert_eq!(tree.comparator(), Comparator::Bytewise);
+        Self {
+            tree,
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<K, V, P: Pager> TryFrom<Betree<P>> for TypedBetree<K, V, P> {
+    type Error = Error;
+
+    /// Wrap a tree, which must order its keys bytewise for typed keys to keep their order
+    fn try_from(tree: Betree<P>) -> Result<Self, Error> {
+        match tree.comparator() {
+            Comparator::Bytewise => Ok(Self::wrap(tree)),
+            comparator => Err(Error::ComparatorMismatch(comparator)),
+        }
+    }
+}
+
+#[test]
+fn test_typed_tree() {
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Session {
+        user: String,
+        scopes: Vec<String>,
+        expires: Option<u64>,
+    }
+
+    use crate::testing::TempPath;
+    let temp = TempPath::new("typed");
+    let path = temp.path();
+    let session = |i: i64| Session {
+        user: format!("user{}", i),
+        scopes: vec!["read".to_owned(); i as usize % 3],
+        expires: (i % 2 == 0).then_some(i as u64),
+    };
+    {
+        let mut tree = TypedBetree::<(String, i64), Session>::new(path);
+        for i in -1000..1000 {
+            tree.insert(&("tenant".to_owned(), i), &session(i)).unwrap();
+        }
+        tree.flush().unwrap();
+    }
+    let mut tree = TypedBetree::<(String, i64), Session>::open(path).unwrap();
+    for i in -1000..1000 {
+        assert_eq!(
+            tree.get(&("tenant".to_owned(), i)).unwrap(),
+            Some(session(i))
+        );
+    }
+    assert_eq!(tree.get(&("other".to_owned(), 0)).unwrap(), None);
+    let long = ("k".repeat(MAX_KEY_SIZE), 0);
+    assert!(matches!(
+        tree.insert(&long, &session(0)),
+        Err(Error::KeyOverflowError)
+    ));
+    drop(tree);
+
+    let temp = TempPath::new("typed_reverse");
+    let path = temp.path();
+    drop(Betree::new_with_comparator(path, Comparator::Reverse));
+    assert!(matches!(
+        TypedBetree::<i64, i64>::open(path),
+        Err(Error::ComparatorMismatch(Comparator::Reverse))
+    ));
+}
@@ -1,18 +1,117 @@
+use crate::ttl;
 use core::fmt::Debug;
 use core::mem::size_of;
 use derive_more::{Deref, DerefMut};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use ser_derive::SizedOnDisk;
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 
-pub type OndiskKeyLength = u16;
-pub type OndiskValueLength = u16;
 pub type OndiskFlags = u8;
-pub type OndiskMessageLength = u16;
-pub type OndiskMapLength = u16;
 pub type PageOffset = usize;
-// pub type Comparator = fn(&[u8], &[u8]) -> Ordering;
+
+/// Layout version of the serialization layer, bump it on any change to on-disk encodings
+/// 1: u16 length prefixes, keys stored in full
+/// 2: varint length prefixes, front coded map keys with restart points, version and comparator
+///    in node headers, Bloom filters of internal message buffers
+/// 3: sequence numbers and retained versions of messages, versions in leaves, sequence number in
+///    the superblock
+/// 4: range deletes in internal nodes
+/// 5: expiries of values in leaves
+/// 6: free list of the allocator outside of the superblock page
+/// 7: pages per node and codec in the superblock
+/// 8: two checksummed superblock slots
+pub const FORMAT_VERSION: u32 = 8;
+
+thread_local! {
+    static LAYOUT: core::cell::Cell<u32> = const { core::cell::Cell::new(FORMAT_VERSION) };
+}
+
+/// Format version encodings are read and written in, FORMAT_VERSION unless in `with_layout`
+pub fn layout() -> u32 {
+    LAYOUT.with(|l| l.get())
+}
+
+/// Run `f` reading and writing the encodings of format `version`, from 2 on. Only `upgrade`
+/// reads older layouts; nodes decoded in one must not be written in another.
+pub fn with_layout<T>(version: u32, f: impl FnOnce() -> T) -> T {
+    let outer = LAYOUT.with(|l| l.replace(version));
+    let res = f();
+    LAYOUT.with(|l| l.set(outer));
+    res
+}
+
+/// Order of the keys of a tree, chosen when it is created and recorded in its superblock and
+/// node headers. Ties are broken bytewise, so distinct keys always stay distinct.
+#[derive(FromPrimitive, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Comparator {
+    /// memcmp order
+    #[default]
+    Bytewise = 0,
+    /// Descending memcmp order
+    Reverse,
+    /// Runs of ASCII digits compare by their numeric value
+    Numeric,
+    /// ASCII letters compare regardless of case
+    CaseInsensitive,
+}
+
+impl Comparator {
+    pub fn compare(self, a: &[u8], b: &[u8]) -> Ordering {
+        match self {
+            Self::Bytewise => a.cmp(b),
+            Self::Reverse => b.cmp(a),
+            Self::Numeric => numeric_cmp(a, b).then_with(|| a.cmp(b)),
+            Self::CaseInsensitive => a
+                .iter()
+                .map(u8::to_ascii_lowercase)
+                .cmp(b.iter().map(u8::to_ascii_lowercase))
+                .then_with(|| a.cmp(b)),
+        }
+    }
+}
+
+/// Compare digit runs by value(ignoring leading zeros) and other bytes as they are
+fn numeric_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (da, db) = (digits(a), digits(b));
+        let ord = if da > 0 && db > 0 {
+            fn trim(run: &[u8]) -> &[u8] {
+                let zeros = run.iter().take_while(|c| **c == b'0').count();
+                &run[zeros..]
+            }
+            let (na, nb) = (trim(&a[..da]), trim(&b[..db]));
+            na.len().cmp(&nb.len()).then_with(|| na.cmp(nb))
+        } else {
+            a.first().cmp(&b.first())
+        };
+        if ord != Ordering::Equal || a.is_empty() {
+            return ord;
+        }
+        let (step_a, step_b) = if da > 0 && db > 0 { (da, db) } else { (1, 1) };
+        (a, b) = (&a[step_a..], &b[step_b..]);
+    }
+}
+
+impl Serializable for Comparator {
+    fn serialize(&self, destination: &mut [u8]) {
+        (*self as u8).serialize(destination)
+    }
+
+    fn deserialize(src: &[u8]) -> Self {
+        let num = u8::deserialize(src);
+        Self::from_u8(num).expect("Unknown comparator")
+    }
+}
+
+impl SizedOnDisk for Comparator {
+    fn size(&self) -> PageOffset {
+        1
+    }
+}
 
 pub trait SizedOnDisk: Clone {
     fn size(&self) -> PageOffset;
@@ -21,20 +120,68 @@ pub trait SizedOnDisk: Clone {
     }
 }
 
+/// LEB128 unsigned integer used for lengths: 7 bits per byte, least significant group first,
+/// the high bit is set on every byte but the last
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Varint(pub u64);
+
+impl SizedOnDisk for Varint {
+    fn size(&self) -> PageOffset {
+        let bits = u64::BITS - self.0.leading_zeros();
+        (bits as PageOffset).div_ceil(7).max(1)
+    }
+}
+
+impl Serializable for Varint {
+    fn serialize(&self, destination: &mut [u8]) {
+        let mut n = self.0;
+        let mut i = 0;
+        while n >= 0x80 {
+            destination[i] = n as u8 | 0x80;
+            n >>= 7;
+            i += 1;
+        }
+        destination[i] = n as u8;
+    }
+
+    fn deserialize(src: &[u8]) -> Self {
+        let mut n = 0;
+        for (i, byte) in src.iter().enumerate() {
+            n |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Self(n)
+    }
+}
+
+impl From<usize> for Varint {
+    fn from(value: usize) -> Self {
+        Self(value as u64)
+    }
+}
+
+impl From<Varint> for usize {
+    fn from(value: Varint) -> Self {
+        value.0 as usize
+    }
+}
+
 impl SizedOnDisk for String {
     fn size(&self) -> PageOffset {
-        self.len() + size_of::<OndiskKeyLength>()
+        self.len() + Varint::from(self.len()).size()
     }
 }
 
-impl From<String> for VectorOnDisk<u8, OndiskKeyLength> {
+impl From<String> for VectorOnDisk<u8> {
     fn from(value: String) -> Self {
         value.into_bytes().into()
     }
 }
 
-impl From<VectorOnDisk<u8, OndiskKeyLength>> for String {
-    fn from(value: VectorOnDisk<u8, OndiskKeyLength>) -> Self {
+impl From<VectorOnDisk<u8>> for String {
+    fn from(value: VectorOnDisk<u8>) -> Self {
         let VectorOnDisk { elements, .. } = value;
         String::from_utf8(elements).unwrap()
     }
@@ -133,22 +280,21 @@ macro_rules! deserialize_with_var {
 
 impl Serializable for String {
     fn serialize(&self, destination: &mut [u8]) {
-        let v = VectorOnDisk::<u8, OndiskKeyLength>::from(self.clone());
+        let v = VectorOnDisk::<u8>::from(self.clone());
         serialize!(v, destination);
     }
 
     fn deserialize(src: &[u8]) -> Self {
-        deserialize_with_var!(v, VectorOnDisk::<u8, OndiskKeyLength>, src);
+        deserialize_with_var!(v, VectorOnDisk::<u8>, src);
         v.into()
     }
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Deref)]
-pub struct VectorOnDisk<T: Serializable, L: num::PrimInt + Serializable> {
+pub struct VectorOnDisk<T: Serializable> {
     #[deref]
     elements: Vec<T>,
     size: PageOffset,
-    _p: std::marker::PhantomData<L>,
 }
 
 const ASCII_MOD: u8 = 127;
@@ -167,34 +313,72 @@ impl Debug for OnDiskValue {
     }
 }
 
-impl<T: Serializable, L: num::PrimInt + Serializable> SizedOnDisk for VectorOnDisk<T, L> {
+impl<T: Serializable> SizedOnDisk for VectorOnDisk<T> {
     fn size(&self) -> PageOffset {
         self.size
     }
 }
 
-#[derive(PartialEq, Eq, Ord, PartialOrd, DerefMut, Clone, Deref, SizedOnDisk)]
+#[derive(DerefMut, Clone, Deref)]
 pub struct OnDiskKey {
     // pub flags: OndiskFlags,
     #[deref]
     #[deref_mut]
-    pub bytes: VectorOnDisk<u8, OndiskKeyLength>,
+    pub bytes: VectorOnDisk<u8>,
+    /// Order of the tree the key belongs to, not stored with the key
+    pub comparator: Comparator,
+}
+
+impl SizedOnDisk for OnDiskKey {
+    fn size(&self) -> PageOffset {
+        self.bytes.size()
+    }
+}
+
+impl PartialEq for OnDiskKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
 }
 
-impl<T: Serializable, L: num::PrimInt + Serializable> From<Vec<T>> for VectorOnDisk<T, L> {
+impl Eq for OnDiskKey {}
+
+impl Ord for OnDiskKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        debug_assert_eq!(self.comparator, other.comparator);
+        self.comparator.compare(self, other)
+    }
+}
+
+impl PartialOrd for OnDiskKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Serializable> From<Vec<T>> for VectorOnDisk<T> {
     fn from(elements: Vec<T>) -> Self {
-        let size = size_of::<L>() + elements.iter().map(|e| e.size()).sum::<PageOffset>();
-        Self {
-            elements,
-            size,
-            _p: std::marker::PhantomData,
-        }
+        let size = Varint::from(elements.len()).size()
+            + elements.iter().map(|e| e.size()).sum::<PageOffset>();
+        Self { elements, size }
     }
 }
 
 impl OnDiskKey {
+    /// Bytes a key of `len` bytes takes on disk
+    pub fn size_of_len(len: usize) -> PageOffset {
+        Varint::from(len).size() + len
+    }
+
     pub fn new(key: Vec<u8>) -> Self {
-        Self { bytes: key.into() }
+        Self::with_comparator(key, Comparator::Bytewise)
+    }
+
+    pub fn with_comparator(key: Vec<u8>, comparator: Comparator) -> Self {
+        Self {
+            bytes: key.into(),
+            comparator,
+        }
     }
 
     #[cfg(test)]
@@ -205,16 +389,14 @@ impl OnDiskKey {
             .take(17)
             .map(char::from)
             .collect();
-        Self {
-            bytes: s.into_bytes().into(),
-        }
+        Self::new(s.into_bytes())
     }
 }
 
 #[derive(SizedOnDisk, Clone, Deref, DerefMut)]
 pub struct OnDiskValue {
     // pub flags: OndiskFlags,
-    pub bytes: VectorOnDisk<u8, OndiskValueLength>,
+    pub bytes: VectorOnDisk<u8>,
 }
 
 impl OnDiskValue {
@@ -223,12 +405,77 @@ impl OnDiskValue {
     }
 }
 
-impl<K: SizedOnDisk, V: SizedOnDisk> SizedOnDisk for BTreeMap<K, V> {
+/// Keys of on disk maps, which are front coded: each key only stores the suffix it does not
+/// share with the previous key, except for restart points
+pub trait PrefixKey: Serializable + Ord {
+    fn key_bytes(&self) -> &[u8];
+    fn from_key_bytes(bytes: Vec<u8>) -> Self;
+}
+
+impl PrefixKey for OnDiskKey {
+    fn key_bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+
+    fn from_key_bytes(bytes: Vec<u8>) -> Self {
+        Self::new(bytes)
+    }
+}
+
+fn shared_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+pub type OndiskRestartOffset = u16;
+/// Expected number of entries between restart points
+const RESTART_INTERVAL: u64 = 16;
+
+/// Restart points are stored in full and listed in the offset array of their map, so views can
+/// binary search them. They are picked by a hash of the key rather than by position, which keeps
+/// size updates local.
+pub fn is_restart(key: &[u8]) -> bool {
+    fnv1a(key) % RESTART_INTERVAL == 0
+}
+
+/// FNV-1a hash of `key`
+pub fn fnv1a(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Bytes taken by `key` when it follows `prev` in a map: shared length, suffix length, suffix
+/// and its slot in the offset array if it is a restart point
+fn front_coded_size<K: PrefixKey>(prev: Option<&K>, key: &K) -> PageOffset {
+    let bytes = key.key_bytes();
+    let (shared, slot) = if is_restart(bytes) {
+        (0, size_of::<OndiskRestartOffset>())
+    } else {
+        (prev.map_or(0, |p| shared_prefix(p.key_bytes(), bytes)), 0)
+    };
+    let unshared = bytes.len() - shared;
+    Varint::from(shared).size() + Varint::from(unshared).size() + unshared + slot
+}
+
+/// Number of restart points and bytes of the offset array and entries of a map
+fn body_size<K: PrefixKey, V: SizedOnDisk>(map: &BTreeMap<K, V>) -> (usize, PageOffset) {
+    let mut prev = None;
+    map.iter().fold((0, 0), |(restarts, body), (k, v)| {
+        let size = front_coded_size(prev, k) + v.size();
+        prev = Some(k);
+        (restarts + is_restart(k.key_bytes()) as usize, body + size)
+    })
+}
+
+/// Map header: number of entries, number of restart points, bytes of the body
+fn map_header_size(len: usize, restarts: usize, body: PageOffset) -> PageOffset {
+    Varint::from(len).size() + Varint::from(restarts).size() + Varint::from(body).size()
+}
+
+impl<K: PrefixKey, V: SizedOnDisk> SizedOnDisk for BTreeMap<K, V> {
     fn size(&self) -> PageOffset {
-        self.iter()
-            .map(|(k, v)| k.size() + v.size())
-            .sum::<PageOffset>()
-            + size_of::<OndiskMapLength>()
+        let (restarts, body) = body_size(self);
+        map_header_size(self.len(), restarts, body) + body
     }
 }
 
@@ -236,7 +483,7 @@ impl<K: SizedOnDisk, V: SizedOnDisk> SizedOnDisk for BTreeMap<K, V> {
 pub struct OndiskTuple {
     key: OnDiskKey,
     flags: OndiskFlags,
-    message: VectorOnDisk<u8, OndiskMessageLength>,
+    message: VectorOnDisk<u8>,
 }
 
 #[derive(FromPrimitive, Clone, Copy, Debug)]
@@ -244,6 +491,10 @@ pub enum MessageType {
     Insert = 1,
     Delete,
     Upsert,
+    /// Deletes the keys from the buffer key of the message up to its value(exclusive)
+    RangeDelete,
+    /// Insert whose value starts with the time it expires at, see `ttl`
+    InsertWithTtl,
 }
 
 impl Serializable for MessageType {
@@ -259,13 +510,122 @@ impl Serializable for MessageType {
 
 impl MessageData {
     pub fn new(ty: MessageType, val: Vec<u8>) -> Self {
+        Self::with_seq(ty, val, 0)
+    }
+
+    pub fn with_seq(ty: MessageType, val: Vec<u8>, seq: u64) -> Self {
         Self {
             ty,
             val: OnDiskValue::new(val),
+            seq,
+            older: vec![],
+        }
+    }
+
+    /// Value the message leaves its key with, None for a delete
+    pub fn value(&self) -> Option<&[u8]> {
+        match self.ty {
+            MessageType::Insert => Some(self.val.as_slice()),
+            MessageType::InsertWithTtl => ttl::live(self.val.as_slice()),
+            MessageType::Delete | MessageType::RangeDelete => None,
+            MessageType::Upsert => unimplemented!(),
+        }
+    }
+
+    /// When the value the message leaves its key with expires, None if it never does
+    pub fn expiry(&self) -> Option<u64> {
+        matches!(self.ty, MessageType::InsertWithTtl).then(|| ttl::expiry(self.val.as_slice()))
+    }
+
+    /// Turn the versions that are expiring inserts past `now` into deletes
+    pub fn expire(&mut self, now: u64) {
+        let expire = |m: &mut MessageData| {
+            if matches!(m.ty, MessageType::InsertWithTtl) && ttl::expiry(m.val.as_slice()) <= now {
+                m.ty = MessageType::Delete;
+                m.val = OnDiskValue::new(vec![]);
+            }
+        };
+        self.older.iter_mut().for_each(expire);
+        expire(self);
+    }
+
+    /// The newest version a reader at `seq` sees, None if every version is newer
+    pub fn at(&self, seq: u64) -> Option<&MessageData> {
+        std::iter::once(self)
+            .chain(self.older.iter())
+            .find(|m| m.seq <= seq)
+    }
+
+    /// This version and the older ones, newest first
+    pub fn versions(&self) -> impl Iterator<Item = &MessageData> {
+        std::iter::once(self).chain(self.older.iter())
+    }
+
+    /// Combine the versions of two messages for the same key, newest first
+    pub fn merge(self, other: MessageData) -> MessageData {
+        let mut versions = vec![];
+        for mut msg in [self, other] {
+            let older = std::mem::take(&mut msg.older);
+            versions.push(msg);
+            versions.extend(older);
+        }
+        versions.sort_by_key(|m| std::cmp::Reverse(m.seq));
+        versions.dedup_by_key(|m| m.seq);
+        let mut newest = versions.remove(0);
+        newest.older = versions;
+        newest
+    }
+
+    /// Make `old`, an earlier message for the same key, the history of this one
+    pub fn push_older(&mut self, mut old: MessageData) {
+        debug_assert!(old.seq <= self.seq);
+        let older = std::mem::take(&mut old.older);
+        self.older.push(old);
+        self.older.extend(older);
+    }
+
+    /// Drop the versions no reader in `readers`(sorted) sees. A version is seen by readers from
+    /// its sequence number up to that of the next newer version
+    pub fn prune(&mut self, readers: &[u64]) {
+        let mut newer = self.seq;
+        self.older.retain(|m| {
+            let seen = reader_between(readers, m.seq, newer);
+            newer = m.seq;
+            seen
+        });
+    }
+
+    /// Whether `prune` would drop a version
+    pub fn prunable(&self, readers: &[u64]) -> bool {
+        let mut newer = self.seq;
+        self.older.iter().any(|m| {
+            let seen = reader_between(readers, m.seq, newer);
+            newer = m.seq;
+            !seen
+        })
+    }
+
+    /// Split off the newest version every reader sees, and keep the newer ones
+    pub fn settle(mut self, horizon: u64) -> (Option<MessageData>, Option<MessageData>) {
+        if self.seq <= horizon {
+            self.older.clear();
+            return (Some(self), None);
         }
+        let settled = self
+            .older
+            .iter()
+            .position(|m| m.seq <= horizon)
+            .map(|i| self.older.split_off(i).swap_remove(0));
+        (settled, Some(self))
     }
 }
 
+/// Whether a reader in `readers`(sorted) is at `from` or later and before `to`
+pub fn reader_between(readers: &[u64], from: u64, to: u64) -> bool {
+    let i = readers.partition_point(|&r| r < from);
+    readers.get(i).is_some_and(|&r| r < to)
+}
+
 impl SizedOnDisk for MessageType {
     fn size(&self) -> PageOffset {
         1
@@ -289,26 +649,77 @@ impl SizedOnDisk for bool {
     }
 }
 
-#[derive(SizedOnDisk, Clone, Debug)]
+/// On disk: type, sequence number as a varint, value, then the number of older versions as a
+/// varint and each of them, which have no older versions of their own. Before format 3, only type
+/// and value.
+#[derive(Clone, Debug)]
 pub struct MessageData {
     pub val: OnDiskValue,
     pub ty: MessageType,
+    /// Position of the write among all writes to the database
+    pub seq: u64,
+    /// Earlier messages for the key that readers still see, newest first
+    pub older: Vec<MessageData>,
+}
+
+impl SizedOnDisk for MessageData {
+    fn size(&self) -> PageOffset {
+        if layout() < 3 {
+            return self.ty.size() + self.val.size();
+        }
+        self.ty.size()
+            + Varint(self.seq).size()
+            + self.val.size()
+            + Varint::from(self.older.len()).size()
+            + self.older.iter().map(|m| m.size()).sum::<PageOffset>()
+    }
 }
 
 impl Serializable for MessageData {
     fn serialize(&self, destination: &mut [u8]) {
         let mut _cursor = 0;
         serialize!(self.ty, destination, _cursor);
+        if layout() < 3 {
+            serialize!(self.val, destination, _cursor);
+            return;
+        }
+        serialize!(Varint(self.seq), destination, _cursor);
         serialize!(self.val, destination, _cursor);
+        serialize!(Varint::from(self.older.len()), destination, _cursor);
+        self.older.iter().for_each(|m| {
+            serialize!(m, destination, _cursor);
+        });
         debug_assert_eq!(_cursor, self.size());
     }
 
     fn deserialize(src: &[u8]) -> Self {
         let mut _cursor = 0;
         deserialize_with_var!(ty, MessageType, src, _cursor);
+        if layout() < 3 {
+            deserialize_with_var!(val, OnDiskValue, src, _cursor);
+            return Self {
+                ty,
+                val,
+                seq: 0,
+                older: vec![],
+            };
+        }
+        deserialize_with_var!(seq, Varint, src, _cursor);
         deserialize_with_var!(val, OnDiskValue, src, _cursor);
+        deserialize_with_var!(older, Varint, src, _cursor);
+        let older = (0..usize::from(older))
+            .map(|_| {
+                deserialize_with_var!(m, MessageData, src, _cursor);
+                m
+            })
+            .collect();
 
-        let s = Self { ty, val };
+        let s = Self {
+            ty,
+            val,
+            seq: seq.0,
+            older,
+        };
         debug_assert_eq!(s.size(), _cursor as PageOffset);
         s
     }
@@ -325,6 +736,38 @@ pub trait Serializable: SizedOnDisk {
     fn deserialize(src: &[u8]) -> Self;
 }
 
+/// Values whose encoded length can be read without decoding them, for views over maps
+pub trait EncodedLen {
+    fn encoded_len(src: &[u8]) -> PageOffset;
+}
+
+impl EncodedLen for u64 {
+    fn encoded_len(_src: &[u8]) -> PageOffset {
+        size_of::<Self>()
+    }
+}
+
+impl EncodedLen for OnDiskValue {
+    fn encoded_len(src: &[u8]) -> PageOffset {
+        let len = Varint::deserialize(src);
+        len.size() + usize::from(len)
+    }
+}
+
+impl EncodedLen for MessageData {
+    fn encoded_len(src: &[u8]) -> PageOffset {
+        let mut cursor = MessageType::Insert.size();
+        cursor += Varint::deserialize(&src[cursor..]).size();
+        cursor += OnDiskValue::encoded_len(&src[cursor..]);
+        let older = Varint::deserialize(&src[cursor..]);
+        cursor += older.size();
+        for _ in 0..usize::from(older) {
+            cursor += Self::encoded_len(&src[cursor..]);
+        }
+        cursor
+    }
+}
+
 macro_rules! SerializeImplForNumber {
     ($primitive_ty:ty) => {
         impl Serializable for $primitive_ty {
@@ -376,11 +819,11 @@ SerializeImplForNumber!(u64);
 SerializeImplForNumber!(PageOffset);
 SerializeImplForNumber!(f32);
 
-impl<T: Serializable, L: Serializable + num::PrimInt> Serializable for VectorOnDisk<T, L> {
+impl<T: Serializable> Serializable for VectorOnDisk<T> {
     fn serialize(&self, destination: &mut [u8]) {
         let mut _cursor: usize = 0;
         let len = self.len();
-        let l = L::from(len).unwrap();
+        let l = Varint::from(len);
         serialize!(l, destination, _cursor);
         if let Some(size) = T::is_packed() {
             let total_bytes = size * len;
@@ -399,10 +842,8 @@ impl<T: Serializable, L: Serializable + num::PrimInt> Serializable for VectorOnD
 
     fn deserialize(destination: &[u8]) -> Self {
         let mut _cursor: usize = 0;
-        let l_size = size_of::<L>();
-        let len1 = L::deserialize(&destination[_cursor..]);
-        let len = <usize as num::NumCast>::from(len1).unwrap();
-        _cursor += l_size;
+        deserialize_with_var!(len1, Varint, destination, _cursor);
+        let len = usize::from(len1);
         let bytes_on_disk: Self;
         if let Some(size) = T::is_packed() {
             let total_bytes = len * size;
@@ -434,10 +875,25 @@ impl Serializable for OnDiskKey {
     fn deserialize(src: &[u8]) -> Self {
         Self {
             bytes: VectorOnDisk::deserialize(src),
+            comparator: Comparator::Bytewise,
         }
     }
 }
 
+/// Give the keys of a map read from disk the comparator of their tree. Maps are deserialized
+/// bytewise, as keys do not store their comparator.
+pub fn with_comparator<V>(
+    map: BTreeMap<OnDiskKey, V>,
+    comparator: Comparator,
+) -> BTreeMap<OnDiskKey, V> {
+    if comparator == Comparator::Bytewise {
+        return map;
+    }
+    map.into_iter()
+        .map(|(k, v)| (OnDiskKey { comparator, ..k }, v))
+        .collect()
+}
+
 impl Serializable for OnDiskValue {
     fn serialize(&self, destination: &mut [u8]) {
         self.bytes.serialize(destination);
@@ -450,28 +906,59 @@ impl Serializable for OnDiskValue {
     }
 }
 
-impl<K: Serializable + Ord, V: Serializable> Serializable for BTreeMap<K, V> {
+/// On disk represenation of a map:
+/// number of entries, number of restart points, bytes of the body: varints
+/// body: offsets of the restart points from the first entry: OndiskRestartOffset each, entries
+/// entry: shared prefix length, suffix length: varints, suffix, value
+impl<K: PrefixKey, V: Serializable> Serializable for BTreeMap<K, V> {
     fn serialize(&self, destination: &mut [u8]) {
         let mut _cursor = 0;
-        let len = self.len();
-        let len1 = len as OndiskMapLength;
-        serialize!(len1, destination, _cursor);
+        let (restarts, body) = body_size(self);
+        serialize!(Varint::from(self.len()), destination, _cursor);
+        serialize!(Varint::from(restarts), destination, _cursor);
+        serialize!(Varint::from(body), destination, _cursor);
+        let mut slot = _cursor;
+        _cursor += restarts * size_of::<OndiskRestartOffset>();
+        let entries = _cursor;
+        let mut prev: &[u8] = &[];
         self.iter().for_each(|(k, v)| {
-            serialize!(k, destination, _cursor);
+            let bytes = k.key_bytes();
+            let shared = if is_restart(bytes) {
+                let offset = (_cursor - entries) as OndiskRestartOffset;
+                serialize!(offset, destination, slot);
+                0
+            } else {
+                shared_prefix(prev, bytes)
+            };
+            let suffix = &bytes[shared..];
+            serialize!(Varint::from(shared), destination, _cursor);
+            serialize!(Varint::from(suffix.len()), destination, _cursor);
+            destination[_cursor.._cursor + suffix.len()].copy_from_slice(suffix);
+            _cursor += suffix.len();
             serialize!(v, destination, _cursor);
+            prev = bytes;
         });
         debug_assert_eq!(_cursor, self.size());
     }
 
     fn deserialize(src: &[u8]) -> Self {
         let mut _cursor = 0;
-        let len = usize::from(OndiskMapLength::deserialize(&src[_cursor..]));
-        _cursor += size_of::<OndiskMapLength>();
-        let map: Self = (0..len)
+        deserialize_with_var!(len, Varint, src, _cursor);
+        deserialize_with_var!(restarts, Varint, src, _cursor);
+        deserialize_with_var!(_body, Varint, src, _cursor);
+        _cursor += usize::from(restarts) * size_of::<OndiskRestartOffset>();
+        let mut prev: Vec<u8> = vec![];
+        let map: Self = (0..usize::from(len))
             .map(|_| {
-                deserialize_with_var!(k, K, src, _cursor);
+                deserialize_with_var!(shared, Varint, src, _cursor);
+                deserialize_with_var!(unshared, Varint, src, _cursor);
+                let (shared, unshared) = (usize::from(shared), usize::from(unshared));
+                let mut bytes = prev[..shared].to_vec();
+                bytes.extend_from_slice(&src[_cursor.._cursor + unshared]);
+                _cursor += unshared;
+                prev.clone_from(&bytes);
                 deserialize_with_var!(v, V, src, _cursor);
-                (k, v)
+                (K::from_key_bytes(bytes), v)
             })
             .collect();
         map
@@ -501,14 +988,17 @@ impl Serializable for Message {
 pub struct BTreeMapOnDisk<K: Serializable, V: Serializable> {
     #[deref]
     inner: BTreeMap<K, V>,
+    /// Bytes of the body, without the header
     size: PageOffset,
+    restarts: usize,
 }
-impl<K: Serializable, V: Serializable> BTreeMapOnDisk<K, V> {
+impl<K: PrefixKey, V: Serializable> BTreeMapOnDisk<K, V> {
     // type InnerMap = BTreeMap<K, V>;
     pub fn new() -> Self {
         let inner = BTreeMap::<K, V>::new();
         Self {
-            size: inner.size(),
+            size: 0,
+            restarts: 0,
             inner,
         }
     }
@@ -519,34 +1009,64 @@ impl<K: Serializable, V: Serializable> BTreeMapOnDisk<K, V> {
     }
 
     pub fn refresh_size(&mut self) {
-        self.size = self.inner.size();
+        (self.restarts, self.size) = body_size(&self.inner);
     }
 }
 
-impl<K: Serializable + Ord, V: Serializable> BTreeMapOnDisk<K, V> {
+impl<K: PrefixKey, V: Serializable> BTreeMapOnDisk<K, V> {
     // type InnerMap = BTreeMap<K, V>;
+    /// Front coded size of `key` and of its successor re-coded against it, and the size of
+    /// the successor coded against the predecessor instead
+    fn neighbour_sizes(&self, key: &K) -> (PageOffset, PageOffset) {
+        use std::ops::Bound;
+        let prev = self.inner.range(..key).next_back().map(|(k, _)| k);
+        let next = self
+            .inner
+            .range::<K, _>((Bound::Excluded(key), Bound::Unbounded))
+            .next()
+            .map(|(k, _)| k);
+        let with = front_coded_size(prev, key) + next.map_or(0, |n| front_coded_size(Some(key), n));
+        let without = next.map_or(0, |n| front_coded_size(prev, n));
+        (with, without)
+    }
+
     pub fn pop_last(&mut self) -> Option<(K, V)> {
+        let (with, without) = self
+            .inner
+            .last_key_value()
+            .map_or((0, 0), |(k, _)| self.neighbour_sizes(k));
         let last = self.inner.pop_last();
-        self.size -= last.size();
+        self.restarts -= last
+            .as_ref()
+            .map_or(0, |(k, _)| is_restart(k.key_bytes()) as usize);
+        self.size += without;
+        self.size -= with + last.as_ref().map_or(0, |(_, v)| v.size());
         last
     }
 
     pub fn insert(&mut self, k: K, v: V) -> Option<V> {
-        let k_size = k.size();
+        let sizes = (!self.inner.contains_key(&k)).then(|| self.neighbour_sizes(&k));
+        if sizes.is_some() {
+            self.restarts += is_restart(k.key_bytes()) as usize;
+        }
         self.size += v.size();
         let res = self.inner.insert(k, v);
-        if res.is_some() {
-            self.size -= res.size();
+        if let Some((with, without)) = sizes {
+            self.size += with;
+            self.size -= without;
         } else {
-            self.size += k_size;
+            self.size -= res.size();
         };
         res
     }
 
     pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (with, without) = self.neighbour_sizes(key);
         let res = self.inner.remove(key);
         if res.is_some() {
-            self.size -= key.size() + res.size();
+            self.restarts -= is_restart(key.key_bytes()) as usize;
+            self.size += without;
+            self.size -= with + res.size();
         }
         res
     }
@@ -556,12 +1076,12 @@ impl<K: Serializable + Ord, V: Serializable> BTreeMapOnDisk<K, V> {
             core::mem::swap(&mut self.inner, other);
         }
         self.inner.append(other);
-        self.size = self.inner.size();
+        (self.restarts, self.size) = body_size(&self.inner);
     }
 
     pub fn split_off(&mut self, key: &K) -> BTreeMap<K, V> {
         let new_map = self.inner.split_off(key);
-        self.size = self.inner.size();
+        (self.restarts, self.size) = body_size(&self.inner);
         new_map
     }
 }
@@ -582,31 +1102,38 @@ impl<T: SizedOnDisk> SizedOnDisk for Option<T> {
     }
 }
 
-impl<K: Serializable, V: Serializable> SizedOnDisk for BTreeMapOnDisk<K, V> {
+impl<K: PrefixKey, V: Serializable> SizedOnDisk for BTreeMapOnDisk<K, V> {
     fn size(&self) -> PageOffset {
-        self.size
+        map_header_size(self.inner.len(), self.restarts, self.size) + self.size
     }
 }
 
-impl<K: Serializable + Ord, V: Serializable> Serializable for BTreeMapOnDisk<K, V> {
+impl<K: PrefixKey, V: Serializable> Serializable for BTreeMapOnDisk<K, V> {
     fn serialize(&self, destination: &mut [u8]) {
         serialize!(self.inner, destination);
     }
 
     fn deserialize(src: &[u8]) -> Self {
         let mut _cursor = 0;
-        deserialize_with_var!(inner, BTreeMap<K, V>, src, _cursor);
+        deserialize_with_var!(_len, Varint, src, _cursor);
+        deserialize_with_var!(restarts, Varint, src, _cursor);
+        deserialize_with_var!(size, Varint, src, _cursor);
         Self {
-            inner,
-            size: _cursor,
+            inner: BTreeMap::deserialize(src),
+            size: size.into(),
+            restarts: restarts.into(),
         }
     }
 }
 
-impl<K: Serializable + Ord, V: Serializable> From<BTreeMap<K, V>> for BTreeMapOnDisk<K, V> {
+impl<K: PrefixKey, V: Serializable> From<BTreeMap<K, V>> for BTreeMapOnDisk<K, V> {
     fn from(inner: BTreeMap<K, V>) -> Self {
-        let size = inner.size();
-        Self { inner, size }
+        let (restarts, size) = body_size(&inner);
+        Self {
+            inner,
+            size,
+            restarts,
+        }
     }
 }
 
@@ -628,10 +1155,10 @@ fn endian() {
 #[test]
 fn test_serialization() {
     let a: Vec<_> = (-1000..1000).collect();
-    let bs = VectorOnDisk::<_, u16>::from(a);
+    let bs = VectorOnDisk::<_>::from(a);
     let mut b = vec![0u8; 84000];
     bs.serialize(&mut b);
-    let bs1: VectorOnDisk<i32, u16> = VectorOnDisk::deserialize(&b);
+    let bs1: VectorOnDisk<i32> = VectorOnDisk::deserialize(&b);
     assert_eq!(bs.elements, bs1.elements);
 }
 
@@ -646,3 +1173,93 @@ fn test_map_serialization() {
     let db = BTreeMap::<OnDiskKey, OnDiskKey>::deserialize(&page);
     assert_eq!(btree, db);
 }
+
+#[test]
+fn test_front_coded_size() {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut map = BTreeMapOnDisk::<OnDiskKey, OnDiskValue>::new();
+    for i in 0..2000u64 {
+        let key = OnDiskKey::new(rng.gen_range(0..500u64).to_be_bytes().to_vec());
+        match i % 4 {
+            0 => {
+                map.remove(&key);
+            }
+            1 if i % 7 == 0 => {
+                map.pop_last();
+            }
+            _ => {
+                map.insert(key, OnDiskValue::new(vec![1; i as usize % 9]));
+            }
+        }
+        assert_eq!(map.size(), map.inner.size());
+        assert_eq!((map.restarts, map.size), body_size(&map.inner));
+    }
+    // Big-endian u64 keys below 500 share their first 6 bytes
+    let full: PageOffset = map.keys().map(|k| k.size() + 2).sum();
+    assert!(map.size() < full);
+
+    let mut page = [0; 8000];
+    map.serialize(&mut page);
+    let db = BTreeMapOnDisk::<OnDiskKey, OnDiskValue>::deserialize(&page);
+    assert_eq!(db.size(), map.size());
+    let entries = |m: &BTreeMapOnDisk<OnDiskKey, OnDiskValue>| -> Vec<(OnDiskKey, Vec<u8>)> {
+        m.iter().map(|(k, v)| (k.clone(), v.to_vec())).collect()
+    };
+    assert_eq!(entries(&db), entries(&map));
+}
+
+#[test]
+fn test_varint() {
+    let mut bytes = [0; 10];
+    for (n, size) in [
+        (0, 1),
+        (127, 1),
+        (128, 2),
+        (16383, 2),
+        (16384, 3),
+        (u64::MAX, 10),
+    ] {
+        let v = Varint(n);
+        assert_eq!(v.size(), size);
+        v.serialize(&mut bytes);
+        assert_eq!(Varint::deserialize(&bytes), v);
+    }
+    // A short key costs one length byte instead of two
+    assert_eq!(OnDiskKey::new(vec![1; 8]).size(), 9);
+}
+
+#[test]
+fn test_comparator() {
+    use crate::Betree;
+    use std::cmp::Ordering::*;
+    let cmp = |c: Comparator, a: &str, b: &str| c.compare(a.as_bytes(), b.as_bytes());
+    assert_eq!(cmp(Comparator::Bytewise, "k10", "k9"), Less);
+    assert_eq!(cmp(Comparator::Reverse, "k10", "k9"), Greater);
+    assert_eq!(cmp(Comparator::Numeric, "k10", "k9"), Greater);
+    assert_eq!(cmp(Comparator::Numeric, "k10x", "k010a"), Greater);
+    assert_eq!(cmp(Comparator::Numeric, "k010", "k10"), Less);
+    assert_eq!(cmp(Comparator::Numeric, "k9", "ka"), Less);
+    assert_eq!(cmp(Comparator::CaseInsensitive, "B", "a"), Greater);
+    assert_eq!(cmp(Comparator::CaseInsensitive, "A", "a"), Less);
+
+    // Pivots and buffers read back from disk must route keys by the same order
+    for comparator in [Comparator::Reverse, Comparator::Numeric] {
+        use crate::testing::TempPath;
+        let temp = TempPath::new("comparator");
+        let path = temp.path();
+        let key = |i: u64| format!("key{}", i).into_bytes();
+        {
+            let mut tree = Betree::new_with_comparator(path, comparator);
+            for i in 0..3000 {
+                tree.insert(key(i), i.to_be_bytes().to_vec());
+            }
+            tree.flush().unwrap();
+        }
+        let mut tree = Betree::open(path).unwrap();
+        for i in 0..3000 {
+            assert_eq!(tree.get(&key(i)), Some(i.to_be_bytes().to_vec()));
+        }
+        assert_eq!(tree.get(b"key3000"), None);
+    }
+}
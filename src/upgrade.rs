@@ -0,0 +1,420 @@
+use crate::error::Error;
+use crate::node::MAGIC;
+use crate::page::PAGESIZE;
+use crate::pager::{PageId, SimplePager};
+use crate::scan::LATEST;
+use crate::seal::Key;
+use crate::superblock::{Checkpoint, Superblock, LEGACY_MAGIC};
+use crate::types::{with_layout, Comparator, MessageType, FORMAT_VERSION};
+use crate::Betree;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// Reader of format 1 layouts: u16 length prefixes, keys stored in full, one page per node
+struct V1Reader<'a> {
+    src: &'a [u8],
+    cursor: usize,
+}
+
+impl<'a> V1Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .src
+            .get(self.cursor..self.cursor + len)
+            .ok_or_else(|| Error::UnexpectedError("Truncated format 1 page".to_owned()))?;
+        self.cursor += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.u16()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+/// Root id and storage file of a format 1 superblock
+fn read_v1_superblock(path: &Path) -> Result<(PageId, PathBuf), Error> {
+    let mut page = vec![0; PAGESIZE as usize];
+    File::open(path)?.read_exact_at(&mut page, 0)?;
+    let mut reader = V1Reader {
+        src: &page,
+        cursor: 0,
+    };
+    if reader.u64()? != LEGACY_MAGIC {
+        return Err(Error::UnexpectedError(
+            "Not a format 1 superblock".to_owned(),
+        ));
+    }
+    let root = reader.u64()?;
+    let _last_checkpoint = reader.u64()?;
+    let storage = String::from_utf8(reader.bytes()?).map_err(|_| Error::UTF8Error)?;
+    Ok((root, storage.into()))
+}
+
+/// Collect the newest state of every key in a format 1 subtree; a message shadows everything
+/// below the node buffering it, so the first state seen wins
+fn collect_v1(
+    file: &File,
+    page_id: PageId,
+    out: &mut BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+) -> Result<(), Error> {
+    let mut page = vec![0; PAGESIZE as usize];
+    file.read_exact_at(&mut page, page_id * PAGESIZE)?;
+    let mut reader = V1Reader {
+        src: &page,
+        cursor: 0,
+    };
+    if reader.u64()? != MAGIC {
+        return Err(Error::UnexpectedError(format!(
+            "Bad format 1 node: {}",
+            page_id
+        )));
+    }
+    // root and dirty flags
+    reader.take(2)?;
+    let is_leaf = reader.u8()? != 0;
+    if is_leaf {
+        for _ in 0..reader.u16()? {
+            let key = reader.bytes()?;
+            let val = reader.bytes()?;
+            out.entry(key).or_insert(Some(val));
+        }
+        return Ok(());
+    }
+    // epsilon
+    reader.take(4)?;
+    let mut children = vec![];
+    for _ in 0..reader.u16()? {
+        reader.bytes()?;
+        children.push(reader.u64()?);
+    }
+    children.push(reader.u64()?);
+    for _ in 0..reader.u16()? {
+        let key = reader.bytes()?;
+        let ty = reader.u8()?;
+        let val = reader.bytes()?;
+        let state = match num::FromPrimitive::from_u8(ty) {
+            Some(MessageType::Insert) => Some(val),
+            Some(MessageType::Delete) => None,
+            _ => {
+                return Err(Error::UnexpectedError(format!(
+                    "Cannot upgrade message type {}",
+                    ty
+                )))
+            }
+        };
+        out.entry(key).or_insert(state);
+    }
+    for child in children {
+        collect_v1(file, child, out)?;
+    }
+    Ok(())
+}
+
+fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".v{}", version));
+    name.into()
+}
+
+/// Where the upgraded tree of `path` is built before it replaces the old one
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".upgrade");
+    name.into()
+}
+
+/// Fill a new tree with `build` and flush it, next to the tree at `path` of format `version`
+/// whose storage file is `storage`. Only then are the old files moved aside with a
+/// `.v<version>` suffix and the new ones moved into their place, the superblock last, so a
+/// failed upgrade leaves the old tree as it was
+fn replace_with<F: FnOnce(&mut Betree<SimplePager>) -> Result<(), Error>>(
+    path: &Path,
+    storage: &Path,
+    version: u32,
+    key: Option<&Key>,
+    comparator: Comparator,
+    build: F,
+) -> Result<(), Error> {
+    let temp = temp_path(path);
+    let temp_storage = Superblock::storage_path(&temp);
+    // Left over by an upgrade that failed
+    let _ = std::fs::remove_file(&temp);
+    let _ = std::fs::remove_file(&temp_storage);
+    let mut tree = Betree::<SimplePager>::create(&temp, key, comparator);
+    let built = build(&mut tree)
+        .and_then(|_| tree.flush())
+        .and_then(|_| tree.rename_storage(Superblock::storage_path(path)));
+    drop(tree);
+    if let Err(e) = built {
+        let _ = std::fs::remove_file(&temp);
+        let _ = std::fs::remove_file(&temp_storage);
+        return Err(e);
+    }
+    std::fs::rename(path, backup_path(path, version))?;
+    std::fs::rename(storage, backup_path(storage, version))?;
+    std::fs::rename(&temp_storage, Superblock::storage_path(path))?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Expiry of every expiring key
+type Expiries = BTreeMap<Vec<u8>, u64>;
+
+/// Everything a tree of format 2 or later holds
+struct Contents {
+    comparator: Comparator,
+    /// Oldest first, with the default tree as of each
+    checkpoints: Vec<(Checkpoint, Pairs, Expiries)>,
+    /// Named trees besides the default one
+    trees: Vec<(String, Pairs)>,
+    pairs: Pairs,
+    expiries: Expiries,
+}
+
+/// Read the tree at `path`, in the layout of its format version
+fn read_contents(path: &Path, key: Option<&Key>) -> Result<Contents, Error> {
+    let mut tree = Betree::<SimplePager>::open_to_upgrade(path, key)?;
+    let mut checkpoints = vec![];
+    for checkpoint in tree.checkpoints().to_vec() {
+        let pairs = tree.at_checkpoint(&checkpoint.name)?.scan(..);
+        let expiries = tree.expiries(Some(&checkpoint.name))?;
+        checkpoints.push((checkpoint, pairs, expiries));
+    }
+    let mut trees = vec![];
+    for name in tree.tree_names().into_iter().skip(1) {
+        let pairs = tree.scan_all(&name)?;
+        trees.push((name, pairs));
+    }
+    Ok(Contents {
+        comparator: tree.comparator(),
+        checkpoints,
+        trees,
+        pairs: tree.scan_at(.., LATEST)?,
+        expiries: tree.expiries(None)?,
+    })
+}
+
+/// Rewrite the tree at `path` from an older format into FORMAT_VERSION, offline.
+/// The old superblock and storage files are kept with a `.v<version>` suffix once the new
+/// tree is on disk.
+/// Returns false if the tree is already current.
+pub fn upgrade<Q: AsRef<Path>>(path: Q) -> Result<bool, Error> {
+    upgrade_with(path.as_ref(), None)
+}
+
+/// Upgrade the encrypted tree at `path`, see `upgrade`
+#[cfg(feature = "encryption")]
+pub fn upgrade_encrypted<Q: AsRef<Path>>(path: Q, key: &Key) -> Result<bool, Error> {
+    upgrade_with(path.as_ref(), Some(key))
+}
+
+fn upgrade_with(path: &Path, key: Option<&Key>) -> Result<bool, Error> {
+    let version = Superblock::format_version(path)?;
+    match version {
+        FORMAT_VERSION => Ok(false),
+        1 => upgrade_v1(path).map(|_| true),
+        // Versions from 2 on only add to the layout, so the current code reads them and the
+        // tree is copied into a new one
+        2..FORMAT_VERSION => {
+            let contents = with_layout(version, || read_contents(path, key))?;
+            let storage = PathBuf::from(Superblock::open(path).storage_filename);
+            let comparator = contents.comparator;
+            replace_with(path, &storage, version, key, comparator, |tree| {
+                // Checkpoints share no pages once copied
+                for (checkpoint, pairs, expiries) in contents.checkpoints {
+                    load(tree, pairs, &expiries)?;
+                    tree.checkpoint_at(&checkpoint.name, checkpoint.timestamp)?;
+                }
+                for (name, pairs) in contents.trees {
+                    tree.create_tree(&name)?;
+                    for (key, val) in pairs {
+                        tree.insert_into(&name, key, val)?;
+                    }
+                }
+                load(tree, contents.pairs, &contents.expiries)
+            })?;
+            Ok(true)
+        }
+        _ => Err(Error::IncompatibleFormat(version)),
+    }
+}
+
+/// Replace the default tree of `tree` with `pairs`, keeping the expiry of the expiring ones
+fn load(tree: &mut Betree<SimplePager>, pairs: Pairs, expiries: &Expiries) -> Result<(), Error> {
+    let expiring: Vec<_> = pairs
+        .iter()
+        .filter_map(|(k, v)| expiries.get(k).map(|&e| (k.clone(), v.clone(), e)))
+        .collect();
+    tree.bulk_load(pairs)?;
+    for (key, val, expiry) in expiring {
+        tree.insert_expiring(key, val, expiry);
+    }
+    Ok(())
+}
+
+fn upgrade_v1(path: &Path) -> Result<(), Error> {
+    let (root, storage) = read_v1_superblock(path)?;
+    let mut entries = BTreeMap::new();
+    collect_v1(&File::open(&storage)?, root, &mut entries)?;
+    replace_with(path, &storage, 1, None, Comparator::Bytewise, |tree| {
+        let pairs = entries
+            .into_iter()
+            .filter_map(|(key, val)| val.map(|val| (key, val)));
+        tree.bulk_load(pairs)
+    })
+}
+
+#[test]
+fn test_upgrade_v1() {
+    use crate::testing::TempPath;
+    let temp = TempPath::new("upgrade");
+    let path = temp.path();
+    let storage = format!("{}.storage", path);
+    fn put(page: &mut Vec<u8>, bytes: &[u8]) {
+        page.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+        page.extend_from_slice(bytes);
+    }
+    fn node(is_leaf: bool) -> Vec<u8> {
+        let mut page = MAGIC.to_le_bytes().to_vec();
+        page.extend_from_slice(&[0, 0, is_leaf as u8]);
+        page
+    }
+    let leaf = |kvs: &[(&str, &str)]| {
+        let mut page = node(true);
+        page.extend_from_slice(&(kvs.len() as u16).to_le_bytes());
+        for (k, v) in kvs {
+            put(&mut page, k.as_bytes());
+            put(&mut page, v.as_bytes());
+        }
+        page
+    };
+    // Root at page 3 over leaves 1(keys < "m") and 2, with buffered messages
+    let mut root = node(false);
+    root.extend_from_slice(&0.5f32.to_le_bytes());
+    root.extend_from_slice(&1u16.to_le_bytes());
+    put(&mut root, b"m");
+    root.extend_from_slice(&1u64.to_le_bytes());
+    root.extend_from_slice(&2u64.to_le_bytes());
+    root.extend_from_slice(&3u16.to_le_bytes());
+    for (k, ty, v) in [("b", 1u8, "9"), ("n", 2, ""), ("z", 1, "5")] {
+        put(&mut root, k.as_bytes());
+        root.push(ty);
+        put(&mut root, v.as_bytes());
+    }
+    let file = File::create(&storage).unwrap();
+    let pages = [
+        leaf(&[("a", "1"), ("b", "2")]),
+        leaf(&[("m", "3"), ("n", "4")]),
+        root,
+    ];
+    for (i, page) in pages.iter().enumerate() {
+        file.write_all_at(page, (i as u64 + 1) * PAGESIZE).unwrap();
+    }
+    file.set_len(4 * PAGESIZE).unwrap();
+    let mut sb = LEGACY_MAGIC.to_le_bytes().to_vec();
+    sb.extend_from_slice(&3u64.to_le_bytes());
+    sb.extend_from_slice(&0u64.to_le_bytes());
+    put(&mut sb, storage.as_bytes());
+    sb.extend_from_slice(&3u64.to_le_bytes());
+    sb.resize(PAGESIZE as usize, 0);
+    std::fs::write(path, sb).unwrap();
+
+    assert_eq!(Superblock::format_version(path).unwrap(), 1);
+    assert!(matches!(
+        Betree::open(path),
+        Err(Error::IncompatibleFormat(1))
+    ));
+    // A failed upgrade left its temporary superblock behind
+    std::fs::write(format!("{}.upgrade", path), b"partial").unwrap();
+    assert!(upgrade(path).unwrap());
+    assert!(!upgrade(path).unwrap());
+    assert!(Path::new(&format!("{}.v1", storage)).exists());
+    assert!(!Path::new(&format!("{}.upgrade", path)).exists());
+    assert!(!Path::new(&format!("{}.upgrade.storage", path)).exists());
+    let mut tree = Betree::open(path).unwrap();
+    for (k, v) in [
+        ("a", Some("1")),
+        ("b", Some("9")),
+        ("m", Some("3")),
+        ("n", None),
+        ("z", Some("5")),
+    ] {
+        assert_eq!(tree.get(k.as_bytes()), v.map(|v| v.as_bytes().to_vec()));
+    }
+}
+
+#[test]
+fn test_upgrade_from_v2() {
+    use crate::testing::{key, TempPath};
+    use std::time::Duration;
+    for version in 2..FORMAT_VERSION {
+        let temp = TempPath::new("upgrade_v2");
+        let path = temp.path();
+        let storage = format!("{}.storage", path);
+        with_layout(version, || {
+            let mut tree = Betree::new(path);
+            for i in 0..3000 {
+                tree.insert(key(i), b"old".to_vec());
+            }
+            tree.checkpoint("first").unwrap();
+            for i in 0..1000 {
+                tree.insert(key(i), b"new".to_vec());
+            }
+            if version >= 4 {
+                tree.delete_range(key(2000), key(2500));
+            }
+            if version >= 5 {
+                tree.insert_with_ttl(b"ttl".to_vec(), b"v".to_vec(), Duration::from_secs(3600));
+            }
+            tree.create_tree("other").unwrap();
+            tree.insert_into("other", b"k".to_vec(), b"v".to_vec())
+                .unwrap();
+            tree.flush().unwrap();
+        });
+
+        assert_eq!(Superblock::format_version(path).unwrap(), version);
+        assert!(matches!(
+            Betree::open(path),
+            Err(Error::IncompatibleFormat(v)) if v == version
+        ));
+        assert!(upgrade(path).unwrap());
+        assert!(!upgrade(path).unwrap());
+        assert!(Path::new(&format!("{}.v{}", storage, version)).exists());
+        assert!(!Path::new(&format!("{}.upgrade", path)).exists());
+        let mut tree = Betree::open(path).unwrap();
+        for i in 0..3000 {
+            let val = match i {
+                0..1000 => Some(b"new".to_vec()),
+                2000..2500 if version >= 4 => None,
+                _ => Some(b"old".to_vec()),
+            };
+            assert_eq!(tree.get(&key(i)), val, "Key {} from format {}", i, version);
+        }
+        assert_eq!(tree.get_from("other", b"k").unwrap(), Some(b"v".to_vec()));
+        let mut first = tree.at_checkpoint("first").unwrap();
+        assert_eq!(first.get(&key(5)), Some(b"old".to_vec()));
+        assert_eq!(first.scan(..).len(), 3000);
+        if version >= 5 {
+            assert_eq!(tree.get(b"ttl"), Some(b"v".to_vec()));
+            crate::ttl::advance(Duration::from_secs(7200));
+            assert_eq!(tree.get(b"ttl"), None);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::error::Error;
+use crate::page::{Page, PAGESIZE};
+use crate::pager::{PageId, Pager};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Max number of operations in flight
+const QUEUE_DEPTH: u32 = 256;
+
+/// A `Pager` that submits batches of page reads and writes through io_uring.
+/// `write_many` and `read_many` keep up to QUEUE_DEPTH pages in flight at once.
+pub struct UringPager {
+    file: File,
+    ring: Mutex<IoUring>,
+}
+
+impl UringPager {
+    fn with_file(file: File) -> Result<Self, Error> {
+        Ok(Self {
+            file,
+            ring: Mutex::new(IoUring::new(QUEUE_DEPTH)?),
+        })
+    }
+
+    fn fd(&self) -> types::Fd {
+        types::Fd(self.file.as_raw_fd())
+    }
+
+    /// Submit all entries, QUEUE_DEPTH at a time, and return their results in order
+    fn run(&self, entries: Vec<squeue::Entry>) -> Result<Vec<i32>, Error> {
+        let mut ring = self.ring.lock().unwrap();
+        let mut results = vec![0; entries.len()];
+        for (i, chunk) in entries.chunks(QUEUE_DEPTH as usize).enumerate() {
+            let base = i * QUEUE_DEPTH as usize;
+            {
+                let mut sq = ring.submission();
+                for (j, entry) in chunk.iter().enumerate() {
+                    let entry = entry.clone().user_data((base + j) as u64);
+                    unsafe { sq.push(&entry) }.map_err(|_| {
+                        Error::UnexpectedError("io_uring submission queue is full".to_owned())
+                    })?;
+                }
+            }
+            loop {
+                match ring.submit_and_wait(chunk.len()) {
+                    Ok(_) => break,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            for cqe in ring.completion() {
+                results[cqe.user_data() as usize] = cqe.result();
+            }
+        }
+        Ok(results)
+    }
+
+    /// Fail on errors; short transfers are completed synchronously
+    fn check(res: i32, finish: impl FnOnce(usize) -> std::io::Result<()>) -> Result<(), Error> {
+        if res < 0 {
+            return Err(std::io::Error::from_raw_os_error(-res).into());
+        }
+        let done = res as usize;
+        if done < PAGESIZE as usize {
+            finish(done)?;
+        }
+        Ok(())
+    }
+}
+
+impl Pager for UringPager {
+    fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let fd = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Self::with_file(fd)
+    }
+
+    fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let fd = OpenOptions::new()
+            .create(false)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        Self::with_file(fd)
+    }
+
+    fn read(&self, page_id: &PageId, page: &mut Page) -> Result<(), Error> {
+        self.file.read_exact_at(page.into(), page_id * PAGESIZE)?;
+        Ok(())
+    }
+
+    fn write(&mut self, page_id: &PageId, data: &Page) -> Result<(), Error> {
+        self.file.write_all_at(data.into(), page_id * PAGESIZE)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let res = self.run(vec![opcode::Fsync::new(self.fd()).build()])?;
+        if res[0] < 0 {
+            return Err(std::io::Error::from_raw_os_error(-res[0]).into());
+        }
+        Ok(())
+    }
+
+    fn write_many(&mut self, pages: &[(PageId, Page)]) -> Result<(), Error> {
+        let entries = pages
+            .iter()
+            .map(|(page_id, page)| {
+                opcode::Write::new(self.fd(), page.as_ptr(), PAGESIZE as u32)
+                    .offset(page_id * PAGESIZE)
+                    .build()
+            })
+            .collect();
+        let results = self.run(entries)?;
+        for ((page_id, page), res) in pages.iter().zip(results) {
+            Self::check(res, |done| {
+                self.file
+                    .write_all_at(&page[done..], page_id * PAGESIZE + done as u64)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn read_many(&self, pages: &mut [(PageId, Page)]) -> Result<(), Error> {
+        let entries = pages
+            .iter_mut()
+            .map(|(page_id, page)| {
+                opcode::Read::new(self.fd(), page.as_mut_ptr(), PAGESIZE as u32)
+                    .offset(*page_id * PAGESIZE)
+                    .build()
+            })
+            .collect();
+        let results = self.run(entries)?;
+        for ((page_id, page), res) in pages.iter_mut().zip(results) {
+            Self::check(res, |done| {
+                self.file
+                    .read_exact_at(&mut page[done..], *page_id * PAGESIZE + done as u64)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_uring_batches() {
+    use crate::testing::TempPath;
+    let temp = TempPath::new("uring");
+    let path = temp.path();
+    let mut pager = UringPager::new(path).unwrap();
+    // More pages than fit in the queue at once
+    let pages: Vec<_> = (0..QUEUE_DEPTH as u64 + 10)
+        .map(|id| {
+            let mut page = Page::default();
+            page.fill(id as u8);
+            (id * 2, page)
+        })
+        .collect();
+    pager.write_many(&pages).unwrap();
+    pager.flush().unwrap();
+
+    let mut read: Vec<_> = pages.iter().map(|(id, _)| (*id, Page::default())).collect();
+    pager.read_many(&mut read).unwrap();
+    for ((_, a), (_, b)) in pages.iter().zip(read.iter()) {
+        assert_eq!(&a[..], &b[..]);
+    }
+}
@@ -0,0 +1,395 @@
+use crate::bloom::{filter_len, Bloom};
+use crate::node::{header_size, ChildId, Node, NodeType, MAGIC, MAX_KEY_SIZE};
+use crate::ttl;
+use crate::types::{
+    Comparator, EncodedLen, MessageData, MessageType, OnDiskKey, OnDiskValue, OndiskRestartOffset,
+    PageOffset, Serializable, SizedOnDisk, Varint, FORMAT_VERSION,
+};
+use core::cmp::Ordering;
+use core::marker::PhantomData;
+use core::mem::size_of;
+
+/// Read-only view of a serialized map, searched in place through its restart points
+#[derive(Clone, Copy)]
+pub struct MapView<'a, V: EncodedLen> {
+    offsets: &'a [u8],
+    entries: &'a [u8],
+    count: usize,
+    comparator: Comparator,
+    /// Bytes of the whole map, header included
+    len: PageOffset,
+    _v: PhantomData<V>,
+}
+
+impl<'a, V: EncodedLen> MapView<'a, V> {
+    pub fn new(src: &'a [u8], comparator: Comparator) -> Self {
+        let mut _cursor = 0;
+        deserialize_with_var!(count, Varint, src, _cursor);
+        deserialize_with_var!(restarts, Varint, src, _cursor);
+        deserialize_with_var!(body, Varint, src, _cursor);
+        let offsets_len = usize::from(restarts) * size_of::<OndiskRestartOffset>();
+        let end = _cursor + usize::from(body);
+        Self {
+            offsets: &src[_cursor.._cursor + offsets_len],
+            entries: &src[_cursor + offsets_len..end],
+            count: count.into(),
+            comparator,
+            len: end,
+            _v: PhantomData,
+        }
+    }
+
+    /// Key of the restart point `i`, which is stored in full
+    fn restart_key(&self, i: usize) -> &'a [u8] {
+        let slot = i * size_of::<OndiskRestartOffset>();
+        let mut _cursor = OndiskRestartOffset::deserialize(&self.offsets[slot..]) as usize;
+        let entries = self.entries;
+        deserialize_with_var!(shared, Varint, entries, _cursor);
+        debug_assert_eq!(shared.0, 0);
+        deserialize_with_var!(unshared, Varint, entries, _cursor);
+        &entries[_cursor.._cursor + usize::from(unshared)]
+    }
+
+    /// Value bytes of the first entry whose key is greater than(or equal to, unless `strict`)
+    /// `key`, and whether its key equals `key`
+    fn seek(&self, key: &[u8], strict: bool) -> Option<(bool, &'a [u8])> {
+        let past = |k: &[u8]| match self.comparator.compare(k, key) {
+            Ordering::Equal => !strict,
+            ord => ord == Ordering::Greater,
+        };
+        let restarts = self.offsets.len() / size_of::<OndiskRestartOffset>();
+        let (mut lo, mut hi) = (0, restarts);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if past(self.restart_key(mid)) {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        // Scan from the last restart point before the answer, or from the first entry
+        let mut _cursor = match lo {
+            0 => 0,
+            i => {
+                let slot = (i - 1) * size_of::<OndiskRestartOffset>();
+                OndiskRestartOffset::deserialize(&self.offsets[slot..]) as usize
+            }
+        };
+        let entries = self.entries;
+        let mut buf = [0u8; MAX_KEY_SIZE];
+        while _cursor < entries.len() {
+            deserialize_with_var!(shared, Varint, entries, _cursor);
+            deserialize_with_var!(unshared, Varint, entries, _cursor);
+            let (shared, unshared) = (usize::from(shared), usize::from(unshared));
+            buf[shared..shared + unshared].copy_from_slice(&entries[_cursor.._cursor + unshared]);
+            _cursor += unshared;
+            let value_len = V::encoded_len(&entries[_cursor..]);
+            let current = &buf[..shared + unshared];
+            if past(current) {
+                return Some((current == key, &entries[_cursor.._cursor + value_len]));
+            }
+            _cursor += value_len;
+        }
+        None
+    }
+
+    /// Value bytes of `key`
+    pub fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.seek(key, false)
+            .and_then(|(found, value)| found.then_some(value))
+    }
+
+    /// Value bytes of the first key greater than `key`
+    pub fn upper_bound(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.seek(key, true).map(|(_, value)| value)
+    }
+
+    /// Cursor over the keys and value bytes of the entries, in order
+    pub fn entries(&self) -> Entries<'a, V> {
+        Entries {
+            entries: self.entries,
+            cursor: 0,
+            key: [0; MAX_KEY_SIZE],
+            _v: PhantomData,
+        }
+    }
+}
+
+/// Cursor over the entries of a `MapView`, rebuilding each key in a stack buffer like `seek`
+pub struct Entries<'a, V: EncodedLen> {
+    entries: &'a [u8],
+    cursor: usize,
+    key: [u8; MAX_KEY_SIZE],
+    _v: PhantomData<V>,
+}
+
+impl<'a, V: EncodedLen> Entries<'a, V> {
+    /// Key and value bytes of the next entry; the key is only valid until the next call
+    pub fn next_entry(&mut self) -> Option<(&[u8], &'a [u8])> {
+        let entries = self.entries;
+        let mut _cursor = self.cursor;
+        if _cursor >= entries.len() {
+            return None;
+        }
+        deserialize_with_var!(shared, Varint, entries, _cursor);
+        deserialize_with_var!(unshared, Varint, entries, _cursor);
+        let (shared, unshared) = (usize::from(shared), usize::from(unshared));
+        self.key[shared..shared + unshared].copy_from_slice(&entries[_cursor.._cursor + unshared]);
+        _cursor += unshared;
+        let value_len = V::encoded_len(&entries[_cursor..]);
+        let value = &entries[_cursor.._cursor + value_len];
+        self.cursor = _cursor + value_len;
+        Some((&self.key[..shared + unshared], value))
+    }
+}
+
+/// Bytes of an encoded OnDiskValue
+fn value_bytes(src: &[u8]) -> &[u8] {
+    let len = Varint::deserialize(src);
+    &src[len.size()..len.size() + usize::from(len)]
+}
+
+/// Type, sequence number and value bytes of each version of an encoded message, newest first
+fn versions(src: &[u8]) -> impl Iterator<Item = (MessageType, u64, &[u8])> {
+    let mut _cursor = 0;
+    let mut remaining = 1;
+    std::iter::from_fn(move || {
+        if remaining == 0 {
+            return None;
+        }
+        deserialize_with_var!(ty, MessageType, src, _cursor);
+        deserialize_with_var!(version, Varint, src, _cursor);
+        let value = value_bytes(&src[_cursor..]);
+        _cursor += OnDiskValue::encoded_len(&src[_cursor..]);
+        deserialize_with_var!(older, Varint, src, _cursor);
+        remaining = remaining + usize::from(older) - 1;
+        Some((ty, version.0, value))
+    })
+}
+
+/// Sequence number and value bytes of the newest version of an encoded message a reader at
+/// `seq` sees, None as value for a delete, None if every version is newer
+fn version_at(src: &[u8], seq: u64) -> Option<(u64, Option<&[u8]>)> {
+    versions(src)
+        .find(|(_, version, _)| *version <= seq)
+        .map(|(ty, version, value)| {
+            let value = match ty {
+                MessageType::Insert => Some(value),
+                MessageType::InsertWithTtl => ttl::live(value),
+                MessageType::Delete | MessageType::RangeDelete => None,
+                MessageType::Upsert => unimplemented!(),
+            };
+            (version, value)
+        })
+}
+
+/// Sequence number of the newest range delete in `deletes` that hides `key` from a reader at
+/// `seq`
+fn deleted_at(deletes: &MapView<'_, MessageData>, key: &[u8], seq: u64) -> Option<u64> {
+    let comparator = deletes.comparator;
+    let mut entries = deletes.entries();
+    let mut newest = None;
+    while let Some((start, msg)) = entries.next_entry() {
+        if comparator.compare(start, key).is_gt() {
+            break;
+        }
+        let hiding = versions(msg)
+            .filter(|(_, version, end)| *version <= seq && comparator.compare(key, end).is_lt())
+            .map(|(_, version, _)| version);
+        newest = newest.max(hiding.max());
+    }
+    newest
+}
+
+/// Read-only view of a serialized clean node, used for lookups without decoding it
+pub enum NodeView<'a> {
+    Leaf {
+        values: MapView<'a, OnDiskValue>,
+        versions: MapView<'a, MessageData>,
+        expiries: MapView<'a, u64>,
+    },
+    Internal {
+        pivot_map: MapView<'a, ChildId>,
+        rightmost_child: ChildId,
+        msg_buffer: MapView<'a, MessageData>,
+        bloom: Bloom<'a>,
+        range_deletes: MapView<'a, MessageData>,
+    },
+}
+
+impl<'a> NodeView<'a> {
+    pub fn new(src: &'a [u8]) -> Self {
+        let mut _cursor = 0;
+        deserialize_with_var!(magic, u64, src, _cursor);
+        assert_eq!(magic, MAGIC);
+        deserialize_with_var!(version, u32, src, _cursor);
+        assert_eq!(version, FORMAT_VERSION);
+        deserialize_with_var!(comparator, Comparator, src, _cursor);
+        _cursor = header_size();
+        deserialize_with_var!(is_leaf, bool, src, _cursor);
+        if is_leaf {
+            let values = MapView::new(&src[_cursor..], comparator);
+            _cursor += values.len;
+            let versions = MapView::new(&src[_cursor..], comparator);
+            _cursor += versions.len;
+            return Self::Leaf {
+                values,
+                versions,
+                expiries: MapView::new(&src[_cursor..], comparator),
+            };
+        }
+        deserialize_with_var!(_epsilon, f32, src, _cursor);
+        let pivot_map = MapView::new(&src[_cursor..], comparator);
+        _cursor += pivot_map.len;
+        deserialize_with_var!(rightmost_child, ChildId, src, _cursor);
+        let msg_buffer = MapView::new(&src[_cursor..], comparator);
+        _cursor += msg_buffer.len;
+        deserialize_with_var!(bloom_bits, u8, src, _cursor);
+        let filter_len = filter_len(msg_buffer.count, bloom_bits);
+        Self::Internal {
+            pivot_map,
+            rightmost_child,
+            msg_buffer,
+            bloom: Bloom::new(&src[_cursor.._cursor + filter_len], bloom_bits),
+            range_deletes: MapView::new(&src[_cursor + filter_len..], comparator),
+        }
+    }
+
+    /// Value of `key` for a reader at `seq` in a leaf or buffered in an internal node, or the
+    /// child to look in
+    pub fn search(&self, key: &[u8], seq: u64) -> Result<Option<&'a [u8]>, ChildId> {
+        match self {
+            Self::Leaf {
+                values,
+                versions,
+                expiries,
+            } => match versions.get(key).and_then(|msg| version_at(msg, seq)) {
+                Some((_, value)) => Ok(value),
+                None if expiries
+                    .get(key)
+                    .is_some_and(|expiry| u64::deserialize(expiry) <= ttl::now()) =>
+                {
+                    Ok(None)
+                }
+                None => Ok(values.get(key).map(value_bytes)),
+            },
+            Self::Internal {
+                pivot_map,
+                rightmost_child,
+                msg_buffer,
+                bloom,
+                range_deletes,
+            } => {
+                let msg = bloom
+                    .may_contain(key)
+                    .then(|| msg_buffer.get(key))
+                    .flatten()
+                    .and_then(|msg| version_at(msg, seq));
+                match (msg, deleted_at(range_deletes, key, seq)) {
+                    (Some((version, value)), deleted) if deleted.is_none_or(|d| version > d) => {
+                        return Ok(value)
+                    }
+                    (_, Some(_)) => return Ok(None),
+                    _ => {}
+                }
+                Err(pivot_map
+                    .upper_bound(key)
+                    .map_or(*rightmost_child, ChildId::deserialize))
+            }
+        }
+    }
+}
+
+/// A cached node: decoded if it was modified since it was read, a view over its bytes otherwise
+pub enum NodeRef<'a> {
+    Node(&'a Node),
+    View(NodeView<'a>),
+}
+
+impl<'a> NodeRef<'a> {
+    /// Value of `key` for a reader at `seq` in a leaf or buffered in an internal node, or the
+    /// child to look in
+    pub fn search(&self, key: &OnDiskKey, seq: u64) -> Result<Option<&'a [u8]>, ChildId> {
+        match self {
+            Self::Node(node) => match &node.node_inner {
+                NodeType::Leaf(leaf) => Ok(leaf.get(key, seq)),
+                NodeType::Internal(internal) => internal.get(key, seq),
+                _ => unimplemented!(),
+            },
+            Self::View(view) => view.search(key, seq),
+        }
+    }
+}
+
+#[test]
+fn test_view_matches_node() {
+    use crate::node::InternalNode;
+    use std::collections::BTreeMap;
+    crate::CFG.get_or_init(crate::Args::default);
+    let key = |i: u64| OnDiskKey::new((i * 3).to_be_bytes().to_vec());
+
+    let seqs = [0, 20, 50, 70, 120, 150, u64::MAX];
+    let mut leaf = Node::new_empty_leaf(false);
+    if let NodeType::Leaf(l) = &mut leaf.node_inner {
+        for i in 0..100 {
+            let msg = MessageData::with_seq(MessageType::Insert, vec![i as u8; 5], i + 1);
+            l.apply(key(i), msg, &[]);
+        }
+        // Readers at 50 and 120 keep the versions they see
+        for i in (0..100).step_by(3) {
+            let ty = [MessageType::Insert, MessageType::Delete][i as usize % 2];
+            let msg = MessageData::with_seq(ty, vec![], 100 + i);
+            l.apply(key(i), msg, &[50, 120]);
+        }
+    }
+    let bytes = leaf.to_bytes().unwrap();
+    let view = NodeView::new(&bytes);
+    for i in 0..300u64 {
+        let k = OnDiskKey::new(i.to_be_bytes().to_vec());
+        for seq in seqs {
+            assert_eq!(view.search(&k, seq), NodeRef::Node(&leaf).search(&k, seq));
+        }
+    }
+    assert_eq!(view.search(&key(3), 50), Ok(Some(&[3u8; 5][..])));
+    assert_eq!(view.search(&key(3), 150), Ok(None));
+
+    let pivots: BTreeMap<_, _> = (1..40).map(|i| (key(i * 2), 1000 + i)).collect();
+    let mut internal = Node::new_internel_root(0, vec![]);
+    if let NodeType::Internal(node) = &mut internal.node_inner {
+        *node = InternalNode::new_internel_root(pivots, 7);
+        node.bloom_bits = 10;
+        for i in (0..100).step_by(5) {
+            let mut msg = MessageData::with_seq(MessageType::Insert, vec![1], 100 + i);
+            msg.push_older(MessageData::with_seq(MessageType::Insert, vec![2], 60));
+            msg.push_older(MessageData::with_seq(MessageType::Delete, vec![], 30));
+            msg.prune(&[0, 40, 70]);
+            node.insert_msg(key(i), msg);
+        }
+        let range_delete =
+            |end, seq| MessageData::with_seq(MessageType::RangeDelete, key(end).to_vec(), seq);
+        let mut deletes = BTreeMap::new();
+        deletes.insert(key(10), range_delete(30, 80).merge(range_delete(14, 120)));
+        deletes.insert(key(20), range_delete(60, 150));
+        node.range_deletes = deletes.into();
+    }
+    let bytes = internal.to_bytes().unwrap();
+    let view = NodeView::new(&bytes);
+    for i in 0..300u64 {
+        let k = OnDiskKey::new(i.to_be_bytes().to_vec());
+        for seq in seqs {
+            assert_eq!(
+                view.search(&k, seq),
+                NodeRef::Node(&internal).search(&k, seq)
+            );
+        }
+    }
+    assert_eq!(view.search(&key(5), 50), Ok(None));
+    assert_eq!(view.search(&key(5), 70), Ok(Some(&[2u8][..])));
+    assert!(view.search(&key(5), 20).is_err());
+    assert_eq!(view.search(&key(12), 130), Ok(None));
+    assert!(view.search(&key(12), 50).is_err());
+    assert_eq!(view.search(&key(15), 200), Ok(Some(&[1u8][..])));
+    assert_eq!(view.search(&key(25), 200), Ok(None));
+    assert_eq!(view.search(&key(25), 130), Ok(Some(&[1u8][..])));
+    assert_eq!(view.search(&key(40), 100), Ok(Some(&[2u8][..])));
+}